@@ -0,0 +1,9 @@
+ALTER TABLE games
+    ADD width SMALLINT NOT NULL DEFAULT 7,
+    ADD height SMALLINT NOT NULL DEFAULT 7,
+    ADD win_len SMALLINT NOT NULL DEFAULT 4;
+
+-- existing games weren't all 7x7 (i.e. the smart bot ones), take dimensions from the serialized state
+UPDATE games SET
+    height = array_length(string_to_array(trim(state), E'\n'), 1),
+    width = array_length(regexp_split_to_array(trim(split_part(trim(state), E'\n', 1)), '\s+'), 1);
@@ -0,0 +1,3 @@
+ALTER TABLE games
+    DROP COLUMN undo_requested_by;
+DROP TYPE player_type;
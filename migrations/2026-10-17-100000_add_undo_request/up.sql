@@ -0,0 +1,4 @@
+create type player_type as enum ('RED', 'BLUE');
+
+ALTER TABLE games
+    ADD undo_requested_by player_type;
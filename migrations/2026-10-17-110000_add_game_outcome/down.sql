@@ -0,0 +1,6 @@
+ALTER TABLE games
+    DROP COLUMN draw_offered_by,
+    DROP COLUMN outcome,
+    DROP COLUMN outcome_winner,
+    DROP COLUMN outcome_reason;
+DROP TYPE outcome_type;
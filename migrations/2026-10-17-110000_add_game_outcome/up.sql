@@ -0,0 +1,8 @@
+create type outcome_type as enum ('WIN', 'DRAW', 'RESIGN', 'ABANDON');
+
+-- games finished before this migration stay without an outcome, the board still tells who won
+ALTER TABLE games
+    ADD draw_offered_by player_type,
+    ADD outcome outcome_type,
+    ADD outcome_winner player_type,
+    ADD outcome_reason TEXT;
@@ -0,0 +1,14 @@
+ALTER TABLE games
+    DROP COLUMN clock_base_ms,
+    DROP COLUMN clock_increment_ms,
+    DROP COLUMN clock_per_move,
+    DROP COLUMN red_time_left_ms,
+    DROP COLUMN blue_time_left_ms,
+    DROP COLUMN clock_started_at;
+-- TODO no permissions, same as with bot_type
+UPDATE games SET outcome = NULL WHERE outcome = 'TIMEOUT';
+DELETE FROM pg_enum
+WHERE enumlabel = 'TIMEOUT'
+  AND enumtypid = (
+    SELECT oid FROM pg_type WHERE typname = 'outcome_type'
+);
@@ -0,0 +1,9 @@
+ALTER TYPE outcome_type ADD VALUE 'TIMEOUT';
+
+ALTER TABLE games
+    ADD clock_base_ms BIGINT,
+    ADD clock_increment_ms BIGINT NOT NULL DEFAULT 0,
+    ADD clock_per_move BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD red_time_left_ms BIGINT,
+    ADD blue_time_left_ms BIGINT,
+    ADD clock_started_at TIMESTAMPTZ;
@@ -0,0 +1,10 @@
+CREATE TABLE game_messages (
+                       id UUID PRIMARY KEY,
+                       game_id UUID NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+                       player player_type NOT NULL,
+                       text TEXT NOT NULL,
+                       created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_game_messages_game_id
+    ON game_messages(game_id, created_at);
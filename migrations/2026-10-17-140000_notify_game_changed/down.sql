@@ -0,0 +1,2 @@
+DROP TRIGGER notify_game_changed ON games;
+DROP FUNCTION notify_game_changed();
@@ -0,0 +1,10 @@
+-- the payload is just the id, notifications are limited in size and the listener fetches the game anyways
+CREATE OR REPLACE FUNCTION notify_game_changed() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('game_changed', NEW.id::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_game_changed AFTER UPDATE ON games
+    FOR EACH ROW EXECUTE PROCEDURE notify_game_changed();
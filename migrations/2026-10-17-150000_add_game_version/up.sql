@@ -0,0 +1,2 @@
+-- bumped on every write, a write based on an older version is refused
+ALTER TABLE games ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
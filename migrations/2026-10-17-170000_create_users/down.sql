@@ -0,0 +1,3 @@
+ALTER TABLE games DROP COLUMN user_blue;
+ALTER TABLE games DROP COLUMN user_red;
+DROP TABLE users;
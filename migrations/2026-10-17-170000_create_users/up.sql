@@ -0,0 +1,20 @@
+CREATE TABLE users (
+                       id UUID PRIMARY KEY,
+                       username TEXT NOT NULL,
+                       password_hash TEXT NOT NULL,
+                       created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- "Bob" and "bob" are the same user
+CREATE UNIQUE INDEX idx_users_username
+    ON users(lower(username));
+
+-- who has claimed the seat, if they were logged in
+ALTER TABLE games ADD COLUMN user_red UUID REFERENCES users(id);
+ALTER TABLE games ADD COLUMN user_blue UUID REFERENCES users(id);
+
+CREATE INDEX idx_games_user_red
+    ON games(user_red);
+
+CREATE INDEX idx_games_user_blue
+    ON games(user_blue);
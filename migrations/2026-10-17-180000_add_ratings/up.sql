@@ -0,0 +1,26 @@
+ALTER TABLE users ADD COLUMN rating DOUBLE PRECISION NOT NULL DEFAULT 1200;
+ALTER TABLE users ADD COLUMN rated_games INTEGER NOT NULL DEFAULT 0;
+
+-- the bots are rated too, as users nobody can log in as
+INSERT INTO users (id, username, password_hash) VALUES
+    ('00000000-0000-0000-0000-000000000001', 'RANDY', ''),
+    ('00000000-0000-0000-0000-000000000002', 'SMART', '');
+
+-- a finished game is rated once, in the same transaction as it's saved with the outcome
+ALTER TABLE games ADD COLUMN rated BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE rating_history (
+                       id UUID PRIMARY KEY,
+                       user_id UUID NOT NULL REFERENCES users(id),
+                       opponent_id UUID NOT NULL REFERENCES users(id),
+                       game_id UUID NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+                       rating_before DOUBLE PRECISION NOT NULL,
+                       rating_after DOUBLE PRECISION NOT NULL,
+                       created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_rating_history_user_id
+    ON rating_history(user_id, created_at);
+
+CREATE INDEX idx_users_rating
+    ON users(rating DESC) WHERE rated_games > 0;
@@ -0,0 +1,7 @@
+-- the existing games get the time of the migration, there is nothing better to go by
+ALTER TABLE games ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE games ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+-- the lobby lists the most recently updated games first
+CREATE INDEX idx_games_updated_at
+    ON games(updated_at DESC);
@@ -0,0 +1,5 @@
+DROP INDEX idx_games_unfinished_updated_at;
+DELETE FROM rating_history WHERE game_id NOT IN (SELECT id FROM games);
+ALTER TABLE rating_history ADD CONSTRAINT rating_history_game_id_fkey FOREIGN KEY (game_id) REFERENCES games(id) ON DELETE CASCADE;
+DROP TABLE archived_games;
+DROP TRIGGER set_updated_at ON games;
@@ -0,0 +1,26 @@
+-- the app sets it, this is for the updates that don't go through it
+SELECT diesel_manage_updated_at('games');
+
+-- finished games are moved here after a while, with only what it takes to show them
+CREATE TABLE archived_games (
+                       id UUID PRIMARY KEY,
+                       state TEXT NOT NULL,
+                       bot_id bot_type,
+                       width SMALLINT NOT NULL,
+                       height SMALLINT NOT NULL,
+                       win_len SMALLINT NOT NULL,
+                       outcome outcome_type NOT NULL,
+                       outcome_winner player_type,
+                       outcome_reason TEXT,
+                       user_red UUID REFERENCES users(id),
+                       user_blue UUID REFERENCES users(id),
+                       created_at TIMESTAMPTZ NOT NULL,
+                       finished_at TIMESTAMPTZ NOT NULL
+);
+
+-- the rating history outlives the games, archived or not
+ALTER TABLE rating_history DROP CONSTRAINT rating_history_game_id_fkey;
+
+-- what the cleanup looks for
+CREATE INDEX idx_games_unfinished_updated_at
+    ON games(updated_at) WHERE outcome IS NULL;
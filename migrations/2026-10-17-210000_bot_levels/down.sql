@@ -0,0 +1,11 @@
+UPDATE users SET rating = 1200 WHERE id = '00000000-0000-0000-0000-000000000002' AND rated_games = 0;
+DELETE FROM rating_history WHERE user_id IN ('00000000-0000-0000-0000-000000000003', '00000000-0000-0000-0000-000000000004', '00000000-0000-0000-0000-000000000005', '00000000-0000-0000-0000-000000000006')
+    OR opponent_id IN ('00000000-0000-0000-0000-000000000003', '00000000-0000-0000-0000-000000000004', '00000000-0000-0000-0000-000000000005', '00000000-0000-0000-0000-000000000006');
+DELETE FROM users WHERE id IN ('00000000-0000-0000-0000-000000000003', '00000000-0000-0000-0000-000000000004', '00000000-0000-0000-0000-000000000005', '00000000-0000-0000-0000-000000000006');
+
+-- the closest there was
+UPDATE games SET bot_id = 'SMART' WHERE bot_id NOT IN ('RANDY', 'SMART');
+UPDATE archived_games SET bot_id = 'SMART' WHERE bot_id NOT IN ('RANDY', 'SMART');
+CREATE TYPE bot_type AS ENUM ('RANDY', 'SMART');
+ALTER TABLE games ALTER COLUMN bot_id TYPE bot_type USING bot_id::bot_type;
+ALTER TABLE archived_games ALTER COLUMN bot_id TYPE bot_type USING bot_id::bot_type;
@@ -0,0 +1,14 @@
+-- any bot goes, without an ALTER TYPE for every new one
+ALTER TABLE games ALTER COLUMN bot_id TYPE TEXT USING bot_id::TEXT;
+ALTER TABLE archived_games ALTER COLUMN bot_id TYPE TEXT USING bot_id::TEXT;
+DROP TYPE bot_type;
+
+INSERT INTO users (id, username, password_hash, rating) VALUES
+    ('00000000-0000-0000-0000-000000000003', 'EASY bot', '', 900),
+    ('00000000-0000-0000-0000-000000000004', 'MEDIUM bot', '', 1200),
+    ('00000000-0000-0000-0000-000000000005', 'HARD bot', '', 1500),
+    ('00000000-0000-0000-0000-000000000006', 'PERFECT bot', '', 1800);
+
+-- SMART is HARD without the blunders and the depth limit, PERFECT is SMART thinking ten times as long. a SMART that has
+-- played already keeps the rating it has earned
+UPDATE users SET rating = 1650 WHERE id = '00000000-0000-0000-0000-000000000002' AND rated_games = 0;
@@ -0,0 +1,2 @@
+DROP TRIGGER notify_game_message ON game_messages;
+DROP FUNCTION notify_game_message();
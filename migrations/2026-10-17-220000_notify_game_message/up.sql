@@ -0,0 +1,10 @@
+-- chat goes to every instance too, the same way as the games
+CREATE OR REPLACE FUNCTION notify_game_message() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('game_message', NEW.id::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_game_message AFTER INSERT ON game_messages
+    FOR EACH ROW EXECUTE PROCEDURE notify_game_message();
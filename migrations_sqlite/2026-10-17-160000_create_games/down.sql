@@ -0,0 +1,2 @@
+DROP TABLE game_messages;
+DROP TABLE games;
@@ -0,0 +1,41 @@
+-- the same games as in postgres, in one go. uuids and timestamps (rfc 3339, utc) are text,
+-- the enums are text with their postgres values
+CREATE TABLE games (
+                       id TEXT PRIMARY KEY NOT NULL,
+                       state TEXT NOT NULL,
+                       player_red TEXT,
+                       player_blue TEXT,
+                       bot_id TEXT CHECK (bot_id IN ('RANDY', 'SMART')),
+                       width SMALLINT NOT NULL,
+                       height SMALLINT NOT NULL,
+                       win_len SMALLINT NOT NULL,
+                       undo_requested_by TEXT CHECK (undo_requested_by IN ('RED', 'BLUE')),
+                       draw_offered_by TEXT CHECK (draw_offered_by IN ('RED', 'BLUE')),
+                       outcome TEXT CHECK (outcome IN ('WIN', 'DRAW', 'RESIGN', 'ABANDON', 'TIMEOUT')),
+                       outcome_winner TEXT CHECK (outcome_winner IN ('RED', 'BLUE')),
+                       outcome_reason TEXT,
+                       clock_base_ms BIGINT,
+                       clock_increment_ms BIGINT NOT NULL DEFAULT 0,
+                       clock_per_move BOOLEAN NOT NULL DEFAULT FALSE,
+                       red_time_left_ms BIGINT,
+                       blue_time_left_ms BIGINT,
+                       clock_started_at TEXT,
+                       version BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE UNIQUE INDEX idx_player_red
+    ON games(player_red);
+
+CREATE UNIQUE INDEX player_blue
+    ON games(player_blue);
+
+CREATE TABLE game_messages (
+                       id TEXT PRIMARY KEY NOT NULL,
+                       game_id TEXT NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+                       player TEXT NOT NULL CHECK (player IN ('RED', 'BLUE')),
+                       text TEXT NOT NULL,
+                       created_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_game_messages_game_id
+    ON game_messages(game_id, created_at);
@@ -0,0 +1,5 @@
+DROP INDEX idx_games_user_blue;
+DROP INDEX idx_games_user_red;
+ALTER TABLE games DROP COLUMN user_blue;
+ALTER TABLE games DROP COLUMN user_red;
+DROP TABLE users;
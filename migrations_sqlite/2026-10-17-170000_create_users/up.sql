@@ -0,0 +1,18 @@
+CREATE TABLE users (
+                       id TEXT PRIMARY KEY NOT NULL,
+                       username TEXT NOT NULL,
+                       password_hash TEXT NOT NULL,
+                       created_at TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX idx_users_username
+    ON users(lower(username));
+
+ALTER TABLE games ADD COLUMN user_red TEXT REFERENCES users(id);
+ALTER TABLE games ADD COLUMN user_blue TEXT REFERENCES users(id);
+
+CREATE INDEX idx_games_user_red
+    ON games(user_red);
+
+CREATE INDEX idx_games_user_blue
+    ON games(user_blue);
@@ -0,0 +1,6 @@
+DROP INDEX idx_users_rating;
+DROP TABLE rating_history;
+ALTER TABLE games DROP COLUMN rated;
+DELETE FROM users WHERE id IN ('00000000-0000-0000-0000-000000000001', '00000000-0000-0000-0000-000000000002');
+ALTER TABLE users DROP COLUMN rated_games;
+ALTER TABLE users DROP COLUMN rating;
@@ -0,0 +1,24 @@
+ALTER TABLE users ADD COLUMN rating DOUBLE NOT NULL DEFAULT 1200;
+ALTER TABLE users ADD COLUMN rated_games INTEGER NOT NULL DEFAULT 0;
+
+INSERT INTO users (id, username, password_hash, created_at) VALUES
+    ('00000000-0000-0000-0000-000000000001', 'RANDY', '', '2026-10-17T18:00:00.000000Z'),
+    ('00000000-0000-0000-0000-000000000002', 'SMART', '', '2026-10-17T18:00:00.000000Z');
+
+ALTER TABLE games ADD COLUMN rated BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE rating_history (
+                       id TEXT PRIMARY KEY NOT NULL,
+                       user_id TEXT NOT NULL REFERENCES users(id),
+                       opponent_id TEXT NOT NULL REFERENCES users(id),
+                       game_id TEXT NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+                       rating_before DOUBLE NOT NULL,
+                       rating_after DOUBLE NOT NULL,
+                       created_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_rating_history_user_id
+    ON rating_history(user_id, created_at);
+
+CREATE INDEX idx_users_rating
+    ON users(rating DESC) WHERE rated_games > 0;
@@ -0,0 +1,3 @@
+DROP INDEX idx_games_updated_at;
+ALTER TABLE games DROP COLUMN updated_at;
+ALTER TABLE games DROP COLUMN created_at;
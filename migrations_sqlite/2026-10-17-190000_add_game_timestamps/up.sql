@@ -0,0 +1,7 @@
+-- sqlite can't add a column with a default that isn't a constant, so the existing games are filled in after
+ALTER TABLE games ADD COLUMN created_at TEXT NOT NULL DEFAULT '';
+ALTER TABLE games ADD COLUMN updated_at TEXT NOT NULL DEFAULT '';
+UPDATE games SET created_at = strftime('%Y-%m-%dT%H:%M:%S.000000Z', 'now'), updated_at = strftime('%Y-%m-%dT%H:%M:%S.000000Z', 'now');
+
+CREATE INDEX idx_games_updated_at
+    ON games(updated_at DESC);
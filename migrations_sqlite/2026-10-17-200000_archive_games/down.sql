@@ -0,0 +1,17 @@
+DROP INDEX idx_games_unfinished_updated_at;
+DELETE FROM rating_history WHERE game_id NOT IN (SELECT id FROM games);
+CREATE TABLE rating_history_old (
+                       id TEXT PRIMARY KEY NOT NULL,
+                       user_id TEXT NOT NULL REFERENCES users(id),
+                       opponent_id TEXT NOT NULL REFERENCES users(id),
+                       game_id TEXT NOT NULL REFERENCES games(id) ON DELETE CASCADE,
+                       rating_before DOUBLE NOT NULL,
+                       rating_after DOUBLE NOT NULL,
+                       created_at TEXT NOT NULL
+);
+INSERT INTO rating_history_old SELECT * FROM rating_history;
+DROP TABLE rating_history;
+ALTER TABLE rating_history_old RENAME TO rating_history;
+CREATE INDEX idx_rating_history_user_id
+    ON rating_history(user_id, created_at);
+DROP TABLE archived_games;
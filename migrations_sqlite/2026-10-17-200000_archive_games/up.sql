@@ -0,0 +1,36 @@
+-- finished games are moved here after a while, with only what it takes to show them
+CREATE TABLE archived_games (
+                       id TEXT PRIMARY KEY NOT NULL,
+                       state TEXT NOT NULL,
+                       bot_id TEXT CHECK (bot_id IN ('RANDY', 'SMART')),
+                       width SMALLINT NOT NULL,
+                       height SMALLINT NOT NULL,
+                       win_len SMALLINT NOT NULL,
+                       outcome TEXT NOT NULL CHECK (outcome IN ('WIN', 'DRAW', 'RESIGN', 'ABANDON', 'TIMEOUT')),
+                       outcome_winner TEXT CHECK (outcome_winner IN ('RED', 'BLUE')),
+                       outcome_reason TEXT,
+                       user_red TEXT REFERENCES users(id),
+                       user_blue TEXT REFERENCES users(id),
+                       created_at TEXT NOT NULL,
+                       finished_at TEXT NOT NULL
+);
+
+-- the rating history outlives the games, archived or not. sqlite can't drop a constraint, so the table is made anew
+CREATE TABLE rating_history_new (
+                       id TEXT PRIMARY KEY NOT NULL,
+                       user_id TEXT NOT NULL REFERENCES users(id),
+                       opponent_id TEXT NOT NULL REFERENCES users(id),
+                       game_id TEXT NOT NULL,
+                       rating_before DOUBLE NOT NULL,
+                       rating_after DOUBLE NOT NULL,
+                       created_at TEXT NOT NULL
+);
+INSERT INTO rating_history_new SELECT * FROM rating_history;
+DROP TABLE rating_history;
+ALTER TABLE rating_history_new RENAME TO rating_history;
+
+CREATE INDEX idx_rating_history_user_id
+    ON rating_history(user_id, created_at);
+
+CREATE INDEX idx_games_unfinished_updated_at
+    ON games(updated_at) WHERE outcome IS NULL;
@@ -0,0 +1,15 @@
+UPDATE users SET rating = 1200 WHERE id = '00000000-0000-0000-0000-000000000002' AND rated_games = 0;
+DELETE FROM rating_history WHERE user_id IN ('00000000-0000-0000-0000-000000000003', '00000000-0000-0000-0000-000000000004', '00000000-0000-0000-0000-000000000005', '00000000-0000-0000-0000-000000000006')
+    OR opponent_id IN ('00000000-0000-0000-0000-000000000003', '00000000-0000-0000-0000-000000000004', '00000000-0000-0000-0000-000000000005', '00000000-0000-0000-0000-000000000006');
+DELETE FROM users WHERE id IN ('00000000-0000-0000-0000-000000000003', '00000000-0000-0000-0000-000000000004', '00000000-0000-0000-0000-000000000005', '00000000-0000-0000-0000-000000000006');
+
+-- the closest there was
+ALTER TABLE games ADD COLUMN bot TEXT CHECK (bot IN ('RANDY', 'SMART'));
+UPDATE games SET bot = CASE WHEN bot_id IN ('RANDY', 'SMART') THEN bot_id WHEN bot_id IS NOT NULL THEN 'SMART' END;
+ALTER TABLE games DROP COLUMN bot_id;
+ALTER TABLE games RENAME COLUMN bot TO bot_id;
+
+ALTER TABLE archived_games ADD COLUMN bot TEXT CHECK (bot IN ('RANDY', 'SMART'));
+UPDATE archived_games SET bot = CASE WHEN bot_id IN ('RANDY', 'SMART') THEN bot_id WHEN bot_id IS NOT NULL THEN 'SMART' END;
+ALTER TABLE archived_games DROP COLUMN bot_id;
+ALTER TABLE archived_games RENAME COLUMN bot TO bot_id;
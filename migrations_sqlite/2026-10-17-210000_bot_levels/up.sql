@@ -0,0 +1,20 @@
+-- any bot goes, without a migration for every new one. sqlite can't drop a constraint, so the column is made anew
+ALTER TABLE games ADD COLUMN bot TEXT;
+UPDATE games SET bot = bot_id;
+ALTER TABLE games DROP COLUMN bot_id;
+ALTER TABLE games RENAME COLUMN bot TO bot_id;
+
+ALTER TABLE archived_games ADD COLUMN bot TEXT;
+UPDATE archived_games SET bot = bot_id;
+ALTER TABLE archived_games DROP COLUMN bot_id;
+ALTER TABLE archived_games RENAME COLUMN bot TO bot_id;
+
+INSERT INTO users (id, username, password_hash, created_at, rating) VALUES
+    ('00000000-0000-0000-0000-000000000003', 'EASY bot', '', '2026-10-17T21:00:00.000000Z', 900),
+    ('00000000-0000-0000-0000-000000000004', 'MEDIUM bot', '', '2026-10-17T21:00:00.000000Z', 1200),
+    ('00000000-0000-0000-0000-000000000005', 'HARD bot', '', '2026-10-17T21:00:00.000000Z', 1500),
+    ('00000000-0000-0000-0000-000000000006', 'PERFECT bot', '', '2026-10-17T21:00:00.000000Z', 1800);
+
+-- SMART is HARD without the blunders and the depth limit, PERFECT is SMART thinking ten times as long. a SMART that has
+-- played already keeps the rating it has earned
+UPDATE users SET rating = 1650 WHERE id = '00000000-0000-0000-0000-000000000002' AND rated_games = 0;
@@ -0,0 +1,168 @@
+// players with a name and a password, to find their games from anywhere. the auth token is a JWT signed with JWT_SECRET,
+// so any instance can check it without going to the database. it's good for AUTH_TOKEN_TTL_HOURS (a week by default)
+
+use std::env;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{rand_core::OsRng, SaltString};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::db::UserId;
+use crate::db_schema::DbUser;
+use crate::error::GameError;
+use crate::ratings::DEFAULT_RATING;
+use crate::store::Store;
+
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 20;
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_PASSWORD_LEN: usize = 128;
+const DEFAULT_TOKEN_TTL_HOURS: i64 = 24 * 7;
+
+struct Keys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    ttl: Duration,
+}
+
+static KEYS: Lazy<Keys> = Lazy::new(|| {
+    let secret = env::var("JWT_SECRET").map(String::into_bytes).unwrap_or_else(|_| {
+        // fine for trying things out, but nobody stays logged in over a restart, and the other instances won't know the tokens
+        eprintln!("JWT_SECRET is not set, using a random one");
+        rand::random::<[u8; 32]>().to_vec()
+    });
+    let ttl_hours = env::var("AUTH_TOKEN_TTL_HOURS").ok().map(|v| v.parse().expect("AUTH_TOKEN_TTL_HOURS must be a number")).unwrap_or(DEFAULT_TOKEN_TTL_HOURS);
+    Keys {
+        encoding: EncodingKey::from_secret(&secret),
+        decoding: DecodingKey::from_secret(&secret),
+        ttl: Duration::hours(ttl_hours),
+    }
+});
+
+// checked against when there is no such user, so that it takes as long as a wrong password
+static UNKNOWN_USER_HASH: Lazy<String> = Lazy::new(|| hash_password("not anybody's password").unwrap());
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String, // the user id
+    exp: i64,
+}
+
+fn validate_username(username: &str) -> Result<String, GameError> {
+    let username = username.trim();
+    let len = username.chars().count();
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&len) {
+        return Err(GameError::Invalid(format!("Username must be between {} and {} characters", MIN_USERNAME_LEN, MAX_USERNAME_LEN)));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(GameError::Invalid("Username can only have latin letters, digits, _ and -".into()));
+    }
+    Ok(username.to_string())
+}
+
+fn validate_password(password: &str) -> Result<(), GameError> {
+    let len = password.chars().count();
+    if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&len) {
+        return Err(GameError::Invalid(format!("Password must be between {} and {} characters", MIN_PASSWORD_LEN, MAX_PASSWORD_LEN)));
+    }
+    Ok(())
+}
+
+fn hash_password(password: &str) -> Result<String, GameError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt).map_err(|e| e.to_string())?.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()).unwrap_or(false)
+}
+
+// hashing takes a while on purpose, so not on the threads serving requests
+async fn blocking<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> Result<R, GameError> {
+    Ok(tokio::task::spawn_blocking(f).await.map_err(|e| e.to_string())?)
+}
+
+fn issue_token(user_id: &UserId) -> Result<String, GameError> {
+    let claims = Claims { sub: user_id.0.to_string(), exp: (Utc::now() + KEYS.ttl).timestamp() };
+    Ok(encode(&Header::default(), &claims, &KEYS.encoding).map_err(|e| e.to_string())?)
+}
+
+pub fn verify_token(token: &str) -> Result<UserId, GameError> {
+    let data = decode::<Claims>(token, &KEYS.decoding, &Validation::default()).map_err(|_| GameError::Unauthenticated)?;
+    Ok(UserId(Uuid::parse_str(&data.claims.sub).map_err(|_| GameError::Unauthenticated)?))
+}
+
+// the new user and their auth token
+pub async fn register(store: &Store, username: &str, password: &str) -> Result<(DbUser, String), GameError> {
+    let username = validate_username(username)?;
+    validate_password(password)?;
+    let password = password.to_string();
+    let password_hash = blocking(move || hash_password(&password)).await??;
+    let user = store.insert_user(&DbUser {
+        id: UserId(Uuid::new_v4()),
+        username,
+        password_hash,
+        created_at: Utc::now(),
+        rating: DEFAULT_RATING,
+        rated_games: 0,
+    }).await?;
+    let token = issue_token(&user.id)?;
+    Ok((user, token))
+}
+
+pub async fn login(store: &Store, username: &str, password: &str) -> Result<(DbUser, String), GameError> {
+    let user = match store.fetch_user_by_name(username.trim()).await {
+        Ok(user) => Some(user),
+        Err(GameError::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+    let hash = user.as_ref().map(|u| u.password_hash.clone()).unwrap_or_else(|| UNKNOWN_USER_HASH.clone());
+    let password = password.to_string();
+    let verified = blocking(move || verify_password(&password, &hash)).await?;
+    match user {
+        Some(user) if verified => {
+            let token = issue_token(&user.id)?;
+            Ok((user, token))
+        }
+        _ => Err(GameError::WrongCredentials),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::accounts::{hash_password, issue_token, validate_username, verify_password, verify_token};
+    use crate::db::UserId;
+    use crate::error::GameError;
+
+    #[test]
+    fn usernames() {
+        assert_eq!(Ok("bob_42".to_string()), validate_username(" bob_42 "));
+        assert!(validate_username("bo").is_err());
+        assert!(validate_username("bob smith").is_err());
+        assert!(validate_username("боб").is_err());
+        assert!(validate_username(&"b".repeat(21)).is_err());
+    }
+
+    #[test]
+    fn passwords() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("correct horse ", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn tokens() {
+        let user = UserId(Uuid::new_v4());
+        let token = issue_token(&user).unwrap();
+        assert_eq!(Ok(user), verify_token(&token));
+        // the signature no longer matches
+        let mut tampered = token.clone();
+        tampered.pop();
+        assert_eq!(Err(GameError::Unauthenticated), verify_token(&tampered));
+        assert_eq!(Err(GameError::Unauthenticated), verify_token("nope"));
+    }
+}
@@ -1,34 +1,105 @@
 use crate::broker::SimpleBroker;
-use crate::db::{fetch_game_state};
+use crate::store::Store;
 use crate::db_schema::DbGame;
 use crate::game::{GameOperations, GameSerializations, Move, Player, State};
+use std::env;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::Duration;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
 use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use rand::Rng;
 use rand::prelude::SliceRandom;
-use crate::adversary_minimax::{minimax, MINMAX_DEPTH_RESTRICTION};
-use crate::db::update_game_state;
+use crate::adversary_minimax::{minimax, SearchLimits};
 
-#[derive(Debug, Clone, Copy, DbEnum, Eq, PartialEq, async_graphql::Enum)]
-#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
+const DEFAULT_BOT_BUDGET_MS: u64 = 1000;
+const DEFAULT_PERFECT_BOT_BUDGET_MS: u64 = 10000;
+
+fn budget_from_env(name: &str, default_ms: u64) -> Duration {
+    Duration::from_millis(env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default_ms))
+}
+
+// how long the minimax bots think about a turn
+static BOT_BUDGET: Lazy<Duration> = Lazy::new(|| budget_from_env("BOT_BUDGET_MS", DEFAULT_BOT_BUDGET_MS));
+// PERFECT takes its time; it stops earlier once it has searched to the end of the game. the game lock it holds meanwhile
+// isn't a pooled connection, see PgStore::try_lock_game
+static PERFECT_BOT_BUDGET: Lazy<Duration> = Lazy::new(|| budget_from_env("PERFECT_BOT_BUDGET_MS", DEFAULT_PERFECT_BOT_BUDGET_MS));
+
+// stored as text, so that a new bot doesn't take a migration
+#[derive(Debug, Clone, Copy, Eq, PartialEq, AsExpression, FromSqlRow, async_graphql::Enum)]
+#[sql_type = "Text"]
 pub enum BotId {
-    RANDY, SMART
+    RANDY, SMART, EASY, MEDIUM, HARD, PERFECT
 }
 
-impl From<String> for BotId {
-    fn from(input: String) -> Self {
-        match input.as_str() {
-            "RANDY" => BotId::RANDY,
-            "SMART" => BotId::SMART,
-            _ => panic!("Unknown bot id"),
+// how a minimax bot is made weaker: it looks only that many turns ahead, misjudges the positions there by up to
+// noise, and now and then plays a random move. the stronger ones are only limited by how long they think
+struct Strength {
+    budget: Duration,
+    max_depth: u8,
+    noise: i32,
+    blunder_rate: f64,
+}
+
+impl BotId {
+    pub const ALL: [BotId; 6] = [BotId::RANDY, BotId::SMART, BotId::EASY, BotId::MEDIUM, BotId::HARD, BotId::PERFECT];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BotId::RANDY => "RANDY",
+            BotId::SMART => "SMART",
+            BotId::EASY => "EASY",
+            BotId::MEDIUM => "MEDIUM",
+            BotId::HARD => "HARD",
+            BotId::PERFECT => "PERFECT",
+        }
+    }
+
+    fn strength(&self) -> Option<Strength> {
+        match self {
+            BotId::RANDY => None,
+            BotId::EASY => Some(Strength { budget: *BOT_BUDGET, max_depth: 2, noise: 60, blunder_rate: 0.3 }),
+            BotId::MEDIUM => Some(Strength { budget: *BOT_BUDGET, max_depth: 4, noise: 20, blunder_rate: 0.1 }),
+            BotId::HARD => Some(Strength { budget: *BOT_BUDGET, max_depth: 8, noise: 5, blunder_rate: 0.02 }),
+            BotId::SMART => Some(Strength { budget: *BOT_BUDGET, max_depth: u8::MAX, noise: 0, blunder_rate: 0.0 }),
+            BotId::PERFECT => Some(Strength { budget: *PERFECT_BOT_BUDGET, max_depth: u8::MAX, noise: 0, blunder_rate: 0.0 }),
         }
     }
 }
 
-pub async fn run_subscribe_bots() {
-    SimpleBroker::<DbGame>::subscribe().for_each(|g| async move { try_bot(&g).await }).await;
+impl FromStr for BotId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BotId::ALL.into_iter().find(|bot| bot.as_str() == s).ok_or_else(|| format!("Unknown bot {}", s))
+    }
+}
+
+impl ToSql<Text, Pg> for BotId {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<Text, Pg>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for BotId {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let s: String = FromSql::<Text, Pg>::from_sql(bytes)?;
+        Ok(s.parse()?)
+    }
+}
+
+pub async fn run_subscribe_bots(store: Store) {
+    SimpleBroker::<DbGame>::subscribe_latest().for_each(|g| {
+        let store = store.clone();
+        async move { try_bot(&store, &g).await }
+    }).await;
 }
 
 fn bot_can_move(db_game: &DbGame) -> bool {
-    if db_game.bot_id.is_none() {
+    if db_game.bot_id.is_none() || db_game.outcome.is_some() {
         return false;
     }
     // only one player
@@ -37,7 +108,7 @@ fn bot_can_move(db_game: &DbGame) -> bool {
         return false;
     }
     // let adversary = &adversaries[0];
-    let state = State::deserialize(&db_game.state).unwrap();
+    let state = db_game.game().unwrap();
     if state.next_player().is_err() {
         return false;
     }
@@ -56,20 +127,37 @@ fn bot_can_move(db_game: &DbGame) -> bool {
     return true;
 }
 
-pub async fn try_bot(db_game: &DbGame) {
+pub async fn try_bot(store: &Store, db_game: &DbGame) {
     if !bot_can_move(db_game) {
         return;
     }
-    let bot_id = BotId::try_from(db_game.bot_id.clone().unwrap()).unwrap();
-    let mut state = State::deserialize(&db_game.state).unwrap();
+    // every instance hears about the update with the postgres broker, only one gets to answer it
+    let _lock = match store.try_lock_game(&db_game.id).await {
+        Ok(Some(lock)) => lock,
+        _ => return,
+    };
+    // and the lock could come after the answer was given already
+    let db_game = &match store.fetch_game_state(&db_game.id).await {
+        Ok(fresh) if fresh.state == db_game.state && bot_can_move(&fresh) => fresh,
+        _ => return,
+    };
+    let bot_id = db_game.bot_id.unwrap();
+    let mut state = db_game.game().unwrap();
     let player = state.next_player().unwrap();
 
-    let bmove = bot_move(&bot_id, &state);
+    // the search takes a while, keep it off the runtime
+    let bmove = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || bot_move(&bot_id, &state)).await.unwrap_or(None)
+    };
 
     match bmove {
         Some(m) => {
             state.push((player, m.0, m.1)).unwrap(); // there is a possible move, safe to unwrap
-            update_game_state(&db_game.id, state.serialize()).await.unwrap();
+            // the player could have taken their turn back meanwhile, the bot gets the next update anyways
+            if let Err(e) = store.update_game_state(db_game, state.serialize()).await {
+                eprintln!("bot: {}", e.message());
+            }
         }
         None => {
             return;
@@ -85,28 +173,15 @@ fn randy(game: &State) -> Option<Move> {
 }
 
 fn bot_move(bot_id: &BotId, game: &State) -> Option<Move> {
-    match bot_id {
-        BotId::RANDY => {
-            randy(game)
-        }
-        BotId::SMART => {
-            return match game.next_player() {
-                Err(_) => {
-                    None
-                }
-                Ok(player) => {
-                    // first 2 turns are for Randy
-                    if game.current_depth() < 2 {
-                        randy(game)
-                    } else if game.depth_left() > MINMAX_DEPTH_RESTRICTION {
-                        randy(game)
-                    } else {
-                        minimax(&mut game.clone())
-                    }
-
-                }
-            }
-        }
+    if game.next_player().is_err() {
+        return None;
     }
+    let strength = match bot_id.strength() {
+        Some(strength) => strength,
+        None => return randy(game),
+    };
+    if rand::thread_rng().gen_bool(strength.blunder_rate) {
+        return randy(game);
+    }
+    minimax(game, &SearchLimits { budget: strength.budget, max_depth: strength.max_depth, noise: strength.noise })
 }
-
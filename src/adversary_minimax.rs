@@ -1,99 +1,268 @@
-use std::cell::Cell;
-use std::cmp::{max, min};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicI32, Ordering};
-use crate::db::GameStateSerialized;
-use crate::game::{Coords, GameOperations, GameSerializations, MatrixOperations, Move, Player, State, WIN_LEN};
-use moka::sync::Cache;
+// the minimax bots: iterative deepening negamax, one turn deeper at a time until the time is up or the depth limit.
+// a position where nobody has won by the horizon is scored by expectimax
 
-pub const MINMAX_DEPTH_RESTRICTION: u8 = 15;
+use std::cmp::{max, min, Reverse};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+use rand::Rng;
+use crate::bitboard::Bitboard;
+use crate::game::{Coords, GameOperations, MatrixOperations, Move, Player, Side, State, MAX_DIM};
+use crate::transposition::{Bound, Entry, TranspositionTable};
 
-pub (crate) fn minimax(game: &State) -> Option<Move> {
-    if game.next_player().is_err() {
-        return None;
-    }
-    let mut hm = Cache::new(10000);
-    let weak = false;
-    minimax_recursion(&mut game.clone(), game.next_player().unwrap(), &Arc::new(&mut hm), if weak { -1 } else { game.size_x() as i32 * game.size_y() as i32 / 2 * -1 }, if weak { 1 } else { game.size_x() as i32 * game.size_y() as i32 / 2 }, Some(MINMAX_DEPTH_RESTRICTION)).0
+const TRANSPOSITION_TABLE_SIZE: usize = 1 << 20;
+const HEURISTIC_CACHE_SIZE: usize = 1 << 18;
+// a won game is worth more than expectimax could ever say about an unfinished one, and the sooner it's won the better
+const WIN_SCALE: i32 = 256;
+const HEURISTIC_MAX: i32 = WIN_SCALE - 1;
+const INFINITY: i32 = i16::MAX as i32; // scores go to the transposition table as i16
+const KILLERS: usize = 2;
+const MOVES: usize = MAX_DIM as usize * 2;
+// looking at the position after a move costs as much as a leaf does, unless it's cached; only worth it higher up the tree
+const HEURISTIC_ORDERING_DEPTH: u8 = 3;
+
+pub (crate) struct SearchLimits {
+    pub budget: Duration,
+    pub max_depth: u8,
+    pub noise: i32, // up to that much either way is added to the score of a position at the horizon, at random
 }
 
-// collect potential scores per 4-cell windows, weighting extremes up
-fn expectimax(game: &State, player: Player) -> i32 {
-    fn window_score(game: &State, player: Player, window: &[Coords]) -> i32 {
-        let mut occurrences: i32 = 0;
-        let mut last_player: Option<Player> = None;
-        let mut non_homogenous = false;
-        for c in window {
-            let cell = game.get_cell(c.0, c.1).unwrap();
-            if cell == None {
-                continue;
-            }
-            if last_player.is_some() && last_player != cell {
-                non_homogenous = true;
-                break;
-            }
-            if last_player == None {
-                last_player = cell;
+struct Search {
+    table: TranspositionTable,
+    heuristics: TranspositionTable, // expectimax for red, per position
+    windows: Vec<Vec<Coords>>,
+    noise: i32,
+    deadline: Instant,
+    stopped: AtomicBool,
+    ordering: bool,
+    killers: Vec<[AtomicU8; KILLERS]>, // per turn of the game, the last moves that cut the search off there; 0 is none
+    history: Vec<AtomicU64>, // per player and move, how much cutting off it has done anywhere
+    nodes: AtomicU64,
+}
+
+fn move_index(m: Move) -> usize {
+    m.0 as usize * 2 + (m.1 == Side::Right) as usize
+}
+
+impl Search {
+    fn new(board: &Bitboard, limits: &SearchLimits, ordering: bool) -> Search {
+        Search {
+            table: TranspositionTable::new(TRANSPOSITION_TABLE_SIZE),
+            heuristics: TranspositionTable::new(HEURISTIC_CACHE_SIZE),
+            windows: windows(board),
+            noise: limits.noise,
+            deadline: Instant::now() + limits.budget,
+            stopped: AtomicBool::new(false),
+            ordering,
+            killers: (0..=board.max_depth()).map(|_| Default::default()).collect(),
+            history: (0..2 * MOVES).map(|_| Default::default()).collect(),
+            nodes: AtomicU64::new(0),
+        }
+    }
+
+    // ordering looks at the same children in every iteration, and they are the leaves of the next one
+    fn heuristic(&self, game: &Bitboard, player: Player) -> i32 {
+        let (key, _) = game.key();
+        let red = match self.heuristics.probe(key) {
+            Some(entry) => entry.score,
+            None => {
+                let score = expectimax_windows(game, Player::Red, &self.windows);
+                self.heuristics.store(key, Entry { score, depth: 0, bound: Bound::Exact, best: None });
+                score
             }
-            occurrences += 1;
+        };
+        if player == Player::Red { red } else { -red }
+    }
+
+    fn history(&self, player: Player, m: Move) -> &AtomicU64 {
+        &self.history[player as usize * MOVES + move_index(m)]
+    }
+
+    fn is_killer(&self, ply: u8, m: Move) -> bool {
+        self.killers[ply as usize].iter().any(|k| k.load(Ordering::Relaxed) == move_index(m) as u8 + 1)
+    }
+
+    // the deeper the search it has cut off, the more it's worth
+    fn record_cutoff(&self, ply: u8, player: Player, m: Move, depth: u8) {
+        if !self.is_killer(ply, m) {
+            let killers = &self.killers[ply as usize];
+            killers[1].store(killers[0].load(Ordering::Relaxed), Ordering::Relaxed);
+            killers[0].store(move_index(m) as u8 + 1, Ordering::Relaxed);
         }
-        // todo better score function
-        if non_homogenous {
-            return 0;
+        self.history(player, m).fetch_add(depth as u64 * depth as u64, Ordering::Relaxed);
+    }
+
+    fn out_of_time(&self) -> bool {
+        if self.stopped.load(Ordering::Relaxed) {
+            return true;
         }
-        if last_player.is_none() {
-            return 0;
+        if Instant::now() >= self.deadline {
+            self.stopped.store(true, Ordering::Relaxed);
+            return true;
         }
-        let signum = if player == last_player.unwrap() { 1 } else { -1 };
-        // weight up "one left to win" considerably
-        if occurrences == 3 {
-            return 30 * signum;
+        false
+    }
+}
+
+// the best move the search has come up with in the budget; any move at all if it hasn't come up with anything
+pub (crate) fn minimax(game: &State, limits: &SearchLimits) -> Option<Move> {
+    iterative_deepening(game.board(), limits, true).0
+}
+
+// and how many positions it has taken
+fn iterative_deepening(board: &Bitboard, limits: &SearchLimits, ordering: bool) -> (Option<Move>, u64) {
+    let mut board = *board;
+    let mut best = match board.possible_moves().first() {
+        Some(&m) => m,
+        None => return (None, 0),
+    };
+    let search = Search::new(&board, limits, ordering);
+    for depth in 1..=min(board.depth_left(), limits.max_depth) {
+        // an unfinished depth is thrown away, the moves it has looked at first aren't the better ones
+        let (m, score) = match minimax_recursion(&mut board, &search, -INFINITY, INFINITY, depth) {
+            (m, Some(score)) => (m, score),
+            (_, None) => break,
+        };
+        if let Some(m) = m {
+            best = m;
         }
-        if occurrences == 2 {
-            return 4 * signum;
+        // somebody wins whatever happens
+        if score.abs() >= WIN_SCALE {
+            break;
         }
-        return occurrences * signum;
-    }
-    let score = game.lines().iter().map(|lines| lines.concat()).map(|line| {
-        let windows = line.windows(WIN_LEN as usize);
-        windows.map(|window| {
-            window_score(game, player, window)
-        }).sum::<i32>()
-    }).sum();
+    }
+    (Some(best), search.nodes.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+fn expectimax<T: GameOperations + MatrixOperations>(game: &T, player: Player) -> i32 {
+    expectimax_windows(game, player, &windows(game))
+}
+
+// the windows only depend on the field size, so the search gets them once
+fn windows<T: GameOperations + MatrixOperations>(game: &T) -> Vec<Vec<Coords>> {
+    game.lines().iter().map(|lines| lines.concat()).flat_map(|line| {
+        line.windows(game.win_len() as usize).map(|window| window.to_vec()).collect::<Vec<_>>()
+    }).collect()
+}
+
+fn window_score<T: GameOperations + MatrixOperations>(game: &T, player: Player, window: &[Coords]) -> i32 {
+    let mut occurrences: i32 = 0;
+    let mut last_player: Option<Player> = None;
+    let mut non_homogenous = false;
+    for c in window {
+        let cell = game.get_cell(c.0, c.1).unwrap();
+        if cell == None {
+            continue;
+        }
+        if last_player.is_some() && last_player != cell {
+            non_homogenous = true;
+            break;
+        }
+        if last_player == None {
+            last_player = cell;
+        }
+        occurrences += 1;
+    }
+    // todo better score function
+    if non_homogenous {
+        return 0;
+    }
+    if last_player.is_none() {
+        return 0;
+    }
+    let signum = if player == last_player.unwrap() { 1 } else { -1 };
+    let win_len = game.win_len() as i32;
+    // weight up "one left to win" considerably
+    if occurrences == win_len - 1 {
+        return 30 * signum;
+    }
+    if occurrences == win_len - 2 {
+        return 4 * signum;
+    }
+    return occurrences * signum;
+}
+
+// collect potential scores per win-length windows, weighting extremes up. the same for both players but the sign
+fn expectimax_windows<T: GameOperations + MatrixOperations>(game: &T, player: Player, windows: &[Vec<Coords>]) -> i32 {
+    let score = windows.iter().map(|window| window_score(game, player, window)).sum();
     min(max(score, -1 as i32 * game.size_y() as i32 * game.size_x() as i32 - 1), game.size_y() as i32 * game.size_x() as i32 + 1) // todo really scale up/down to game size i.e. https://stackoverflow.com/questions/5294955/how-to-scale-down-a-range-of-numbers-with-a-known-min-and-max-value
 }
 
-fn minimax_recursion(game: &mut State, player: Player,
-                     solutions_done: &Arc<&mut Cache<String, (Option<Move>, Option<i32>)>>,
+fn mirror_move(m: Move, mirrored: bool) -> Move {
+    if mirrored { (m.0, m.1.other()) } else { m }
+}
+
+// the moves most likely to cut the search off first: the ones that stop the opponent from winning, the best one the last
+// time here, the ones that have cut it off after as many turns elsewhere, and the rest by how the position looks after them and how
+// much cutting off they have done
+fn order_moves(game: &Bitboard, search: &Search, moves: Vec<Move>, hint: Option<Move>, depth: u8) -> Vec<Move> {
+    if !search.ordering {
+        return moves;
+    }
+    let player = game.next_player().unwrap();
+    let ply = game.current_depth();
+    let mut keyed = moves.into_iter().map(|m| {
+        let class = if game.completes_line(player.other(), m) {
+            3
+        } else if hint == Some(m) {
+            2
+        } else if search.is_killer(ply, m) {
+            1
+        } else {
+            0
+        };
+        let heuristic = if depth >= HEURISTIC_ORDERING_DEPTH {
+            let mut child = *game;
+            child.push_move(m).unwrap();
+            search.heuristic(&child, player)
+        } else {
+            0
+        };
+        ((class, heuristic, search.history(player, m).load(Ordering::Relaxed)), m)
+    }).collect::<Vec<_>>();
+    // stable, so it's still the middle first otherwise
+    keyed.sort_by_key(|&(key, _)| Reverse(key));
+    keyed.into_iter().map(|(_, m)| m).collect()
+}
+
+fn win_score(game: &Bitboard) -> i32 {
+    (game.size_x() as i32 * game.size_y() as i32 + 1 - game.current_depth() as i32) / 2 * WIN_SCALE
+}
+
+// the score is for the player to move; none if the time ran out
+fn minimax_recursion(game: &mut Bitboard,
+                     search: &Search,
                      mut alpha: i32,
                      mut beta: i32,
-                     recommended_depth: Option<u8>) -> (Option<Move>, Option<i32>) {
+                     depth: u8) -> (Option<Move>, Option<i32>) {
     use rayon::prelude::*;
-    if recommended_depth.is_some() && recommended_depth.unwrap() == 0 {
+    if search.out_of_time() {
         return (None, None);
     }
-    let mut possible_moves = game.possible_moves(); // so the caller won't trick us with a wrong depth
+    search.nodes.fetch_add(1, Ordering::Relaxed);
+    let possible_moves = game.possible_moves(); // so the caller won't trick us with a wrong depth
     if possible_moves.is_empty() {
         // last player supposed to be here when possible_moves is exhausted
         return (None, Some(0));
     }
-    if game.is_stalemate() {
-        return (None, Some(0));
-    }
     // try a winning move
+    let player = game.next_player().unwrap();
     for m in possible_moves.iter() {
-        game.push_move(m.clone()).unwrap();
-        let is_win = game.try_winner().is_some();
-        game.pop().unwrap();
-
-        if is_win {
-            return (Some(m.clone()),
-                    Some((game.size_x() as i32 * game.size_y() as i32 + 1 - game.current_depth() as i32) / 2));
+        if game.is_turn_winning(&(player, m.0, m.1)) {
+            return (Some(*m), Some(win_score(game)));
+        }
+    }
+    if depth == 0 {
+        let mut score = search.heuristic(game, player);
+        if search.noise > 0 {
+            score = (score + rand::thread_rng().gen_range(-search.noise..=search.noise)).clamp(-HEURISTIC_MAX, HEURISTIC_MAX);
         }
+        return (None, Some(score));
     }
 
-    let mut max = (game.size_x() as i32 * game.size_y() as i32 - 1 - game.current_depth() as i32) / 2;
+    // the best we could do is to win with our next turn
+    let max = (game.size_x() as i32 * game.size_y() as i32 - 1 - game.current_depth() as i32) / 2 * WIN_SCALE + HEURISTIC_MAX;
     if beta > max {
         beta = max; // there is no need to keep beta above our max possible score.
         if alpha >= beta {
@@ -101,59 +270,86 @@ fn minimax_recursion(game: &mut State, player: Player,
         } // prune the exploration if the [alpha;beta] window is empty.
     }
 
-    // TODO here goes the turn selection optimisation
-    // possible_moves.sort_by(|a, b| {
-    //     game.push_move(a.clone());
-    //     let a_score = expectimax(game, player);
-    //     game.pop();
-    //     game.push_move(b.clone());
-    //     let b_score = expectimax(game, player);
-    //     game.pop();
-    //     a_score.cmp(&b_score)
-    // });
-    let mut best_move: Mutex<Option<Move>> = Mutex::new(None);
-    let mut new_alpha: AtomicI32 = AtomicI32::new(alpha.clone());
+    let (key, mirrored) = game.key();
+    let entry = search.table.probe(key);
+    // a shallower search still knows which move to look at first
+    let hint = entry.and_then(|e| e.best).map(|m| mirror_move(m, mirrored));
+    if let Some(entry) = entry.filter(|e| e.depth >= depth) {
+        let best = hint;
+        match entry.bound {
+            Bound::Exact => return (best, Some(entry.score)),
+            Bound::Lower => alpha = alpha.max(entry.score),
+            Bound::Upper => beta = beta.min(entry.score),
+        }
+        if alpha >= beta {
+            return (best, Some(entry.score));
+        }
+    }
+
+    let moves = order_moves(game, search, possible_moves, hint, depth);
+    let best_move: Mutex<Option<Move>> = Mutex::new(None);
+    let new_alpha: AtomicI32 = AtomicI32::new(alpha);
 
-    possible_moves.into_par_iter().try_for_each(|m| {
-        let mut try_game = &mut game.clone();
+    let visit = |m: Move| {
+        let try_game = &mut game.clone();
         try_game.push_move(m).unwrap();
-        let hash = try_game.hash_non_historical();
-        let solution_done = solutions_done.get(&hash);
-        let score = if solution_done.is_some() {
-            solution_done.unwrap().1
-        } else {
-            let r = minimax_recursion(try_game, player, solutions_done, beta * -1, new_alpha.load(Ordering::SeqCst) * -1, recommended_depth.map(|d| d - 1)).1.map(|s| s * -1);
-            solutions_done.insert(hash, (Some(m.clone()), r.clone()));
-            r
+        let score = match minimax_recursion(try_game, search, beta * -1, new_alpha.load(Ordering::SeqCst) * -1, depth - 1).1 {
+            Some(s) => s * -1,
+            None => return Err(()),
         };
 
-        if score.is_some() && score.unwrap() >= beta {
-            try_game.pop().unwrap();
-            *best_move.lock().unwrap() = Some(m.clone());
-            new_alpha.store(score.unwrap(), Ordering::SeqCst);
+        let mut best_move = best_move.lock().unwrap();
+        if score >= beta {
+            *best_move = Some(m);
+            new_alpha.store(score, Ordering::SeqCst);
+            search.record_cutoff(game.current_depth(), player, m, depth);
             return Err(());
         }
-        if score.is_some() && score.unwrap() > new_alpha.load(Ordering::SeqCst) {
-            new_alpha.store(score.unwrap(), Ordering::SeqCst);
-            *best_move.lock().unwrap() = Some(m.clone());
+        if score > new_alpha.load(Ordering::SeqCst) {
+            new_alpha.store(score, Ordering::SeqCst);
+            *best_move = Some(m);
         }
-        try_game.pop().unwrap();
-        return Ok(());
-    });
-
-
-    let r = (*best_move.lock().unwrap(), Some(new_alpha.load(Ordering::SeqCst))); r
+        Ok(())
+    };
+    // the first move alone, the rest in parallel: with a good order, its score is what cuts the rest off
+    let (first, rest) = moves.split_first().unwrap();
+    if visit(*first).is_ok() {
+        let _ = rest.par_iter().try_for_each(|&m| visit(m));
+    }
+    // half of the moves weren't looked at
+    if search.stopped.load(Ordering::Relaxed) {
+        return (None, None);
+    }
 
+    let (best, score) = (*best_move.lock().unwrap(), new_alpha.load(Ordering::SeqCst));
+    // a score that isn't above alpha only says that nothing here is better than it
+    let bound = if score >= beta {
+        Bound::Lower
+    } else if best.is_none() {
+        Bound::Upper
+    } else {
+        Bound::Exact
+    };
+    search.table.store(key, Entry { score, depth, bound, best: best.map(|m| mirror_move(m, mirrored)) });
+    (best, Some(score))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::adversary_minimax::{expectimax, minimax};
+    use std::time::{Duration, Instant};
+    use crate::adversary_minimax::{expectimax, iterative_deepening, minimax, SearchLimits};
     use crate::db::GameStateSerialized;
     use crate::game::Side::{Left, Right};
     use crate::game::{GameSerializations, State};
     use crate::game::Player::{Blue, Red};
 
+    // plenty, the small boards are solved long before
+    const BUDGET: &SearchLimits = &SearchLimits { budget: Duration::from_secs(60), max_depth: u8::MAX, noise: 0 };
+
+    fn limits(budget: Duration) -> SearchLimits {
+        SearchLimits { budget, max_depth: u8::MAX, noise: 0 }
+    }
+
     const GAME_OPPORTUNITY: &str = r#"
 1 9 8  2
 3 4 10 11
@@ -210,6 +406,15 @@ mod tests {
 2 0 0 0 0 0 1
 0 0 0 0 0 0 5
 6 0 0 0 0 0 0
+0 0 0 0 0 0 0
+    "#;
+    const GAME_BLOCKER_BIG: &str = r#"
+0 0 0 0 0 4 2
+0 0 0 0 0 0 0
+0 0 0 0 0 0 0
+1 3 5 0 0 0 0
+0 0 0 0 0 0 0
+0 0 0 0 0 0 0
 0 0 0 0 0 0 0
     "#;
     // with more optimisations, uncover more 0s!
@@ -246,36 +451,59 @@ mod tests {
     }
     #[test]
     fn minimax_opportunity() {
-        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_OPPORTUNITY.to_string())).unwrap());
+        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_OPPORTUNITY.to_string())).unwrap(), BUDGET);
         assert_eq!(r, Some((3, Left)));
     }
     #[test]
     fn minimax_opportunity2() {
-        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_OPPORTUNITY2.to_string())).unwrap());
+        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_OPPORTUNITY2.to_string())).unwrap(), BUDGET);
         assert_eq!(r, Some((1, Right)));
     }
     #[test]
     fn minimax_blocker() {
-        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_BLOCKER.to_string())).unwrap());
+        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_BLOCKER.to_string())).unwrap(), BUDGET);
         assert_eq!(r, Some((2, Right)));
     }
     #[test]
     fn minimax_bigger_opportunity() {
-        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_OPPORTUNITY_BIGGER.to_string())).unwrap());
+        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_OPPORTUNITY_BIGGER.to_string())).unwrap(), BUDGET);
         assert_eq!(r, Some((3, Left)));
     }
     #[test]
     fn minimax_real_opportunity() {
-        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_OPPORTUNITY_REAL.to_string())).unwrap());
+        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_OPPORTUNITY_REAL.to_string())).unwrap(), BUDGET);
         assert_eq!(r, Some((3, Right)));
     }
     #[test]
     fn performance() {
-        let r = minimax(&State::deserialize(&GameStateSerialized(PERFORMANCE_TEST.to_string())).unwrap());
+        let game = State::deserialize(&GameStateSerialized(PERFORMANCE_TEST.to_string())).unwrap();
+        // one thread, so that the counts are the same every time
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let (_, unordered) = pool.install(|| iterative_deepening(game.board(), BUDGET, false));
+        let (r, ordered) = pool.install(|| iterative_deepening(game.board(), BUDGET, true));
+        assert!(r.is_some());
+        assert!(ordered * 2 < unordered, "{} positions ordered, {} unordered", ordered, unordered);
+    }
+    #[test]
+    fn big_board() {
+        let started = Instant::now();
+        assert!(minimax(&State::new(7, 7), &limits(Duration::from_millis(200))).is_some());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let r = minimax(&State::deserialize(&GameStateSerialized(GAME_BLOCKER_BIG.to_string())).unwrap(), &limits(Duration::from_millis(500)));
+        assert_eq!(r, Some((3, Left)));
+    }
+    #[test]
+    fn shallow() {
+        // two turns ahead is enough to see the threat, even when misjudging everything else
+        let game = State::deserialize(&GameStateSerialized(GAME_BLOCKER_BIG.to_string())).unwrap();
+        let r = minimax(&game, &SearchLimits { budget: Duration::from_secs(10), max_depth: 2, noise: 60 });
+        assert_eq!(r, Some((3, Left)));
+        let r = minimax(&game, &SearchLimits { budget: Duration::from_secs(10), max_depth: 1, noise: 0 });
+        assert!(r.is_some());
     }
     #[test]
     fn bug_1() {
-        let r = minimax(&State::deserialize(&GameStateSerialized(BUG_1.to_string())).unwrap());
+        let r = minimax(&State::deserialize(&GameStateSerialized(BUG_1.to_string())).unwrap(), BUDGET);
         assert_ne!(r, None);
     }
 }
\ No newline at end of file
@@ -0,0 +1,409 @@
+// the field as a bit mask per player, for the search to make and take back turns in no time.
+// cell (x, y) is bit y * (width + 1) + x; the extra column is always empty, so that no line wraps around to the next row.
+// it also keeps a zobrist hash of itself and of its mirror image, a position and its mirror are worth the same
+
+use crate::error::GameError;
+use crate::game::{Coords, GameOperations, MatrixOperations, Move, Player, Side, Turn, FIRST_PLAYER, MAX_DIM};
+
+const MAX_CELLS: usize = MAX_DIM as usize * MAX_DIM as usize;
+const _: () = assert!((MAX_DIM as usize + 1) * MAX_DIM as usize <= u128::BITS as usize, "the field doesn't fit a u128");
+
+// a random number per player and bit, the same ones every run. splitmix64, which is good enough for that
+const fn zobrist_keys() -> [[u64; 128]; 2] {
+    let mut keys = [[0; 128]; 2];
+    let mut seed: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        keys[i / 128][i % 128] = z ^ (z >> 31);
+        i += 1;
+    }
+    keys
+}
+const ZOBRIST: [[u64; 128]; 2] = zobrist_keys();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bitboard {
+    width: u8,
+    height: u8,
+    win_len: u8,
+    red: u128,
+    blue: u128,
+    // how many cells of each row are filled from the left and from the right. a piece always lands next to
+    // the ones that came from the same side, so a row is the two filled ends with the empty cells in between
+    left: [u8; MAX_DIM as usize],
+    right: [u8; MAX_DIM as usize],
+    moves: [u8; MAX_CELLS], // the bits, in the order the turns were made
+    depth: u8,
+    winner: Option<Player>,
+    hash: u64,
+    mirror_hash: u64, // as if x were width - 1 - x
+}
+
+impl Bitboard {
+    pub fn new(width: u8, height: u8, win_len: u8) -> Bitboard {
+        assert!(width <= MAX_DIM && height <= MAX_DIM, "{}x{} is bigger than a bitboard", width, height);
+        Bitboard {
+            width,
+            height,
+            win_len,
+            red: 0,
+            blue: 0,
+            left: [0; MAX_DIM as usize],
+            right: [0; MAX_DIM as usize],
+            moves: [0; MAX_CELLS],
+            depth: 0,
+            winner: None,
+            hash: 0,
+            mirror_hash: 0,
+        }
+    }
+
+    pub fn with_win_len(mut self, win_len: u8) -> Bitboard {
+        self.win_len = win_len;
+        self.update_winner();
+        self
+    }
+
+    fn stride(&self) -> u8 {
+        self.width + 1
+    }
+
+    fn bit(&self, x: u8, y: u8) -> u8 {
+        y * self.stride() + x
+    }
+
+    fn coords(&self, bit: u8) -> Coords {
+        (bit % self.stride(), bit / self.stride())
+    }
+
+    fn mask(&self, player: Player) -> u128 {
+        match player {
+            Player::Red => self.red,
+            Player::Blue => self.blue,
+        }
+    }
+
+    fn mask_mut(&mut self, player: Player) -> &mut u128 {
+        match player {
+            Player::Red => &mut self.red,
+            Player::Blue => &mut self.blue,
+        }
+    }
+
+    // puts the piece there or takes it away
+    fn toggle(&mut self, player: Player, x: u8, y: u8) {
+        let bit = self.bit(x, y);
+        *self.mask_mut(player) ^= 1 << bit;
+        let keys = &ZOBRIST[player as usize];
+        self.hash ^= keys[bit as usize];
+        self.mirror_hash ^= keys[self.bit(self.width - 1 - x, y) as usize];
+    }
+
+    // the position as a key, whose turn it is follows from it. it's the same for the mirror image, which is
+    // what the bool says, the moves for it have the sides swapped
+    pub fn key(&self) -> (u64, bool) {
+        if self.mirror_hash < self.hash {
+            (self.mirror_hash, true)
+        } else {
+            (self.hash, false)
+        }
+    }
+
+    // win_len in a row in any direction: every bit of the line stays set once shifted by its own offsets
+    fn has_line(&self, mask: u128) -> bool {
+        let stride = self.stride() as u32;
+        [1, stride, stride + 1, stride - 1].iter().any(|&direction| {
+            let mut line = mask;
+            for i in 1..self.win_len as u32 {
+                line &= mask >> (i * direction);
+            }
+            line != 0
+        })
+    }
+
+    fn update_winner(&mut self) {
+        self.winner = self.last_player().ok().filter(|&p| self.has_line(self.mask(p)));
+    }
+
+    // where the piece would land in the row
+    fn landing(&self, y: u8, side: Side) -> Option<u8> {
+        let (left, right) = (self.left[y as usize], self.right[y as usize]);
+        if left + right >= self.width {
+            return None;
+        }
+        Some(match side {
+            Side::Left => left,
+            Side::Right => self.width - right - 1,
+        })
+    }
+
+    fn validate_turn(&self, turn: Turn) -> Result<u8, GameError> {
+        let (player, y, side) = turn;
+        if !self.can_continue() {
+            return Err(GameError::GameOver);
+        }
+        if self.next_player()? != player {
+            return Err(GameError::WrongPlayer);
+        }
+        if y >= self.height {
+            return Err(GameError::OutOfBounds { height: y });
+        }
+        self.landing(y, side).ok_or(GameError::RowFull { height: y, side })
+    }
+
+    pub fn push(&mut self, turn: Turn) -> Result<Coords, GameError> {
+        let (player, y, side) = turn;
+        let x = self.validate_turn(turn)?;
+        match side {
+            Side::Left => self.left[y as usize] += 1,
+            Side::Right => self.right[y as usize] += 1,
+        }
+        self.toggle(player, x, y);
+        self.moves[self.depth as usize] = self.bit(x, y);
+        self.depth += 1;
+        if self.has_line(self.mask(player)) {
+            self.winner = Some(player);
+        }
+        Ok((x, y))
+    }
+
+    pub fn push_move(&mut self, move_: Move) -> Result<Coords, GameError> {
+        let player = self.next_player()?;
+        self.push((player, move_.0, move_.1))
+    }
+
+    pub fn pop(&mut self) -> Result<Coords, GameError> {
+        let player = self.last_player()?;
+        self.depth -= 1;
+        let bit = std::mem::take(&mut self.moves[self.depth as usize]);
+        let (x, y) = self.coords(bit);
+        self.toggle(player, x, y);
+        // the last cell of a row could have come from either side, it's the same row either way
+        if x + 1 == self.left[y as usize] {
+            self.left[y as usize] -= 1;
+        } else {
+            self.right[y as usize] -= 1;
+        }
+        // there was no winner, or the game wouldn't have gone on
+        self.winner = None;
+        Ok((x, y))
+    }
+
+    // whether the piece would make a line for the player, whoever's turn it is
+    pub fn completes_line(&self, player: Player, move_: Move) -> bool {
+        let (y, side) = move_;
+        if y >= self.height {
+            return false;
+        }
+        match self.landing(y, side) {
+            Some(x) => self.has_line(self.mask(player) | 1 << self.bit(x, y)),
+            None => false,
+        }
+    }
+
+    // a turn as it was stored, without knowing which side it came from
+    pub(crate) fn place(&mut self, coords: Coords, player: Player) {
+        let (x, y) = coords;
+        self.toggle(player, x, y);
+        self.moves[self.depth as usize] = self.bit(x, y);
+        self.depth += 1;
+        let filled = |x: u8| (self.red | self.blue) & (1 << self.bit(x, y)) != 0;
+        let left = (0..self.width).take_while(|&x| filled(x)).count() as u8;
+        // a full row is split at the last piece, so that pop can take it back
+        let (left, right) = if left == self.width {
+            (x + 1, self.width - x - 1)
+        } else {
+            (left, (0..self.width).rev().take_while(|&x| filled(x)).count() as u8)
+        };
+        self.left[y as usize] = left;
+        self.right[y as usize] = right;
+        self.update_winner();
+    }
+}
+
+impl MatrixOperations for Bitboard {
+    fn calc_field_index(&self, x: u8, y: u8) -> u8 {
+        self.bit(x, y)
+    }
+    fn get_cell(&self, x: u8, y: u8) -> Result<Option<Player>, String> {
+        if x >= self.width || y >= self.height {
+            return Err(format!("out of bounds {} {}", x, y));
+        }
+        let bit = 1 << self.bit(x, y);
+        Ok(if self.red & bit != 0 {
+            Some(Player::Red)
+        } else if self.blue & bit != 0 {
+            Some(Player::Blue)
+        } else {
+            None
+        })
+    }
+    fn next_cell_towards(&self, direction: Side, y: u8) -> Result<Option<Coords>, GameError> {
+        if y >= self.height {
+            return Err(GameError::OutOfBounds { height: y });
+        }
+        Ok(self.landing(y, direction).map(|x| (x, y)))
+    }
+    fn size_x(&self) -> u8 {
+        self.width
+    }
+    fn size_y(&self) -> u8 {
+        self.height
+    }
+}
+
+impl GameOperations for Bitboard {
+    fn win_len(&self) -> u8 {
+        self.win_len
+    }
+    fn current_depth(&self) -> u8 {
+        self.depth
+    }
+    fn max_depth(&self) -> u8 {
+        self.width * self.height
+    }
+    fn depth_left(&self) -> u8 {
+        self.max_depth() - self.depth
+    }
+    fn next_player(&self) -> Result<Player, GameError> {
+        if !self.can_continue() {
+            return Err(GameError::GameOver);
+        }
+        Ok(if self.depth & 1 == 0 { FIRST_PLAYER } else { FIRST_PLAYER.other() })
+    }
+    fn last_player(&self) -> Result<Player, GameError> {
+        if self.depth == 0 {
+            return Err(GameError::NothingToTakeBack);
+        }
+        Ok(if self.depth & 1 == 1 { FIRST_PLAYER } else { FIRST_PLAYER.other() })
+    }
+    fn can_continue(&self) -> bool {
+        !self.is_finished() && !self.is_stalemate()
+    }
+    fn try_winner(&self) -> Option<Player> {
+        self.winner
+    }
+    fn is_finished(&self) -> bool {
+        self.winner.is_some()
+    }
+    fn is_stalemate(&self) -> bool {
+        !self.is_finished() && self.depth == self.max_depth()
+    }
+    fn possible_moves(&self) -> Vec<Move> {
+        if !self.can_continue() {
+            return Vec::new();
+        }
+        let mut res = Vec::with_capacity(self.height as usize * 2);
+        for i in 0..self.height {
+            // "better turns first" order, where the positions at the middle are prioritized https://github.com/PascalPons/connect4/commit/6caf32a4845bf1478b0d30bebd6366bfea75b7b5
+            let y = ((self.height as i8) / 2 + (1 - 2 * (i % 2) as i8) * (i as i8 + 1) / 2) as u8;
+            let (left, right) = (self.landing(y, Side::Left), self.landing(y, Side::Right));
+            if left.is_some() {
+                res.push((y, Side::Left));
+            }
+            if right.is_some() {
+                res.push((y, Side::Right));
+            }
+        }
+        res
+    }
+    fn is_turn_winning(&self, turn: &Turn) -> bool {
+        self.validate_turn(*turn).is_ok() && self.completes_line(turn.0, (turn.1, turn.2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::seq::SliceRandom;
+    use crate::bitboard::Bitboard;
+    use crate::error::GameError;
+    use crate::game::{GameOperations, MatrixOperations, Player, Side};
+
+    // the slow and obvious way: walk from every cell in every direction
+    fn naive_winner(board: &Bitboard) -> Option<Player> {
+        let player = board.last_player().ok()?;
+        let (width, height, win_len) = (board.size_x() as i8, board.size_y() as i8, board.win_len() as i8);
+        let wins = (0..width).any(|x| (0..height).any(|y| [(1, 0), (0, 1), (1, 1), (1, -1)].iter().any(|&(dx, dy)| {
+            (0..win_len).all(|i| {
+                let (cx, cy) = (x + i * dx, y + i * dy);
+                cx >= 0 && cy >= 0 && cx < width && cy < height && board.get_cell(cx as u8, cy as u8) == Ok(Some(player))
+            })
+        })));
+        if wins { Some(player) } else { None }
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let mut board = Bitboard::new(3, 2, 3);
+        assert_eq!(Ok((0, 0)), board.push((Player::Red, 0, Side::Left)));
+        assert_eq!(Ok((2, 0)), board.push((Player::Blue, 0, Side::Right)));
+        assert_eq!(Ok((1, 0)), board.push((Player::Red, 0, Side::Right)));
+        assert_eq!(Err(GameError::RowFull { height: 0, side: Side::Left }), board.push((Player::Blue, 0, Side::Left)));
+        assert_eq!(Err(GameError::WrongPlayer), board.push((Player::Red, 1, Side::Left)));
+        assert_eq!(Err(GameError::OutOfBounds { height: 2 }), board.push((Player::Blue, 2, Side::Left)));
+        let full = board;
+        assert_eq!(Ok((1, 0)), board.pop());
+        // the middle one can come from the left just as well
+        assert_eq!(Ok((1, 0)), board.push((Player::Red, 0, Side::Left)));
+        assert_eq!(full.key(), board.key());
+        while board.pop().is_ok() {}
+        assert_eq!(Bitboard::new(3, 2, 3), board);
+    }
+
+    #[test]
+    fn mirror_keys() {
+        let mut board = Bitboard::new(5, 4, 4);
+        let mut mirror = board;
+        for (y, side) in [(0, Side::Left), (0, Side::Left), (3, Side::Right), (1, Side::Left)] {
+            board.push_move((y, side)).unwrap();
+            mirror.push_move((y, side.other())).unwrap();
+            assert_eq!(board.key().0, mirror.key().0);
+            assert_ne!(board.key().1, mirror.key().1);
+        }
+        assert_ne!(board.key(), Bitboard::new(5, 4, 4).key());
+        // the same pieces in another order
+        let mut other = Bitboard::new(5, 4, 4);
+        for (y, side) in [(3, Side::Right), (1, Side::Left), (0, Side::Left), (0, Side::Left)] {
+            other.push_move((y, side)).unwrap();
+        }
+        assert_eq!(board.key(), other.key());
+        board.pop().unwrap();
+        assert_ne!(board.key().0, other.key().0);
+    }
+
+    #[test]
+    fn lines_dont_wrap() {
+        // the end of one row and the start of the next one
+        let mut board = Bitboard::new(3, 3, 3);
+        for turn in [(Player::Red, 0, Side::Right), (Player::Blue, 2, Side::Left), (Player::Red, 1, Side::Left), (Player::Blue, 2, Side::Left)] {
+            board.push(turn).unwrap();
+        }
+        assert!(!board.is_turn_winning(&(Player::Red, 1, Side::Left)));
+        board.push((Player::Red, 1, Side::Left)).unwrap();
+        assert_eq!(None, board.try_winner());
+    }
+
+    #[test]
+    fn random_games() {
+        let mut rng = rand::thread_rng();
+        for (width, height, win_len) in [(7, 7, 4), (4, 5, 3), (10, 10, 5), (10, 3, 2), (2, 10, 2)] {
+            for _ in 0..200 {
+                let mut board = Bitboard::new(width, height, win_len);
+                while let Some(&m) = board.possible_moves().choose(&mut rng) {
+                    let winning = board.is_turn_winning(&(board.next_player().unwrap(), m.0, m.1));
+                    let before = board;
+                    board.push_move(m).unwrap();
+                    assert_eq!(naive_winner(&board), board.try_winner(), "{:?}", board);
+                    assert_eq!(winning, board.is_finished());
+                    let mut popped = board;
+                    popped.pop().unwrap();
+                    assert_eq!(before, popped);
+                }
+            }
+        }
+    }
+}
@@ -1,22 +1,135 @@
-// simple broker from the async-graphql Subscriptions example
+// simple broker from the async-graphql Subscriptions example, with bounded queues per subscriber:
+// a slow subscriber loses messages (or the whole subscription) instead of eating up the memory
 
 use std::any::{Any, TypeId};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::env;
 use std::marker::PhantomData;
 use std::pin::Pin;
-use std::sync::Mutex;
-use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
 
-use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use futures_util::{Stream, StreamExt};
+use futures_util::Stream;
 use once_cell::sync::Lazy;
 use slab::Slab;
 
+const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+// what to do with a message that doesn't fit into the subscriber's queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    DropOldest,
+    // keep only the latest message per key, i.e. per game for full game snapshots. falls back to DropOldest for messages without a key
+    Coalesce,
+    // end the subscription, the client has to resubscribe and catch up on its own
+    Disconnect,
+}
+
+pub struct BrokerConfig {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+// BROKER_QUEUE_CAPACITY and BROKER_OVERFLOW=drop_oldest|coalesce|disconnect
+static CONFIG: Lazy<BrokerConfig> = Lazy::new(|| BrokerConfig {
+    capacity: env::var("BROKER_QUEUE_CAPACITY").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_QUEUE_CAPACITY),
+    overflow: match env::var("BROKER_OVERFLOW").as_deref() {
+        Ok("drop_oldest") => Overflow::DropOldest,
+        Ok("coalesce") | Err(_) => Overflow::Coalesce,
+        Ok("disconnect") => Overflow::Disconnect,
+        Ok(other) => panic!("Unknown broker overflow policy {}", other),
+    },
+});
+
+// messages that are fine to replace with a newer one with the same key
+pub trait Coalesce {
+    type Key: Eq;
+    fn coalesce_key(&self) -> Self::Key;
+}
+
+fn same_key<T: Coalesce>(a: &T, b: &T) -> bool {
+    a.coalesce_key() == b.coalesce_key()
+}
+
+#[derive(Default)]
+struct Metrics {
+    published: AtomicU64,
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+    disconnected: AtomicU64,
+    max_lag: AtomicUsize,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(Default::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrokerMetrics {
+    pub published: u64, // per subscriber, a message for 3 subscribers counts 3 times
+    pub dropped: u64,
+    pub coalesced: u64,
+    pub disconnected: u64,
+    pub max_lag: usize, // the longest queue any subscriber has had so far
+}
+
+pub fn metrics() -> BrokerMetrics {
+    BrokerMetrics {
+        published: METRICS.published.load(Ordering::Relaxed),
+        dropped: METRICS.dropped.load(Ordering::Relaxed),
+        coalesced: METRICS.coalesced.load(Ordering::Relaxed),
+        disconnected: METRICS.disconnected.load(Ordering::Relaxed),
+        max_lag: METRICS.max_lag.load(Ordering::Relaxed),
+    }
+}
+
 static SUBSCRIBERS: Lazy<Mutex<HashMap<TypeId, Box<dyn Any + Send>>>> = Lazy::new(Default::default);
 
-struct Senders<T>(Slab<UnboundedSender<T>>);
+type Filter<T> = Box<dyn Fn(&T) -> bool + Send>;
+
+struct Queue<T> {
+    // checked before queueing, so the other subscribers' traffic never takes the room of the messages this one wants
+    filter: Filter<T>,
+    messages: VecDeque<T>,
+    capacity: usize,
+    overflow: Overflow,
+    same_key: Option<fn(&T, &T) -> bool>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl<T> Queue<T> {
+    fn push(&mut self, msg: T) {
+        if self.closed {
+            return;
+        }
+        METRICS.published.fetch_add(1, Ordering::Relaxed);
+        if let (Overflow::Coalesce, Some(same_key)) = (self.overflow, self.same_key) {
+            if let Some(queued) = self.messages.iter_mut().find(|m| same_key(m, &msg)) {
+                *queued = msg;
+                METRICS.coalesced.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        if self.messages.len() >= self.capacity {
+            if self.overflow == Overflow::Disconnect {
+                self.messages.clear();
+                self.closed = true;
+                METRICS.disconnected.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.messages.pop_front();
+                self.messages.push_back(msg);
+                METRICS.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        } else {
+            self.messages.push_back(msg);
+            METRICS.max_lag.fetch_max(self.messages.len(), Ordering::Relaxed);
+        }
+    }
+}
 
-struct BrokerStream<T: Sync + Send + Clone + 'static>(usize, UnboundedReceiver<T>);
+struct Senders<T>(Slab<Arc<Mutex<Queue<T>>>>);
+
+struct BrokerStream<T: Sync + Send + Clone + 'static>(usize, Arc<Mutex<Queue<T>>>);
 
 fn with_senders<T, F, R>(f: F) -> R
     where
@@ -39,8 +152,16 @@ impl<T: Sync + Send + Clone + 'static> Drop for BrokerStream<T> {
 impl<T: Sync + Send + Clone + 'static> Stream for BrokerStream<T> {
     type Item = T;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.1.poll_next_unpin(cx)
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut queue = self.1.lock().unwrap();
+        if let Some(msg) = queue.messages.pop_front() {
+            return Poll::Ready(Some(msg));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
     }
 }
 
@@ -51,18 +172,114 @@ impl<T: Sync + Send + Clone + 'static> SimpleBroker<T> {
     /// Publish a message that all subscription streams can receive.
     pub fn publish(msg: T) {
         with_senders::<T, _, _>(|senders| {
-            for (_, sender) in senders.0.iter_mut() {
-                sender.start_send(msg.clone()).ok();
+            for (_, queue) in senders.0.iter_mut() {
+                let mut queue = queue.lock().unwrap();
+                if !(queue.filter)(&msg) {
+                    continue;
+                }
+                queue.push(msg.clone());
+                if let Some(waker) = queue.waker.take() {
+                    waker.wake();
+                }
             }
         });
     }
 
-    /// Subscribe to the message of the specified type and returns a `Stream`.
-    pub fn subscribe() -> impl Stream<Item = T> {
+    /// Subscribe to the messages of the specified type the filter accepts and returns a `Stream`.
+    pub fn subscribe_where(filter: impl Fn(&T) -> bool + Send + 'static) -> impl Stream<Item = T> {
+        Self::subscribe_with(CONFIG.capacity, CONFIG.overflow, None, Box::new(filter))
+    }
+
+    fn subscribe_with(capacity: usize, overflow: Overflow, same_key: Option<fn(&T, &T) -> bool>, filter: Filter<T>) -> BrokerStream<T> {
         with_senders::<T, _, _>(|senders| {
-            let (tx, rx) = mpsc::unbounded();
-            let id = senders.0.insert(tx);
-            BrokerStream(id, rx)
+            let queue = Arc::new(Mutex::new(Queue {
+                filter,
+                messages: VecDeque::new(),
+                capacity,
+                overflow,
+                same_key,
+                closed: false,
+                waker: None,
+            }));
+            let id = senders.0.insert(queue.clone());
+            BrokerStream(id, queue)
         })
     }
-}
\ No newline at end of file
+}
+
+impl<T: Coalesce + Sync + Send + Clone + 'static> SimpleBroker<T> {
+    /// Subscribe to all the messages, the configured policy can coalesce them by their key.
+    pub fn subscribe_coalesced() -> impl Stream<Item = T> {
+        Self::subscribe_coalesced_where(|_| true)
+    }
+
+    /// Same as `subscribe_coalesced`, but only for the messages the filter accepts, like `subscribe_where`.
+    pub fn subscribe_coalesced_where(filter: impl Fn(&T) -> bool + Send + 'static) -> impl Stream<Item = T> {
+        Self::subscribe_with(CONFIG.capacity, CONFIG.overflow, Some(same_key::<T>), Box::new(filter))
+    }
+
+    /// Never loses the latest message per key, whatever the configuration is; for internal consumers.
+    pub fn subscribe_latest() -> impl Stream<Item = T> {
+        Self::subscribe_with(usize::MAX, Overflow::Coalesce, Some(same_key::<T>), Box::new(|_| true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use futures_util::StreamExt;
+    use crate::broker::{Coalesce, METRICS, Overflow, SimpleBroker};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Msg(u8, u8); // key, value
+
+    impl Coalesce for Msg {
+        type Key = u8;
+        fn coalesce_key(&self) -> u8 {
+            self.0
+        }
+    }
+
+    fn drain<S: futures_util::Stream<Item = Msg> + Unpin>(stream: &mut S) -> Vec<Msg> {
+        let mut res = vec![];
+        while let Some(Some(m)) = stream.next().now_or_never() {
+            res.push(m);
+        }
+        res
+    }
+
+    use futures_util::FutureExt;
+
+    #[test]
+    fn overflow_policies() {
+        let mut dropping = Box::pin(SimpleBroker::<Msg>::subscribe_with(2, Overflow::DropOldest, None, Box::new(|_| true)));
+        let mut coalescing = Box::pin(SimpleBroker::<Msg>::subscribe_with(2, Overflow::Coalesce, Some(super::same_key::<Msg>), Box::new(|_| true)));
+        let mut disconnecting = Box::pin(SimpleBroker::<Msg>::subscribe_with(2, Overflow::Disconnect, None, Box::new(|_| true)));
+        let disconnected = METRICS.disconnected.load(Ordering::Relaxed);
+        for m in [Msg(1, 1), Msg(2, 1), Msg(1, 2), Msg(2, 2), Msg(3, 1)] {
+            SimpleBroker::publish(m);
+        }
+        assert_eq!(vec![Msg(2, 2), Msg(3, 1)], drain(&mut dropping));
+        assert_eq!(vec![Msg(2, 2), Msg(3, 1)], drain(&mut coalescing));
+        assert_eq!(Some(None), disconnecting.next().now_or_never());
+        assert!(METRICS.disconnected.load(Ordering::Relaxed) > disconnected);
+        SimpleBroker::publish(Msg(1, 3));
+        SimpleBroker::publish(Msg(1, 4));
+        assert_eq!(vec![Msg(1, 4)], drain(&mut coalescing));
+        assert_eq!(vec![Msg(1, 3), Msg(1, 4)], drain(&mut dropping));
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Other(u8);
+
+    #[test]
+    fn filtered() {
+        // other keys don't push the wanted one out, nor disconnect
+        let mut mine = Box::pin(SimpleBroker::<Other>::subscribe_with(2, Overflow::Disconnect, None, Box::new(|m: &Other| m.0 == 1)));
+        for m in [Other(1), Other(2), Other(3), Other(4)] {
+            SimpleBroker::publish(m);
+        }
+        assert_eq!(Some(Some(Other(1))), mine.next().now_or_never());
+        assert_eq!(None, mine.next().now_or_never());
+    }
+}
@@ -0,0 +1,86 @@
+// in-game chat. only the two players talk, everyone watching can read
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+use crate::db::PlayerToken;
+use crate::error::GameError;
+use crate::store::Store;
+use crate::db_schema::DbGameMessage;
+
+pub const MAX_MESSAGE_LEN: usize = 500; // chars
+const RATE_LIMIT_MESSAGES: usize = 5;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+
+// when each player has sent the messages within the window, per instance
+static SENT: Lazy<Mutex<HashMap<PlayerToken, VecDeque<Instant>>>> = Lazy::new(Default::default);
+
+fn validate_text(text: &str) -> Result<String, GameError> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(GameError::Invalid("Message is empty".into()));
+    }
+    if text.chars().count() > MAX_MESSAGE_LEN {
+        return Err(GameError::Invalid(format!("Message is longer than {} characters", MAX_MESSAGE_LEN)));
+    }
+    Ok(text.to_string())
+}
+
+fn check_rate(player_token: &PlayerToken, now: Instant) -> Result<(), GameError> {
+    let mut all = SENT.lock().unwrap();
+    all.retain(|_, sent| sent.back().map(|t| now.duration_since(*t) < RATE_LIMIT_WINDOW).unwrap_or(false));
+    let sent = all.entry(player_token.clone()).or_default();
+    while sent.front().map(|t| now.duration_since(*t) >= RATE_LIMIT_WINDOW).unwrap_or(false) {
+        sent.pop_front();
+    }
+    if sent.len() >= RATE_LIMIT_MESSAGES {
+        return Err(GameError::RateLimited);
+    }
+    sent.push_back(now);
+    Ok(())
+}
+
+pub async fn send_message(store: &Store, player_token: &PlayerToken, text: &str) -> Result<DbGameMessage, GameError> {
+    let text = validate_text(text)?;
+    let db_game_and_player = store.fetch_game_state_for_player(player_token).await?;
+    check_rate(player_token, Instant::now())?;
+    store.insert_game_message(&DbGameMessage {
+        id: Uuid::new_v4(),
+        game_id: db_game_and_player.game.id,
+        player: db_game_and_player.player,
+        text,
+        created_at: Utc::now(),
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+    use crate::chat::{check_rate, validate_text, MAX_MESSAGE_LEN, RATE_LIMIT_MESSAGES, RATE_LIMIT_WINDOW};
+    use crate::db::PlayerToken;
+
+    #[test]
+    fn text() {
+        assert_eq!(Ok("gg".to_string()), validate_text("  gg \n"));
+        assert!(validate_text("   ").is_err());
+        assert!(validate_text(&"ы".repeat(MAX_MESSAGE_LEN)).is_ok());
+        assert!(validate_text(&"ы".repeat(MAX_MESSAGE_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn rate() {
+        let player = PlayerToken(Uuid::new_v4());
+        let other = PlayerToken(Uuid::new_v4());
+        let start = Instant::now();
+        for _ in 0..RATE_LIMIT_MESSAGES {
+            check_rate(&player, start).unwrap();
+        }
+        assert!(check_rate(&player, start + Duration::from_secs(1)).is_err());
+        assert!(check_rate(&other, start + Duration::from_secs(1)).is_ok());
+        assert!(check_rate(&player, start + RATE_LIMIT_WINDOW).is_ok());
+    }
+}
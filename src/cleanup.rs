@@ -0,0 +1,136 @@
+// games nobody plays anymore. an unfinished game idle for GAME_IDLE_HOURS is abandoned by whoever's turn it is, or deleted
+// if nobody has made a turn in it; a finished game is moved to archived_games after GAME_ARCHIVE_AFTER_DAYS
+
+use std::env;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use crate::db_schema::{DbGame, GameOutcome};
+use crate::error::GameError;
+use crate::game::GameOperations;
+use crate::store::Store;
+
+const DEFAULT_IDLE_HOURS: i64 = 24;
+const DEFAULT_ARCHIVE_AFTER_DAYS: i64 = 7;
+const DEFAULT_CLEANUP_INTERVAL_SECS: u64 = 10 * 60;
+const BATCH_SIZE: i64 = 500;
+
+pub struct CleanupConfig {
+    pub idle: chrono::Duration,
+    pub archive_after: chrono::Duration,
+    pub interval: Duration,
+}
+
+impl CleanupConfig {
+    pub fn from_env() -> CleanupConfig {
+        let number = |name: &str, default: i64| env::var(name).ok().map(|v| v.parse::<i64>().unwrap_or_else(|_| panic!("{} must be a number", name))).unwrap_or(default);
+        CleanupConfig {
+            idle: chrono::Duration::hours(number("GAME_IDLE_HOURS", DEFAULT_IDLE_HOURS)),
+            archive_after: chrono::Duration::days(number("GAME_ARCHIVE_AFTER_DAYS", DEFAULT_ARCHIVE_AFTER_DAYS)),
+            interval: Duration::from_secs(number("CLEANUP_INTERVAL_SECS", DEFAULT_CLEANUP_INTERVAL_SECS as i64) as u64),
+        }
+    }
+}
+
+// false if there is nothing worth keeping
+fn settle_idle(game: &mut DbGame) -> Result<bool, GameError> {
+    // the games from before the outcomes were recorded
+    game.settle_outcome()?;
+    if game.outcome.is_some() {
+        return Ok(true);
+    }
+    let state = game.game()?;
+    if state.current_depth() == 0 {
+        return Ok(false);
+    }
+    let player = state.next_player()?;
+    game.set_outcome(GameOutcome::ABANDON, Some(player.other()), format!("{} abandoned the game", player));
+    Ok(true)
+}
+
+async fn retire(store: &Store, mut game: DbGame) -> Result<(), GameError> {
+    if settle_idle(&mut game)? {
+        store.save_game(&game).await.map(|_| ())
+    } else {
+        store.delete_game(&game).await
+    }
+}
+
+// a batch of the idle games at a time, the rest are for the next run
+pub async fn cleanup(store: &Store, config: &CleanupConfig, now: DateTime<Utc>) -> Result<(), GameError> {
+    for game in store.fetch_idle_games(now - config.idle, BATCH_SIZE).await? {
+        match retire(store, game).await {
+            // somebody has made a turn after all, or another instance has got there first
+            Ok(()) | Err(GameError::Conflict { .. }) | Err(GameError::NotFound) => (),
+            Err(e) => eprintln!("cleanup: {}", e.message()),
+        }
+    }
+    while store.archive_finished_games(now - config.archive_after, BATCH_SIZE).await? == BATCH_SIZE as usize {}
+    Ok(())
+}
+
+pub async fn run_cleanup(store: Store) {
+    let config = CleanupConfig::from_env();
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = cleanup(&store, &config, Utc::now()).await {
+            eprintln!("cleanup: {}", e.message());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::cleanup::{cleanup, settle_idle, CleanupConfig};
+    use crate::db::PlayerToken;
+    use crate::db_schema::{DbGame, GameDimensions, GameOutcome};
+    use crate::error::GameError;
+    use crate::game::{GameOperations, GameSerializations, Player, Side};
+    use crate::memory_store::MemoryStore;
+    use crate::store::Store;
+
+    #[test]
+    fn idle_games() {
+        let mut game = DbGame::new(GameDimensions::default());
+        assert_eq!(Ok(false), settle_idle(&mut game));
+        let mut state = game.game().unwrap();
+        state.push_move((0, Side::Left)).unwrap();
+        game.state = state.serialize();
+        assert_eq!(Ok(true), settle_idle(&mut game));
+        assert_eq!(Some(GameOutcome::ABANDON), game.outcome);
+        // blue was to move
+        assert_eq!(Some(Player::Red), game.outcome_winner);
+    }
+
+    #[tokio::test]
+    async fn archive() {
+        let store: Store = Arc::new(MemoryStore::default());
+        let config = CleanupConfig { idle: chrono::Duration::hours(1), archive_after: chrono::Duration::days(1), interval: Duration::from_secs(1) };
+        let junk = store.init_game_state(None, GameDimensions::default(), None).await.unwrap();
+        let (red, _) = store.claim_game_player(&junk.id, Player::Red, None).await.unwrap();
+        let played = store.init_game_state(None, GameDimensions::default(), None).await.unwrap();
+        let (played_red, _) = store.claim_game_player(&played.id, Player::Red, None).await.unwrap();
+        let db_game = store.fetch_game_by_player(&PlayerToken(played_red)).await.unwrap();
+        let mut state = db_game.game().unwrap();
+        state.push_move((0, Side::Left)).unwrap();
+        store.update_game_state(&db_game, state.serialize()).await.unwrap();
+
+        // nothing is idle yet
+        cleanup(&store, &config, Utc::now()).await.unwrap();
+        assert!(store.fetch_game_by_player(&PlayerToken(red)).await.is_ok());
+
+        cleanup(&store, &config, Utc::now() + chrono::Duration::hours(2)).await.unwrap();
+        assert_eq!(Err(GameError::NotFound), store.fetch_game_state(&junk.id).await.map(|_| ()));
+        assert_eq!(Some(GameOutcome::ABANDON), store.fetch_game_state(&played.id).await.unwrap().outcome);
+
+        cleanup(&store, &config, Utc::now() + chrono::Duration::days(2)).await.unwrap();
+        assert_eq!(Err(GameError::NotFound), store.fetch_game_state(&played.id).await.map(|_| ()));
+        let archived = store.fetch_game_or_archived(&played.id).await.unwrap();
+        assert_eq!(Some(GameOutcome::ABANDON), archived.outcome);
+        assert_eq!(1, archived.game().unwrap().current_depth());
+        assert_eq!(Err(GameError::NotFound), store.fetch_game_or_archived(&junk.id).await.map(|_| ()));
+    }
+}
@@ -0,0 +1,246 @@
+// time controls. the clock of the player to move runs since clock_started_at; it's started by the first turn,
+// so the first turn is never timed (the opponent may not be even there yet)
+
+use std::env;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use crate::error::GameError;
+use crate::store::Store;
+use crate::db_schema::{DbGame, GameOutcome};
+use crate::game::{GameOperations, Player};
+
+const MAX_BASE_MS: i64 = 24 * 60 * 60 * 1000;
+const MAX_INCREMENT_MS: i64 = 60 * 60 * 1000;
+const DEFAULT_CLOCK_TICK_MS: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeControl {
+    pub base_ms: i64, // the whole game budget, or a budget for each turn if per_move
+    pub increment_ms: i64,
+    pub per_move: bool,
+}
+
+impl TimeControl {
+    pub fn new(base_ms: i64, increment_ms: i64, per_move: bool) -> Result<TimeControl, GameError> {
+        if base_ms <= 0 || base_ms > MAX_BASE_MS {
+            return Err(GameError::Invalid(format!("time must be positive and at most {} seconds", MAX_BASE_MS / 1000)));
+        }
+        if !(0..=MAX_INCREMENT_MS).contains(&increment_ms) {
+            return Err(GameError::Invalid(format!("increment must be between 0 and {} seconds", MAX_INCREMENT_MS / 1000)));
+        }
+        if per_move && increment_ms != 0 {
+            return Err(GameError::Invalid("increment makes no sense with time per move".into()));
+        }
+        Ok(TimeControl { base_ms, increment_ms, per_move })
+    }
+    pub fn apply(&self, game: &mut DbGame) {
+        game.clock_base_ms = Some(self.base_ms);
+        game.clock_increment_ms = self.increment_ms;
+        game.clock_per_move = self.per_move;
+        game.red_time_left_ms = Some(self.base_ms);
+        game.blue_time_left_ms = Some(self.base_ms);
+        game.clock_started_at = None;
+    }
+}
+
+fn time_left_mut(game: &mut DbGame, player: Player) -> &mut Option<i64> {
+    match player {
+        Player::Red => &mut game.red_time_left_ms,
+        Player::Blue => &mut game.blue_time_left_ms,
+    }
+}
+
+// whose clock is running, if any
+pub fn ticking(game: &DbGame) -> Option<Player> {
+    if game.clock_base_ms.is_none() || game.clock_started_at.is_none() || game.outcome.is_some() {
+        return None;
+    }
+    game.game().ok()?.next_player().ok()
+}
+
+// time left as of now, with the running clock accounted for
+pub fn time_left(game: &DbGame, player: Player, now: DateTime<Utc>) -> Option<i64> {
+    let stored = match player {
+        Player::Red => game.red_time_left_ms,
+        Player::Blue => game.blue_time_left_ms,
+    }?;
+    Some(match (ticking(game), game.clock_started_at) {
+        (Some(p), Some(started_at)) if p == player => stored - (now - started_at).num_milliseconds(),
+        _ => stored,
+    })
+}
+
+// the player whose time is up
+pub fn flagged(game: &DbGame, now: DateTime<Utc>) -> Option<Player> {
+    let player = ticking(game)?;
+    if time_left(game, player, now)? <= 0 {
+        Some(player)
+    } else {
+        None
+    }
+}
+
+pub fn flag(game: &mut DbGame, player: Player) {
+    *time_left_mut(game, player) = Some(0);
+    game.set_outcome(GameOutcome::TIMEOUT, Some(player.other()), format!("{} ran out of time", player));
+}
+
+// charge the player who just moved and start the opponent's clock
+pub fn punch(game: &mut DbGame, moved: Option<Player>, now: DateTime<Utc>) {
+    let base = match game.clock_base_ms {
+        Some(base) => base,
+        None => return,
+    };
+    if let (Some(player), Some(started_at)) = (moved, game.clock_started_at) {
+        let increment = game.clock_increment_ms;
+        let per_move = game.clock_per_move;
+        let left = time_left_mut(game, player);
+        *left = Some(if per_move {
+            base
+        } else {
+            left.unwrap_or(base) - (now - started_at).num_milliseconds() + increment
+        });
+    }
+    game.clock_started_at = Some(now);
+}
+
+// the time spent on a turn taken back isn't given back, it isn't kept. the player whose clock was running is charged for
+// the thinking so far (without the increment, that's for turns), and the clock starts over for whoever is to move now,
+// unless nobody has made a turn anymore
+pub fn take_back(game: &mut DbGame, ticked: Option<Player>, now: DateTime<Utc>) {
+    if game.clock_base_ms.is_none() {
+        return;
+    }
+    if let (Some(player), Some(started_at), false) = (ticked, game.clock_started_at, game.clock_per_move) {
+        let left = time_left_mut(game, player);
+        *left = left.map(|left| left - (now - started_at).num_milliseconds());
+    }
+    let empty = game.game().map(|state| state.current_depth() == 0).unwrap_or(false);
+    game.clock_started_at = if empty { None } else { Some(now) };
+}
+
+// nobody has to make a turn to lose on time
+pub async fn run_clock_watcher(store: Store) {
+    let tick = env::var("CLOCK_TICK_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_CLOCK_TICK_MS);
+    let mut interval = tokio::time::interval(Duration::from_millis(tick));
+    loop {
+        interval.tick().await;
+        let games = match store.fetch_running_timed_games().await {
+            Ok(games) => games,
+            Err(e) => {
+                eprintln!("clock watcher: {}", e.message());
+                continue;
+            }
+        };
+        let now = Utc::now();
+        for mut game in games {
+            if let Some(player) = flagged(&game, now) {
+                flag(&mut game, player);
+                if let Err(e) = store.save_game(&game).await {
+                    eprintln!("clock watcher: {}", e.message());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use crate::clock::{flagged, punch, take_back, ticking, time_left, TimeControl};
+    use crate::db_schema::{DbGame, GameDimensions};
+    use crate::game::{GameOperations, GameSerializations};
+    use crate::game::Player::{Blue, Red};
+    use crate::game::Side::Left;
+
+    fn timed_game(time_control: TimeControl) -> DbGame {
+        let mut game = DbGame::new(GameDimensions::default());
+        time_control.apply(&mut game);
+        game
+    }
+
+    fn turn(game: &mut DbGame, at: chrono::DateTime<Utc>) {
+        let mut state = game.game().unwrap();
+        state.push_move((0, Left)).unwrap();
+        let moved = state.last_player().ok();
+        game.state = state.serialize();
+        punch(game, moved, at);
+    }
+
+    #[test]
+    fn first_turn_is_free() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let mut game = timed_game(TimeControl::new(10_000, 2_000, false).unwrap());
+        assert_eq!(None, ticking(&game));
+        turn(&mut game, start + Duration::seconds(60));
+        assert_eq!(Some(10_000), game.red_time_left_ms);
+        assert_eq!(Some(Blue), ticking(&game));
+    }
+
+    #[test]
+    fn increment() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let mut game = timed_game(TimeControl::new(10_000, 2_000, false).unwrap());
+        turn(&mut game, start);
+        turn(&mut game, start + Duration::seconds(3));
+        assert_eq!(Some(9_000), game.blue_time_left_ms);
+        assert_eq!(Some(10_000), time_left(&game, Red, start + Duration::seconds(3)));
+        assert_eq!(Some(4_000), time_left(&game, Red, start + Duration::seconds(9)));
+        assert_eq!(None, flagged(&game, start + Duration::seconds(12)));
+        assert_eq!(Some(Red), flagged(&game, start + Duration::seconds(13)));
+    }
+
+    #[test]
+    fn per_move() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let mut game = timed_game(TimeControl::new(5_000, 0, true).unwrap());
+        turn(&mut game, start);
+        turn(&mut game, start + Duration::seconds(4));
+        assert_eq!(Some(5_000), game.blue_time_left_ms);
+        assert_eq!(Some(Red), flagged(&game, start + Duration::seconds(9)));
+    }
+
+    #[test]
+    fn taken_back() {
+        let start = Utc.timestamp_opt(0, 0).unwrap();
+        let mut game = timed_game(TimeControl::new(10_000, 2_000, false).unwrap());
+        turn(&mut game, start);
+        turn(&mut game, start + Duration::seconds(3));
+        // red thinks for 4 seconds, then blue's turn is taken back
+        let ticked = ticking(&game);
+        let mut state = game.game().unwrap();
+        state.pop().unwrap();
+        game.state = state.serialize();
+        take_back(&mut game, ticked, start + Duration::seconds(7));
+        assert_eq!(Some(6_000), game.red_time_left_ms);
+        assert_eq!(Some(9_000), game.blue_time_left_ms);
+        assert_eq!(Some(Blue), ticking(&game));
+        assert_eq!(Some(8_000), time_left(&game, Blue, start + Duration::seconds(8)));
+
+        // the first turn is never timed, taken back or not
+        let ticked = ticking(&game);
+        let mut state = game.game().unwrap();
+        state.pop().unwrap();
+        game.state = state.serialize();
+        take_back(&mut game, ticked, start + Duration::seconds(9));
+        assert_eq!(Some(7_000), game.blue_time_left_ms);
+        assert_eq!(None, game.clock_started_at);
+        assert_eq!(None, ticking(&game));
+        assert_eq!(Some(6_000), time_left(&game, Red, start + Duration::seconds(60)));
+    }
+
+    #[test]
+    fn validation() {
+        assert!(TimeControl::new(0, 0, false).is_err());
+        assert!(TimeControl::new(10_000, -1, false).is_err());
+        assert!(TimeControl::new(10_000, 1_000, true).is_err());
+    }
+
+    #[test]
+    fn untimed() {
+        let mut game = DbGame::new(GameDimensions::default());
+        turn(&mut game, Utc::now());
+        assert_eq!(None, game.clock_started_at);
+        assert_eq!(None, flagged(&game, Utc::now()));
+    }
+}
@@ -1,41 +1,103 @@
 use std::cmp::max;
 use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
 use std::env;
+use std::time::Duration;
 use async_graphql::NewType;
-use crate::db_schema::{DbGame, DbGamePlayerRedUpdate, DbGamePlayerBlueUpdate, DEFAULT_GAME_SIZE};
-use lazy_static::lazy_static;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use crate::db_schema::{DbArchivedGame, DbGame, DbGameMessage, DbRatingChange, DbUser};
 use diesel::{
-    r2d2::{Pool, ConnectionManager},
+    r2d2::{Pool, ConnectionManager, CustomizeConnection},
     pg::PgConnection
 };
 use uuid::Uuid;
-use crate::adversary::BotId;
-use crate::adversary::BotId::SMART;
-use crate::broker::SimpleBroker;
-use crate::game::Player;
+use crate::error::GameError;
+use crate::lobby::LobbyList;
+use crate::ratings::{needs_rating, rate, rated_players};
+use crate::store::{GameLock, GameStore, UserGamesFilter};
 
 type PgPool = Pool<ConnectionManager<PgConnection>>;
 
-struct Statics {
-    db_connection: PgPool,
+const DEFAULT_POOL_SIZE: u32 = 8;
+const DEFAULT_CONNECTION_TIMEOUT_MS: u64 = 5000;
+
+// DATABASE_URL, DB_POOL_SIZE, DB_CONNECTION_TIMEOUT_MS (to wait for a free connection) and DB_STATEMENT_TIMEOUT_MS
+#[derive(Clone, Debug)]
+pub struct DbConfig {
+    pub url: String,
+    pub pool_size: u32,
+    pub connection_timeout: Duration,
+    pub statement_timeout: Option<Duration>,
 }
 
-lazy_static! {
-    static ref STATICS: Statics = {
-       Statics {
-           db_connection: PgPool::builder()
-               .max_size(8)
-               .build(ConnectionManager::new(env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set")))
-               .expect("failed to create db connection_pool")
-       }
-    };
+impl DbConfig {
+    pub fn from_env() -> DbConfig {
+        let ms = |name: &str| env::var(name).ok().map(|v| v.parse::<u64>().unwrap_or_else(|_| panic!("{} must be a number", name)));
+        DbConfig {
+            url: env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+            pool_size: env::var("DB_POOL_SIZE").ok().map(|v| v.parse().expect("DB_POOL_SIZE must be a number")).unwrap_or(DEFAULT_POOL_SIZE),
+            connection_timeout: Duration::from_millis(ms("DB_CONNECTION_TIMEOUT_MS").unwrap_or(DEFAULT_CONNECTION_TIMEOUT_MS)),
+            statement_timeout: ms("DB_STATEMENT_TIMEOUT_MS").map(Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StatementTimeout(Duration);
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!("SET statement_timeout = {}", self.0.as_millis())).map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+// the blocking diesel calls run on tokio's blocking threads, not on the ones serving requests and websockets
+pub struct PgStore {
+    pool: PgPool,
+    url: String, // for the game locks, they don't take a connection from the pool
+}
+
+impl PgStore {
+    pub fn connect(config: &DbConfig) -> Result<PgStore, String> {
+        let mut builder = PgPool::builder()
+            .max_size(config.pool_size)
+            .connection_timeout(config.connection_timeout);
+        if let Some(timeout) = config.statement_timeout {
+            builder = builder.connection_customizer(Box::new(StatementTimeout(timeout)));
+        }
+        let pool = builder.build(ConnectionManager::new(&config.url)).map_err(|e| e.to_string())?;
+        Ok(PgStore { pool, url: config.url.clone() })
+    }
+
+    async fn run<R, E, F>(&self, f: F) -> Result<R, E>
+        where
+            R: Send + 'static,
+            E: From<String> + Send + 'static,
+            F: FnOnce(&PgConnection) -> Result<R, E> + Send + 'static,
+    {
+        run_blocking(&self.pool, f).await
+    }
+
+    pub fn run_embed_migrations(&self) {
+        embedded_migrations::run(&self.pool.get().unwrap()).expect("failed to run migrations");
+    }
 }
 
 embed_migrations!();
 
-pub fn run_embed_migrations() {
-    embedded_migrations::run(&STATICS.db_connection.get().unwrap());
+pub(crate) async fn run_blocking<C, R, E, F>(pool: &Pool<ConnectionManager<C>>, f: F) -> Result<R, E>
+    where
+        C: Connection + Send + 'static,
+        R: Send + 'static,
+        E: From<String> + Send + 'static,
+        F: FnOnce(&C) -> Result<R, E> + Send + 'static,
+{
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let conn = pool.get().map_err(|e| e.to_string())?;
+        f(&conn)
+    }).await.map_err(|e| e.to_string())?
 }
 
 #[derive(Clone, DieselNewType, PartialEq, Eq, Hash)]
@@ -80,89 +142,290 @@ impl From<&str> for GameStateSerialized {
 pub struct PlayerToken(pub Uuid);
 #[derive(Clone, Debug, NewType, DieselNewType, PartialEq, Eq, Hash)]
 pub struct GameToken(pub Uuid);
+#[derive(Clone, Debug, NewType, DieselNewType, PartialEq, Eq, Hash)]
+pub struct UserId(pub Uuid);
 
-pub(crate) async fn init_game_state(bot: Option<BotId>) -> Result<DbGame, String> {
-    use crate::db_schema_macro::games::dsl::*;
-    let mut new_game = DbGame::new(if bot == Some(SMART) {4} else {DEFAULT_GAME_SIZE},
-                                   if bot == Some(SMART) {5} else {DEFAULT_GAME_SIZE});
-    new_game.bot_id = bot;
-    let conn: &PgConnection = &STATICS.db_connection.get().unwrap();
-    let r = diesel::insert_into(games)
-        .values(&new_game)
-        .get_result::<DbGame>(conn).map_err(|e| e.to_string())?;
-    Ok(r)
+// a connection of its own, held for as long as the bot thinks; closing it would release the lock too
+struct PgLock {
+    conn: Option<PgConnection>,
+    key: i64,
 }
 
-pub struct DbGameAndPlayer {
-    pub game: DbGame,
-    pub player: Player
+impl Drop for PgLock {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let key = self.key;
+            tokio::task::spawn_blocking(move || diesel::select(pg_advisory_unlock(key)).get_result::<bool>(&conn).ok());
+        }
+    }
 }
 
-pub(crate) async fn fetch_game_state(game_token: &GameToken) -> Result<DbGame, String> {
-    use crate::db_schema_macro::games::dsl::*;
-    let game = &games.filter(id.eq(game_token)).first::<DbGame>(&STATICS.db_connection.get().unwrap()).map_err(|e| e.to_string())?;
-    Ok(game.clone())
-}
+sql_function!(fn pg_try_advisory_lock(key: diesel::sql_types::BigInt) -> diesel::sql_types::Bool);
+sql_function!(fn pg_advisory_unlock(key: diesel::sql_types::BigInt) -> diesel::sql_types::Bool);
+// usernames are unique regardless of the case, both in postgres and sqlite
+sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
 
-pub(crate) async fn fetch_game_state_for_player(player_token: &PlayerToken) -> Result<DbGameAndPlayer, String> {
-    use crate::db_schema_macro::games::dsl::*;
-    let token = player_token.0;
-    let game = &games.filter(player_red.eq(token).or(player_blue.eq(token))).first::<DbGame>(&STATICS.db_connection.get().unwrap()).map_err(|e| e.to_string())?;
-    // warn: non exhaustive
-    let player = if game.player_red == Some(player_token.clone()) {
-        Player::Red
-    } else {
-        Player::Blue
+// both players are locked in the same order, so two games finishing at once can't deadlock
+fn rate_game(conn: &PgConnection, game: &DbGame) -> Result<(), GameError> {
+    use crate::db_schema_macro::{rating_history, users};
+    let (red, blue) = match rated_players(game) {
+        Some(players) => players,
+        None => return Ok(()),
     };
-    Ok(DbGameAndPlayer { game: game.clone(), player })
+    let players = users::table.filter(users::id.eq_any(vec![red.clone(), blue.clone()]))
+        .order(users::id).for_update()
+        .load::<DbUser>(conn)?;
+    let find = |user_id: &UserId| players.iter().find(|u| u.id == *user_id).ok_or(GameError::NotFound);
+    let changes = rate(game, find(&red)?, find(&blue)?, Utc::now()).to_vec();
+    for change in changes.iter() {
+        diesel::update(users::table.filter(users::id.eq(&change.user_id)))
+            .set((users::rating.eq(change.rating_after), users::rated_games.eq(users::rated_games + 1)))
+            .execute(conn)?;
+    }
+    diesel::insert_into(rating_history::table).values(&changes).execute(conn)?;
+    Ok(())
 }
 
-pub(crate) async fn update_game_state(game_token: &GameToken, s: GameStateSerialized) -> Result<DbGame, String> {
-    // use crate::db_schema_macro::games::dsl::*;
-    let mut game = fetch_game_state(game_token).await?;
-    let conn: &PgConnection = &STATICS.db_connection.get().unwrap();
-    game.state = s.clone();
-    let r = diesel::update(&game)
-        // .set(&game)
-        .set(&game)
-        .get_result::<DbGame>(conn).map_err(|e| e.to_string())?;
-    SimpleBroker::publish(r.clone());
-    Ok(r)
+pub(crate) fn username_taken(e: diesel::result::Error) -> GameError {
+    match e {
+        diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _) => GameError::UsernameTaken,
+        e => e.into(),
+    }
 }
 
-pub(crate) async fn claim_game_player(game_token: &GameToken, player: Player) -> Result<(Uuid, DbGame), String> {
-    use crate::db_schema_macro::games::dsl::*;
-    let conn: &PgConnection = &STATICS.db_connection.get().unwrap();
-    let game = games.filter(id.eq(game_token)).first::<DbGame>(conn).map_err(|e| e.to_string())?.clone();
-    if !game.can_player_join(&player) {
-        return Err("game is full".to_string());
-    }
-    let new_id = Uuid::new_v4();
-    let statement = match player {
-        Player::Red => {
-            if !game.player_red.is_none() {
-                return Err("Player red already been claimed".to_string());
+#[async_trait]
+impl GameStore for PgStore {
+    async fn insert_game(&self, game: &DbGame) -> Result<DbGame, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        let new_game = game.clone();
+        self.run(move |conn| {
+            diesel::insert_into(games)
+                .values(&new_game)
+                .get_result::<DbGame>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_game_state(&self, game_token: &GameToken) -> Result<DbGame, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        let game_token = game_token.clone();
+        self.run(move |conn| {
+            games.filter(id.eq(game_token)).first::<DbGame>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_game_by_player(&self, player_token: &PlayerToken) -> Result<DbGame, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        let token = player_token.0;
+        self.run(move |conn| {
+            games.filter(player_red.eq(token).or(player_blue.eq(token))).first::<DbGame>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn write_game(&self, game: &DbGame) -> Result<DbGame, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        let game = game.clone();
+        self.run(move |conn| conn.transaction::<_, GameError, _>(|| {
+            let mut next = game.clone();
+            next.version += 1;
+            next.updated_at = Utc::now();
+            let needs_rating = needs_rating(&next);
+            next.rated |= needs_rating;
+            let r = diesel::update(games.filter(id.eq(&game.id)).filter(version.eq(game.version)))
+                .set(&next)
+                .get_result::<DbGame>(conn).optional()?;
+            match r {
+                Some(r) => {
+                    if needs_rating {
+                        rate_game(conn, &r)?;
+                    }
+                    Ok(r)
+                }
+                None => {
+                    let actual = games.filter(id.eq(&game.id)).select(version).first::<i64>(conn)?;
+                    Err(GameError::Conflict { expected: game.version, actual })
+                }
             }
-            diesel::update(&game)
-                .set(&DbGamePlayerRedUpdate {
-                    id: game.id.clone(),
-                    // TODO PlayerToken::new
-                    player_red: PlayerToken(new_id),
-                }).get_result::<DbGame>(conn)
-        },
-        Player::Blue => {
-            if !game.player_blue.is_none() {
-                return Err("Player blue already been claimed".to_string());
+        })).await
+    }
+
+    async fn fetch_running_timed_games(&self) -> Result<Vec<DbGame>, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        self.run(|conn| {
+            games.filter(outcome.is_null().and(clock_base_ms.is_not_null()).and(clock_started_at.is_not_null()))
+                .load::<DbGame>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    // an advisory lock, so that it works across instances
+    async fn try_lock_game(&self, game_token: &GameToken) -> Result<Option<GameLock>, GameError> {
+        let key = i64::from_be_bytes(game_token.0.as_bytes()[..8].try_into().unwrap());
+        let url = self.url.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = PgConnection::establish(&url).map_err(|e| e.to_string())?;
+            let locked = diesel::select(pg_try_advisory_lock(key)).get_result::<bool>(&conn).map_err(GameError::from)?;
+            Ok(if locked { Some(GameLock::new(PgLock { conn: Some(conn), key })) } else { None })
+        }).await.map_err(|e| e.to_string())?
+    }
+
+    async fn insert_message(&self, message: &DbGameMessage) -> Result<DbGameMessage, GameError> {
+        use crate::db_schema_macro::game_messages::dsl::*;
+        let message = message.clone();
+        self.run(move |conn| {
+            diesel::insert_into(game_messages)
+                .values(&message)
+                .get_result::<DbGameMessage>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_game_messages(&self, game_token: &GameToken) -> Result<Vec<DbGameMessage>, GameError> {
+        use crate::db_schema_macro::game_messages::dsl::*;
+        let game_token = game_token.clone();
+        self.run(move |conn| {
+            game_messages.filter(game_id.eq(game_token)).order(created_at.asc())
+                .load::<DbGameMessage>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_game_message(&self, message_id: &Uuid) -> Result<DbGameMessage, GameError> {
+        use crate::db_schema_macro::game_messages::dsl::*;
+        let message_id = *message_id;
+        self.run(move |conn| {
+            game_messages.filter(id.eq(message_id)).first::<DbGameMessage>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn insert_user(&self, user: &DbUser) -> Result<DbUser, GameError> {
+        use crate::db_schema_macro::users::dsl::*;
+        let user = user.clone();
+        self.run(move |conn| {
+            diesel::insert_into(users)
+                .values(&user)
+                .get_result::<DbUser>(conn).map_err(username_taken)
+        }).await
+    }
+
+    async fn fetch_user(&self, user_id: &UserId) -> Result<DbUser, GameError> {
+        use crate::db_schema_macro::users::dsl::*;
+        let user_id = user_id.clone();
+        self.run(move |conn| {
+            users.filter(id.eq(user_id)).first::<DbUser>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_user_by_name(&self, name: &str) -> Result<DbUser, GameError> {
+        use crate::db_schema_macro::users::dsl::*;
+        let name = name.to_lowercase();
+        self.run(move |conn| {
+            users.filter(lower(username).eq(name)).first::<DbUser>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_leaderboard(&self, limit: i64, offset: i64) -> Result<Vec<DbUser>, GameError> {
+        use crate::db_schema_macro::users::dsl::*;
+        self.run(move |conn| {
+            users.filter(rated_games.gt(0)).order((rating.desc(), username.asc())).limit(limit).offset(offset)
+                .load::<DbUser>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_rating_history(&self, user: &UserId, opponent: Option<UserId>) -> Result<Vec<DbRatingChange>, GameError> {
+        use crate::db_schema_macro::rating_history::dsl::*;
+        let user = user.clone();
+        self.run(move |conn| {
+            let mut query = rating_history.filter(user_id.eq(user)).into_boxed();
+            if let Some(opponent) = opponent {
+                query = query.filter(opponent_id.eq(opponent));
             }
-            diesel::update(&game)
-                .set(&DbGamePlayerBlueUpdate {
-                    id: game.id.clone(),
-                    player_blue: PlayerToken(new_id),
-                }).get_result::<DbGame>(conn)
+            query.order(created_at.asc()).load::<DbRatingChange>(conn).map_err(GameError::from)
+        }).await
+    }
 
-        },
-    };
-    let r = statement.map_err(|e| e.to_string())?;
-    SimpleBroker::publish(r.clone());
-    Ok((new_id, r))
-}
\ No newline at end of file
+    async fn fetch_user_games(&self, user_id: &UserId, filter: &UserGamesFilter) -> Result<Vec<DbGame>, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        let user_id = user_id.0;
+        let filter = filter.clone();
+        self.run(move |conn| {
+            let mut query = games.filter(user_red.eq(user_id).or(user_blue.eq(user_id))).into_boxed();
+            if let Some(finished) = filter.finished {
+                query = if finished { query.filter(outcome.is_not_null()) } else { query.filter(outcome.is_null()) };
+            }
+            if let Some(o) = filter.outcome {
+                query = query.filter(outcome.eq(o));
+            }
+            if let Some(vs_bot) = filter.vs_bot {
+                query = if vs_bot { query.filter(bot_id.is_not_null()) } else { query.filter(bot_id.is_null()) };
+            }
+            query.order(updated_at.desc()).load::<DbGame>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_lobby_games(&self, list: LobbyList, limit: i64, offset: i64) -> Result<Vec<DbGame>, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        self.run(move |conn| {
+            let mut query = games.filter(player_red.is_not_null().or(player_blue.is_not_null())).into_boxed();
+            query = match list {
+                LobbyList::Open => query.filter(outcome.is_null().and(bot_id.is_null()).and(player_red.is_null().or(player_blue.is_null()))),
+                LobbyList::Ongoing => query.filter(outcome.is_null().and(bot_id.is_not_null().or(player_red.is_not_null().and(player_blue.is_not_null())))),
+                LobbyList::Finished => query.filter(outcome.is_not_null()),
+            };
+            query.order((updated_at.desc(), id)).limit(limit).offset(offset).load::<DbGame>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_open_game_ids(&self) -> Result<Vec<GameToken>, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        self.run(move |conn| {
+            games.filter(outcome.is_null().and(bot_id.is_null()))
+                .filter(player_red.is_not_null().or(player_blue.is_not_null()))
+                .filter(player_red.is_null().or(player_blue.is_null()))
+                .select(id).load::<GameToken>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn fetch_idle_games(&self, updated_before: DateTime<Utc>, limit: i64) -> Result<Vec<DbGame>, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        self.run(move |conn| {
+            games.filter(outcome.is_null().and(updated_at.lt(updated_before)))
+                .order(updated_at).limit(limit)
+                .load::<DbGame>(conn).map_err(GameError::from)
+        }).await
+    }
+
+    async fn delete_game(&self, game: &DbGame) -> Result<(), GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        let game = game.clone();
+        self.run(move |conn| {
+            let deleted = diesel::delete(games.filter(id.eq(&game.id)).filter(version.eq(game.version))).execute(conn)?;
+            if deleted == 0 {
+                let actual = games.filter(id.eq(&game.id)).select(version).first::<i64>(conn)?;
+                return Err(GameError::Conflict { expected: game.version, actual });
+            }
+            Ok(())
+        }).await
+    }
+
+    // the rows another instance is archiving are skipped, not waited for
+    async fn archive_finished_games(&self, finished_before: DateTime<Utc>, limit: i64) -> Result<usize, GameError> {
+        use crate::db_schema_macro::games::dsl::*;
+        use crate::db_schema_macro::archived_games;
+        self.run(move |conn| conn.transaction::<_, GameError, _>(|| {
+            let finished = games.filter(outcome.is_not_null().and(updated_at.lt(finished_before)))
+                .order(updated_at).limit(limit)
+                .for_update().skip_locked()
+                .load::<DbGame>(conn)?;
+            let archived = finished.iter().filter_map(DbArchivedGame::from_db_game).collect::<Vec<_>>();
+            diesel::insert_into(archived_games::table).values(&archived).execute(conn)?;
+            let ids = archived.iter().map(|g| g.id.clone()).collect::<Vec<_>>();
+            diesel::delete(games.filter(id.eq_any(ids))).execute(conn)?;
+            Ok(archived.len())
+        })).await
+    }
+
+    async fn fetch_archived_game(&self, game_token: &GameToken) -> Result<DbArchivedGame, GameError> {
+        use crate::db_schema_macro::archived_games::dsl::*;
+        let game_token = game_token.clone();
+        self.run(move |conn| {
+            archived_games.filter(id.eq(game_token)).first::<DbArchivedGame>(conn).map_err(GameError::from)
+        }).await
+    }
+}
@@ -1,17 +1,56 @@
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 use crate::adversary::BotId;
-use crate::db::{GameStateSerialized, GameToken, PlayerToken};
-use crate::db_schema_macro::games;
-use crate::game::Player;
+use crate::broker::Coalesce;
+use crate::db::{GameStateSerialized, GameToken, PlayerToken, UserId};
+use crate::db_schema_macro::{archived_games, games, game_messages, rating_history, users};
+use crate::game::{DEFAULT_WIN_LEN, GameOperations, GameSerializations, Player, State};
 
 #[derive(Queryable, Insertable, Identifiable, AsChangeset, Clone)]
 #[table_name="games"]
+#[changeset_options(treat_none_as_null="true")] // the whole row gets saved, i.e. to clear a request
 pub struct DbGame {
     pub id: GameToken,
     pub state: GameStateSerialized,
     pub player_red: Option<PlayerToken>,
     pub player_blue: Option<PlayerToken>,
     pub bot_id: Option<BotId>,
+    pub width: i16,
+    pub height: i16,
+    pub win_len: i16,
+    pub undo_requested_by: Option<Player>,
+    pub draw_offered_by: Option<Player>,
+    pub outcome: Option<GameOutcome>,
+    pub outcome_winner: Option<Player>,
+    pub outcome_reason: Option<String>,
+    pub clock_base_ms: Option<i64>, // no clock at all without it
+    pub clock_increment_ms: i64,
+    pub clock_per_move: bool,
+    pub red_time_left_ms: Option<i64>,
+    pub blue_time_left_ms: Option<i64>,
+    pub clock_started_at: Option<DateTime<Utc>>,
+    pub version: i64, // see save_game
+    pub user_red: Option<UserId>, // whoever was logged in when claiming the seat
+    pub user_blue: Option<UserId>,
+    pub rated: bool, // see ratings.rs
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>, // on every write, see write_game
+}
+
+// every update is the whole game, so only the latest one matters
+impl Coalesce for DbGame {
+    type Key = GameToken;
+    fn coalesce_key(&self) -> GameToken {
+        self.id.clone()
+    }
+}
+
+// how the game ended; the reason is for humans
+#[derive(Debug, Clone, Copy, DbEnum, Eq, PartialEq, async_graphql::Enum)]
+#[PgType = "outcome_type"]
+#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
+pub enum GameOutcome {
+    WIN, DRAW, RESIGN, ABANDON, TIMEOUT
 }
 
 impl DbGame {
@@ -35,6 +74,22 @@ impl DbGame {
         }
         Ok(())
     }
+    // the seat the user has claimed, if any
+    pub fn user_seat(&self, user: &UserId) -> Option<Player> {
+        if self.user_red.as_ref() == Some(user) {
+            Some(Player::Red)
+        } else if self.user_blue.as_ref() == Some(user) {
+            Some(Player::Blue)
+        } else {
+            None
+        }
+    }
+    pub fn player_token(&self, player: Player) -> Option<&PlayerToken> {
+        match player {
+            Player::Red => self.player_red.as_ref(),
+            Player::Blue => self.player_blue.as_ref(),
+        }
+    }
     pub fn can_player_join(&self, player: &Player) -> bool {
         self.actor_count() <= 1 && match player {
             Player::Red => self.player_red.is_none(),
@@ -43,22 +98,112 @@ impl DbGame {
     }
 }
 
-#[derive(Identifiable, AsChangeset, Clone)]
-#[table_name="games"]
-pub struct DbGamePlayerRedUpdate {
-    pub id: GameToken,
-    pub player_red: PlayerToken,
+#[derive(Queryable, Insertable, Clone)]
+#[table_name="game_messages"]
+pub struct DbGameMessage {
+    pub id: Uuid,
+    pub game_id: GameToken,
+    pub player: Player,
+    pub text: String,
+    pub created_at: DateTime<Utc>,
 }
 
-#[derive(Identifiable, AsChangeset, Clone)]
-#[table_name="games"]
-pub struct DbGamePlayerBlueUpdate {
+#[derive(Queryable, Insertable, Clone)]
+#[table_name="users"]
+pub struct DbUser {
+    pub id: UserId,
+    pub username: String,
+    pub password_hash: String, // argon2, in the PHC string format
+    pub created_at: DateTime<Utc>,
+    pub rating: f64,
+    pub rated_games: i32,
+}
+
+// one per player per rated game
+#[derive(Queryable, Insertable, Clone, Debug, PartialEq)]
+#[table_name="rating_history"]
+pub struct DbRatingChange {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub opponent_id: UserId,
+    pub game_id: GameToken,
+    pub rating_before: f64,
+    pub rating_after: f64,
+    pub created_at: DateTime<Utc>,
+}
+
+// a finished game, once it's been over for a while. the tokens, the clock and the rest of what a game in play needs are gone
+#[derive(Queryable, Insertable, Clone)]
+#[table_name="archived_games"]
+pub struct DbArchivedGame {
     pub id: GameToken,
-    pub player_blue: PlayerToken,
+    pub state: GameStateSerialized,
+    pub bot_id: Option<BotId>,
+    pub width: i16,
+    pub height: i16,
+    pub win_len: i16,
+    pub outcome: GameOutcome,
+    pub outcome_winner: Option<Player>,
+    pub outcome_reason: Option<String>,
+    pub user_red: Option<UserId>,
+    pub user_blue: Option<UserId>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+impl DbArchivedGame {
+    // None if the game isn't finished
+    pub fn from_db_game(game: &DbGame) -> Option<DbArchivedGame> {
+        Some(DbArchivedGame {
+            id: game.id.clone(),
+            state: game.state.clone(),
+            bot_id: game.bot_id,
+            width: game.width,
+            height: game.height,
+            win_len: game.win_len,
+            outcome: game.outcome?,
+            outcome_winner: game.outcome_winner,
+            outcome_reason: game.outcome_reason.clone(),
+            user_red: game.user_red.clone(),
+            user_blue: game.user_blue.clone(),
+            created_at: game.created_at,
+            finished_at: game.updated_at,
+        })
+    }
+
+    // to be shown like any other finished game, nobody can claim or move in it
+    pub fn db_game(&self) -> DbGame {
+        let mut game = DbGame::new(GameDimensions { width: self.width as u8, height: self.height as u8, win_len: self.win_len as u8 });
+        game.id = self.id.clone();
+        game.state = self.state.clone();
+        game.bot_id = self.bot_id;
+        game.outcome = Some(self.outcome);
+        game.outcome_winner = self.outcome_winner;
+        game.outcome_reason = self.outcome_reason.clone();
+        game.user_red = self.user_red.clone();
+        game.user_blue = self.user_blue.clone();
+        game.rated = true;
+        game.created_at = self.created_at;
+        game.updated_at = self.finished_at;
+        game
+    }
 }
 
 pub const DEFAULT_GAME_SIZE: u8 = 7;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameDimensions {
+    pub width: u8,
+    pub height: u8,
+    pub win_len: u8,
+}
+
+impl Default for GameDimensions {
+    fn default() -> GameDimensions {
+        GameDimensions { width: DEFAULT_GAME_SIZE, height: DEFAULT_GAME_SIZE, win_len: DEFAULT_WIN_LEN }
+    }
+}
+
 pub fn empty_state(width: u8, height: u8) -> String {
     let mut res = String::new();
     for _ in 0..height {
@@ -71,13 +216,57 @@ pub fn empty_state(width: u8, height: u8) -> String {
 }
 
 impl DbGame {
-    pub fn new(width: u8, height: u8) -> DbGame {
+    pub fn new(dimensions: GameDimensions) -> DbGame {
+        let now = Utc::now();
         DbGame {
             id: GameToken(Uuid::new_v4()),
-            state: empty_state(width, height).trim().into(),
+            state: empty_state(dimensions.width, dimensions.height).trim().into(),
             player_red: None,
             player_blue: None,
             bot_id: None,
+            width: dimensions.width as i16,
+            height: dimensions.height as i16,
+            win_len: dimensions.win_len as i16,
+            undo_requested_by: None,
+            draw_offered_by: None,
+            outcome: None,
+            outcome_winner: None,
+            outcome_reason: None,
+            clock_base_ms: None,
+            clock_increment_ms: 0,
+            clock_per_move: false,
+            red_time_left_ms: None,
+            blue_time_left_ms: None,
+            clock_started_at: None,
+            version: 0,
+            user_red: None,
+            user_blue: None,
+            rated: false,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+    pub fn game(&self) -> Result<State, String> {
+        Ok(State::deserialize(&self.state)?.with_win_len(self.win_len as u8))
+    }
+    pub fn set_outcome(&mut self, outcome: GameOutcome, winner: Option<Player>, reason: String) {
+        self.outcome = Some(outcome);
+        self.outcome_winner = winner;
+        self.outcome_reason = Some(reason);
+        self.undo_requested_by = None;
+        self.draw_offered_by = None;
+    }
+    // record the outcome if the board itself has decided the game
+    pub fn settle_outcome(&mut self) -> Result<(), String> {
+        if self.outcome.is_some() {
+            return Ok(());
         }
+        let state = self.game()?;
+        if let Some(winner) = state.try_winner() {
+            self.set_outcome(GameOutcome::WIN, Some(winner), format!("{} in a row", state.win_len()));
+        } else if state.is_stalemate() {
+            self.set_outcome(GameOutcome::DRAW, None, "no moves left".into());
+        }
+        Ok(())
     }
 }
\ No newline at end of file
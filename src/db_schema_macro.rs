@@ -1,11 +1,86 @@
 table! {
-    use crate::adversary::BotIdMapping;
-    use diesel::sql_types::{Nullable, Text, Uuid};
+    use crate::game::PlayerMapping;
+    use crate::db_schema::GameOutcomeMapping;
+    use diesel::sql_types::{BigInt, Bool, Nullable, SmallInt, Text, Timestamptz, Uuid};
     games {
         id -> Uuid,
         state -> Text,
         player_red -> Nullable<Uuid>,
         player_blue -> Nullable<Uuid>,
-        bot_id -> Nullable<BotIdMapping>,
+        bot_id -> Nullable<Text>,
+        width -> SmallInt,
+        height -> SmallInt,
+        win_len -> SmallInt,
+        undo_requested_by -> Nullable<PlayerMapping>,
+        draw_offered_by -> Nullable<PlayerMapping>,
+        outcome -> Nullable<GameOutcomeMapping>,
+        outcome_winner -> Nullable<PlayerMapping>,
+        outcome_reason -> Nullable<Text>,
+        clock_base_ms -> Nullable<BigInt>,
+        clock_increment_ms -> BigInt,
+        clock_per_move -> Bool,
+        red_time_left_ms -> Nullable<BigInt>,
+        blue_time_left_ms -> Nullable<BigInt>,
+        clock_started_at -> Nullable<Timestamptz>,
+        version -> BigInt,
+        user_red -> Nullable<Uuid>,
+        user_blue -> Nullable<Uuid>,
+        rated -> Bool,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
     }
-}
\ No newline at end of file
+}
+table! {
+    use crate::game::PlayerMapping;
+    use diesel::sql_types::{Text, Timestamptz, Uuid};
+    game_messages {
+        id -> Uuid,
+        game_id -> Uuid,
+        player -> PlayerMapping,
+        text -> Text,
+        created_at -> Timestamptz,
+    }
+}
+table! {
+    use diesel::sql_types::{Double, Integer, Text, Timestamptz, Uuid};
+    users {
+        id -> Uuid,
+        username -> Text,
+        password_hash -> Text,
+        created_at -> Timestamptz,
+        rating -> Double,
+        rated_games -> Integer,
+    }
+}
+table! {
+    use diesel::sql_types::{Double, Timestamptz, Uuid};
+    rating_history {
+        id -> Uuid,
+        user_id -> Uuid,
+        opponent_id -> Uuid,
+        game_id -> Uuid,
+        rating_before -> Double,
+        rating_after -> Double,
+        created_at -> Timestamptz,
+    }
+}
+table! {
+    use crate::game::PlayerMapping;
+    use crate::db_schema::GameOutcomeMapping;
+    use diesel::sql_types::{Nullable, SmallInt, Text, Timestamptz, Uuid};
+    archived_games {
+        id -> Uuid,
+        state -> Text,
+        bot_id -> Nullable<Text>,
+        width -> SmallInt,
+        height -> SmallInt,
+        win_len -> SmallInt,
+        outcome -> GameOutcomeMapping,
+        outcome_winner -> Nullable<PlayerMapping>,
+        outcome_reason -> Nullable<Text>,
+        user_red -> Nullable<Uuid>,
+        user_blue -> Nullable<Uuid>,
+        created_at -> Timestamptz,
+        finished_at -> Timestamptz,
+    }
+}
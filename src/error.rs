@@ -0,0 +1,127 @@
+// what can go wrong, from a turn in the wrong row to the database being away.
+// clients tell the errors apart by the code (see graphql.rs), the message is only for humans and can change
+//
+// there is no Display on purpose: async-graphql turns anything Display into an error without the code,
+// so a forgotten conversion would slip through silently
+
+use diesel::result::Error as DieselError;
+use crate::game::{Player, Side};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GameError {
+    WrongPlayer,
+    RowFull { height: u8, side: Side },
+    OutOfBounds { height: u8 },
+    GameOver,
+    TimeIsUp,
+    NothingToTakeBack,
+    NotFound,
+    GameFull,
+    SlotTaken(Player),
+    // somebody else has saved the game since it was read, i.e. a double-clicked turn
+    Conflict { expected: i64, actual: i64 },
+    UndoAlreadyRequested,
+    NoUndoRequested,
+    DrawAlreadyOffered,
+    NoDrawOffered,
+    BotsPlayToTheEnd,
+    NotInThisGame,
+    RateLimited,
+    UsernameTaken,
+    WrongCredentials,
+    // no auth token, or a bad or expired one
+    Unauthenticated,
+    // the input doesn't make sense, the message says why
+    Invalid(String),
+    // our fault; the message is logged, not shown
+    Internal(String),
+}
+
+impl GameError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            GameError::WrongPlayer => "WRONG_PLAYER",
+            GameError::RowFull { .. } => "ROW_FULL",
+            GameError::OutOfBounds { .. } => "OUT_OF_BOUNDS",
+            GameError::GameOver => "GAME_OVER",
+            GameError::TimeIsUp => "TIME_IS_UP",
+            GameError::NothingToTakeBack => "NOTHING_TO_TAKE_BACK",
+            GameError::NotFound => "NOT_FOUND",
+            GameError::GameFull => "GAME_FULL",
+            GameError::SlotTaken(_) => "SLOT_TAKEN",
+            GameError::Conflict { .. } => "VERSION_CONFLICT",
+            GameError::UndoAlreadyRequested => "UNDO_ALREADY_REQUESTED",
+            GameError::NoUndoRequested => "NO_UNDO_REQUESTED",
+            GameError::DrawAlreadyOffered => "DRAW_ALREADY_OFFERED",
+            GameError::NoDrawOffered => "NO_DRAW_OFFERED",
+            GameError::BotsPlayToTheEnd => "BOTS_PLAY_TO_THE_END",
+            GameError::NotInThisGame => "NOT_IN_THIS_GAME",
+            GameError::RateLimited => "RATE_LIMITED",
+            GameError::UsernameTaken => "USERNAME_TAKEN",
+            GameError::WrongCredentials => "WRONG_CREDENTIALS",
+            GameError::Unauthenticated => "UNAUTHENTICATED",
+            GameError::Invalid(_) => "INVALID_INPUT",
+            GameError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            GameError::WrongPlayer => "Wrong player".into(),
+            GameError::RowFull { height, side } => format!("Row {} is full from the {}", height, side),
+            GameError::OutOfBounds { height } => format!("out of bounds {}", height),
+            GameError::GameOver => "Game is over".into(),
+            GameError::TimeIsUp => "Time is up".into(),
+            GameError::NothingToTakeBack => "No turns to take back".into(),
+            GameError::NotFound => "NotFound".into(),
+            GameError::GameFull => "game is full".into(),
+            GameError::SlotTaken(player) => format!("Player {} already been claimed", player.to_string().to_lowercase()),
+            GameError::Conflict { expected, actual } => format!("Game has changed: expected version {}, but it is {}", expected, actual),
+            GameError::UndoAlreadyRequested => "Undo already requested".into(),
+            GameError::NoUndoRequested => "No undo requested by the opponent".into(),
+            GameError::DrawAlreadyOffered => "Draw already offered".into(),
+            GameError::NoDrawOffered => "No draw offered by the opponent".into(),
+            GameError::BotsPlayToTheEnd => "Bots play to the end".into(),
+            GameError::NotInThisGame => "Player is not in this game".into(),
+            GameError::RateLimited => "Too many messages, slow down".into(),
+            GameError::UsernameTaken => "Username is taken".into(),
+            GameError::WrongCredentials => "Wrong username or password".into(),
+            GameError::Unauthenticated => "Log in first".into(),
+            GameError::Invalid(message) => message.clone(),
+            GameError::Internal(message) => message.clone(),
+        }
+    }
+}
+
+// the plain strings left are from the places that can't go wrong unless the data is broken
+impl From<String> for GameError {
+    fn from(e: String) -> Self {
+        GameError::Internal(e)
+    }
+}
+
+impl From<DieselError> for GameError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => GameError::NotFound,
+            e => GameError::Internal(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::result::Error as DieselError;
+    use crate::error::GameError;
+    use crate::game::{Player, Side};
+
+    #[test]
+    fn codes_and_messages() {
+        assert_eq!("WRONG_PLAYER", GameError::WrongPlayer.code());
+        assert_eq!("Wrong player", GameError::WrongPlayer.message());
+        assert_eq!("Row 3 is full from the Left", GameError::RowFull { height: 3, side: Side::Left }.message());
+        assert_eq!("Player red already been claimed", GameError::SlotTaken(Player::Red).message());
+        assert_eq!(GameError::NotFound, DieselError::NotFound.into());
+        assert_eq!("INTERNAL", GameError::from(DieselError::RollbackTransaction).code());
+    }
+}
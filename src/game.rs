@@ -1,14 +1,19 @@
 // the main game file; for better testing and portability, it's immutable
 
+use std::cmp::max;
 use std::str::SplitWhitespace;
 use strum_macros;
 use async_graphql::Enum;
+use crate::bitboard::Bitboard;
 use crate::db::GameStateSerialized;
-use crate::game::Player::{Blue, Red};
+use crate::error::GameError;
 
 // code assumes our field is at least 1x1
-const MIN_DIM: u8 = 1;
-pub const WIN_LEN: u8 = 4;
+pub(crate) const MIN_DIM: u8 = 1;
+// cells are indexed and turns are serialized as u8, so keep the field well under 255 cells
+pub(crate) const MAX_DIM: u8 = 10;
+const MIN_WIN_LEN: u8 = 2;
+pub const DEFAULT_WIN_LEN: u8 = 4;
 
 const SERIALIZATION_COL_SEPARATOR: &str = " "; // "separate" cols from each other, but "split" rows
 // reuse and keep near SERIALIZATION_COL_SEPARATOR
@@ -20,13 +25,24 @@ const SERIALIZATION_ROW_SEPARATOR: &str = "\n";
 
 // Vs. red and yellow for connect-4. Because it's a statement. "We're not connect-4!"
 // we assume Red is always going first. like in Chess.
-#[derive(Enum, Eq, PartialEq, Debug, Clone, Copy, strum_macros::Display)]
+#[derive(Enum, DbEnum, Eq, PartialEq, Debug, Clone, Copy, strum_macros::Display)]
+#[PgType = "player_type"]
+#[DbValueStyle = "SCREAMING_SNAKE_CASE"]
 pub enum Player {
     Red,
     Blue
 }
 
-const FIRST_PLAYER: Player = Player::Red;
+pub(crate) const FIRST_PLAYER: Player = Player::Red;
+
+impl Player {
+    pub fn other(&self) -> Player {
+        match self {
+            Player::Red => Player::Blue,
+            Player::Blue => Player::Red,
+        }
+    }
+}
 
 #[derive(Enum, Eq, PartialEq, Debug, Clone, Copy, strum_macros::Display)]
 pub enum Side {
@@ -34,21 +50,27 @@ pub enum Side {
     Right
 }
 
+impl Side {
+    pub fn other(&self) -> Side {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        }
+    }
+}
+
 type Height = u8; // the vertical axis, sides are to the left/right of it
 
 pub type Turn = (Player, Height, Side);
 pub type Move = (Height, Side);
 pub type CoordsHistory = Vec<Coords>;
+pub type HistoryTurn = (Turn, Coords); // the turn as it was made and where the piece landed
 type Cell = Option<Player>;
-type Field = Vec<Cell>;
 
 #[derive(Clone, Debug)]
 pub struct State {
-    size_x: u8,
-    size_y: u8,
     coords_history: CoordsHistory, // actually, we can do with Only this field
-    field: Field, // derivative to History+sizes but here for convenience and performance
-    winner_cache: Cell
+    board: Bitboard, // derivative to History+sizes but here for convenience and performance, the engine plays on it
 }
 
 pub(crate) type Coords = (u8, u8);
@@ -57,79 +79,55 @@ fn calc_field_index(size_x: u8, x: u8, y: u8) -> u8 {
     y * size_x/*or size_y?*/ + x
 }
 
-fn calc_coords(size_x: u8, index: u8) -> Coords {
-    (index % size_x, index / size_x)
-}
-
 // anything to do directly with the "coords" on the field
 pub trait MatrixOperations {
     fn calc_field_index(&self, x: u8, y: u8) -> u8;
     fn get_cell(&self, x: u8, y: u8) -> Result<Cell, String>;
-    fn next_cell_towards(&self, direction: Side, y: u8) -> Result<Option<Coords>, String>;
+    fn next_cell_towards(&self, direction: Side, y: u8) -> Result<Option<Coords>, GameError>;
     fn size_x(&self) -> u8;
     fn size_y(&self) -> u8;
-    fn line_iterators(&self) -> Vec<fn(u8, u8) -> Vec<Vec<Coords>>>;
-    fn lines(&self) -> Vec<Vec<Vec<Coords>>>;
+    fn line_iterators(&self) -> Vec<fn(u8, u8) -> Vec<Vec<Coords>>> {
+        vec![
+            make_rows_iterator,
+            make_columns_iterator,
+            make_diagonal_l_iterator,
+            make_diagonal_r_iterator
+        ]
+    }
+    fn lines(&self) -> Vec<Vec<Vec<Coords>>> {
+        self.line_iterators().iter().map(|f| f(self.size_x(), self.size_y())).collect()
+    }
 }
 
 impl MatrixOperations for State {
     // matrix coordinate -> array index
     fn calc_field_index(&self, x: u8, y: u8) -> u8 {
-        calc_field_index(self.size_x, x, y)
+        calc_field_index(self.size_x(), x, y)
     }
     // get cell at x, y
     fn get_cell(&self, x: u8, y: u8) -> Result<Cell, String> {
-        let (size_x, size_y, field) = (self.size_x, self.size_y, &self.field);
-        if x >= size_x || y >= size_y {
-            return Err(format!("out of bounds {} {}", x, y));
-        }
-        let cell = self.field[self.calc_field_index(x, y) as usize].clone();
-        Ok(cell)
+        self.board.get_cell(x, y)
     }
     // where a new piece would land; empty space or nothing
-    fn next_cell_towards(&self, direction: Side, y: u8) -> Result<Option<Coords>, String> {
-        if y >= self.size_y {
-            return Err(format!("out of bounds {}", y));
-        }
-        let size_x = self.size_x;
-        for i in 0..size_x {
-            let x = match direction {
-                Side::Left => i,
-                Side::Right => size_x - i - 1,
-            };
-            let cell = self.get_cell(x, y)?;
-            if cell.is_none() {
-                return Ok(Some((x, y)));
-            }
-        }
-        Ok(None)
+    fn next_cell_towards(&self, direction: Side, y: u8) -> Result<Option<Coords>, GameError> {
+        self.board.next_cell_towards(direction, y)
     }
     fn size_x(&self) -> u8 {
-        self.size_x
+        self.board.size_x()
     }
     fn size_y(&self) -> u8 {
-        self.size_y
-    }
-    fn line_iterators(&self) -> Vec<fn(u8, u8) -> Vec<Vec<Coords>>> {
-        vec![
-            make_rows_iterator,
-            make_columns_iterator,
-            make_diagonal_l_iterator,
-            make_diagonal_r_iterator
-        ]
-    }
-    fn lines(&self) -> Vec<Vec<Vec<Coords>>> {
-        self.line_iterators().iter().map(|f| f(self.size_x(), self.size_y())).collect()
+        self.board.size_y()
     }
 }
 
 // game "domain" logic
 pub trait GameOperations<T: MatrixOperations = Self> {
+    fn win_len(&self) -> u8;
     fn current_depth(&self) -> u8;
     fn max_depth(&self) -> u8;
     fn depth_left(&self) -> u8;
-    fn next_player(&self) -> Result<Player, String>;
-    fn last_player(&self) -> Result<Player, String>;
+    fn next_player(&self) -> Result<Player, GameError>;
+    fn last_player(&self) -> Result<Player, GameError>;
     fn can_continue(&self) -> bool;
     fn try_winner(&self) -> Cell;
     fn is_finished(&self) -> bool;
@@ -170,7 +168,7 @@ fn make_diagonal_l_iterator(width: u8, height: u8) -> Vec<Vec<Coords>> {
         for j in 0..(k + 1) {
             let i: u8 = k - j;
             if i < height && j < width {
-                diagonal.push((i, j));
+                diagonal.push((j, i));
             }
         }
         diagonals.push(diagonal);
@@ -185,7 +183,7 @@ fn make_diagonal_r_iterator(width: u8, height: u8) -> Vec<Vec<Coords>> {
         for j in 0..(k + 1) {
             let i: u8 = k - j;
             if i < height && j < width {
-                diagonal.push((i, height - j - 1));
+                diagonal.push((j, height - i - 1));
             }
         }
         diagonals.push(diagonal);
@@ -195,141 +193,50 @@ fn make_diagonal_r_iterator(width: u8, height: u8) -> Vec<Vec<Coords>> {
 
 
 impl GameOperations for State {
+    fn win_len(&self) -> u8 {
+        self.board.win_len()
+    }
     fn current_depth(&self) -> u8 {
-        self.coords_history.len() as u8
+        self.board.current_depth()
     }
     fn depth_left(&self) -> u8 {
-        self.max_depth() - self.current_depth()
+        self.board.depth_left()
     }
     fn max_depth(&self) -> u8 {
-        self.size_x * self.size_y
+        self.board.max_depth()
     }
-    fn next_player(&self) -> Result<Player, String> {
-        if !self.can_continue() {
-            return Err("Game is over".into());
-        }
-        Ok(match self.coords_history.last() {
-            Some(&(x, y)) => match self.field[self.calc_field_index(x, y) as usize] {
-                Some(Player::Red) => Player::Blue,
-                Some(Player::Blue) => Player::Red,
-                _ => panic!("next_player: unexpected cell state")
-            }
-            None => FIRST_PLAYER
-        })
+    fn next_player(&self) -> Result<Player, GameError> {
+        self.board.next_player()
     }
-    fn last_player(&self) -> Result<Player, String> {
-        self.coords_history.last().map(|&(x, y)| {
-            self.field[self.calc_field_index(x, y) as usize].unwrap()
-        }).ok_or("No moves yet".into())
+    fn last_player(&self) -> Result<Player, GameError> {
+        self.board.last_player()
     }
     fn can_continue(&self) -> bool {
-        !self.is_finished() && !self.is_stalemate()
+        self.board.can_continue()
     }
     fn try_winner(&self) -> Cell {
-        self.winner_cache
+        self.board.try_winner()
     }
     fn is_finished(&self) -> bool {
-        self.try_winner().is_some()
+        self.board.is_finished()
     }
     fn is_stalemate(&self) -> bool {
-        !self.is_finished() && self.size_x as usize * self.size_y as usize == self.coords_history.len()
+        self.board.is_stalemate()
     }
     fn possible_moves(&self) -> Vec<Move> {
-        if self.is_finished() {
-            return Vec::new();
-        }
-        if self.is_stalemate() {
-            return Vec::new();
-        }
-        let mut res = Vec::new();
-        let player = self.next_player().unwrap();
-        for i in 0..self.size_y {
-            // "better turns first" order, where the positions at the middle are prioritized https://github.com/PascalPons/connect4/commit/6caf32a4845bf1478b0d30bebd6366bfea75b7b5
-            let y = (self.size_y as i8) / 2 + (1-2 * (i % 2) as i8) * ( i as i8 + 1 ) / 2;
-            for s in vec![Side::Left, Side::Right].into_iter() {
-                if self.validate_turn((player, y.clone() as u8, s.clone())).is_ok() {
-                    res.push((y as u8, s))
-                }
-            }
-        }
-        res
+        self.board.possible_moves()
     }
     fn is_turn_winning(&self, turn: &Turn) -> bool {
-        let (player, height, side) = turn.clone();
-        let mut bruteforce_game = self.clone();
-        let move_result = bruteforce_game.push((player, height, side));
-        if move_result.is_err() {
-            return false;
-        }
-        bruteforce_game.try_winner().is_some()
+        self.board.is_turn_winning(turn)
     }
 }
 
 pub trait GameSerializations<T: MatrixOperations = Self> {
     fn serialize(&self) -> GameStateSerialized;
-    fn hash_non_historical(&self) -> String;
     fn deserialize(s: &GameStateSerialized) -> Result<T, String>;
     fn to_rows(&self) -> Vec<Vec<Option<Player>>>; // for network, keep here or...?
 }
 
-trait WinnerCache<T: GameOperations + MatrixOperations = Self> {
-    fn try_winner_(&mut self) -> Cell;
-}
-
-impl WinnerCache for State {
-    fn try_winner_(&mut self) -> Cell {
-        let last_move_ = self.coords_history.last();
-        if last_move_.is_none() {
-            return Cell::None;
-        }
-        let last_move = last_move_.unwrap();
-        fn check_line(state: &State, player: Player, lc: &Coords, rc: &Coords, acc: u8, lplus: &dyn Fn(&Coords) -> Option<Coords>, rplus: &dyn Fn(&Coords) -> Option<Coords>) -> bool {
-            if acc == WIN_LEN {
-                return true;
-            }
-            let next_lc = lplus(lc);
-            let next_rc = rplus(rc);
-            if next_lc.is_none() && next_rc.is_none() {
-                return false;
-            }
-            let next_lc_player_me: bool = next_lc.and_then(|c| state.field[state.calc_field_index(c.0, c.1) as usize]).map(|p| p == player).unwrap_or(false);
-            let next_rc_player_me: bool = next_rc.and_then(|c| state.field[state.calc_field_index(c.0, c.1) as usize]).map(|p| p == player).unwrap_or(false);
-            return if next_lc_player_me {
-                check_line(state, player, &next_lc.unwrap(), &rc, acc + 1, lplus, rplus)
-            } else if next_rc_player_me {
-                check_line(state, player, &lc, &next_rc.unwrap(), acc + 1, lplus, rplus)
-            } else {
-                false
-            }
-        }
-        fn checked_coords(c: (i8, i8), state: &State) -> Option<Coords> {
-            if c.0 < 0 || c.0 >= state.size_x as i8 || c.1 < 0 || c.1 >= state.size_y as i8 {
-                return None;
-            }
-            Some((c.0 as u8, c.1 as u8))
-        }
-        let horizontal_adders = (|c: &Coords| checked_coords((c.0 as i8 - 1, c.1 as i8), &self), |c: &Coords| checked_coords((c.0 as i8 + 1, c.1 as i8), &self));
-        let vertical_adders = (|c: &Coords| checked_coords((c.0 as i8, c.1 as i8 - 1), &self), |c: &Coords| checked_coords((c.0 as i8, c.1 as i8 + 1), &self));
-        let diag_l_r_adders = (|c: &Coords| checked_coords((c.0 as i8 - 1, c.1 as i8 + 1), &self), |c: &Coords| checked_coords((c.0 as i8 + 1, c.1 as i8 - 1), &self));
-        let diag_r_l_adders = (|c: &Coords| checked_coords((c.0 as i8 - 1, c.1 as i8 - 1), &self), |c: &Coords| checked_coords((c.0 as i8 + 1, c.1 as i8 + 1), &self));
-        let fns: Vec<(Box<dyn Fn(&Coords) -> Option<Coords>>, Box<dyn Fn(&Coords) -> Option<Coords>>)> = vec![
-            (Box::new(horizontal_adders.0), Box::new(horizontal_adders.1)),
-            (Box::new(vertical_adders.0), Box::new(vertical_adders.1)),
-            (Box::new(diag_l_r_adders.0), Box::new(diag_l_r_adders.1)),
-            (Box::new(diag_r_l_adders.0), Box::new(diag_r_l_adders.1)),
-        ];
-        for adder in fns.iter() {
-            let (l, r) = adder;
-            if check_line(&self, self.last_player().unwrap(), &last_move, &last_move, 1, &**l, &**r) {
-                return self.last_player().unwrap().into();
-            }
-        }
-
-        None
-    }
-
-}
-
 fn validate_continuous<T: Copy>(v: &Vec<Option<T>>) -> Result<Vec<T>, String> {
     if v.len() == 0 { return Ok(vec![]); }
     let bools = v.iter().map(|x| x.is_some()).collect::<Vec<bool>>();
@@ -380,102 +287,113 @@ fn deserialize_intermediate_history(s: &String) -> Result<Vec<(Coords, Player)>,
 impl GameSerializations for State {
 
     fn serialize(&self) -> GameStateSerialized {
-        let mut field: Vec<u8> = vec![0; self.size_x as usize * self.size_y as usize];
+        let mut field: Vec<u8> = vec![0; self.size_x() as usize * self.size_y() as usize];
         for (hi, coords) in self.coords_history.iter().enumerate() {
             let i = self.calc_field_index(coords.0, coords.1);
             field[i as usize] = hi as u8 + 1; // serialized turns are 1-indexed
         }
-        return GameStateSerialized(field.chunks(self.size_x as usize).map(|x| x.iter().map(|n|n.to_string()).collect::<Vec<String>>().join(SERIALIZATION_COL_SEPARATOR))
+        return GameStateSerialized(field.chunks(self.size_x() as usize).map(|x| x.iter().map(|n|n.to_string()).collect::<Vec<String>>().join(SERIALIZATION_COL_SEPARATOR))
             .collect::<Vec<String>>().join(SERIALIZATION_ROW_SEPARATOR));
     }
-    fn hash_non_historical(&self) -> String {
-        let mut field: Vec<Cell> = vec![None; self.size_x as usize * self.size_y as usize];
-        for (j, coords) in self.coords_history.iter().enumerate() {
-            let i = self.calc_field_index(coords.0, coords.1);
-            field[i as usize] = Some(if j % 2 == 0 { Red } else { Blue });
-        }
-        return field.chunks(self.size_x as usize).map(|x| x.iter().map(|n| (match n {
-            Some(Blue) => "B",
-            Some(Red) => "R",
-            None => "_",
-        }).to_string()).collect::<Vec<String>>().join(SERIALIZATION_COL_SEPARATOR))
-            .collect::<Vec<String>>().join(SERIALIZATION_ROW_SEPARATOR);
-    }
-
     fn deserialize(s: &GameStateSerialized) -> Result<State, String> {
         let (width, height) = validate_serialized_dimensions(&s.0)?;
+        if width > MAX_DIM || height > MAX_DIM { return Err(format!("{}x{} is too big", width, height)); }
         let mut state = State::new(width, height);
-        for (coords, player) in deserialize_intermediate_history(&s.0)?.iter() {
-            state.coords_history.push(coords.clone());
-            let index = calc_field_index(width, coords.0, coords.1);
-            state.field[index as usize] = Some(player.clone());
+        for &(coords, player) in deserialize_intermediate_history(&s.0)?.iter() {
+            state.coords_history.push(coords);
+            state.board.place(coords, player);
         }
-        state.update_winner();
         Ok(state)
     }
     fn to_rows(&self) -> Vec<Vec<Option<Player>>> {
-        let mut res = vec![vec![None; self.size_x as usize]; self.size_y as usize];
-        for (i, cell) in self.field.iter().enumerate() {
-            let coords = calc_coords(self.size_x, i as u8);
-            res[coords.1 as usize][coords.0 as usize] = cell.clone();
-        }
-        res
+        (0..self.size_y()).map(|y| (0..self.size_x()).map(|x| self.get_cell(x, y).unwrap()).collect()).collect()
     }
 }
 
 impl State {
 
-    fn validate_turn(&self, turn: Turn) -> Result<(), String> {
-        if !self.can_continue() {
-            return Err("Game is over".into());
-        }
-        if &self.next_player()? != &turn.0 {
-            return Err("Wrong player".into());
-        }
-        let next = self.next_cell_towards(turn.2.clone(), turn.1 as u8)?;
-        if next.is_none() {
-            return Err(format!("Can't push turn {} {} {}", turn.0, turn.1, turn.2));
-        }
-        Ok(())
-    }
-    pub fn push_move(&mut self, move_: Move) -> Result<(), String> {
+    pub fn push_move(&mut self, move_: Move) -> Result<(), GameError> {
         let player = self.next_player()?;
         let turn = (player, move_.0, move_.1);
         self.push(turn)
     }
-    fn update_winner(&mut self) -> () {
-        self.winner_cache = self.try_winner_();
-    }
-    pub fn push(&mut self, turn: Turn) -> Result<(), String> {
-        self.validate_turn(turn)?;
-        let next = self.next_cell_towards(turn.2.clone(), turn.1 as u8)?;
-        // self.history.push(turn.clone());
-        self.coords_history.push(next.unwrap());
-        let next_ = next.unwrap(); // already checked above
-        let index =  self.calc_field_index(next_.0.clone(), next_.1.clone()) as usize;
-        self.field[index] = Some(turn.0);
-        self.update_winner();
+    pub fn push(&mut self, turn: Turn) -> Result<(), GameError> {
+        let coords = self.board.push(turn)?;
+        self.coords_history.push(coords);
         Ok(())
     }
-    pub fn pop(&mut self) -> Result<(), String> {
-        // let turn = self.history.pop().ok_or_else(|| String::from("No turns to pop"))?;
-        let coords = self.coords_history.pop().ok_or_else(|| String::from("No turns to pop"))?;
-        let index = self.calc_field_index(coords.0, coords.1) as usize;
-        self.field[index] = None;
-        self.winner_cache = None;
+    pub fn pop(&mut self) -> Result<(), GameError> {
+        self.board.pop()?;
+        self.coords_history.pop();
+        Ok(())
+    }
+    // takes back the last turn of the player along with the opponent's reply, if there was one
+    pub fn take_back(&mut self, player: Player) -> Result<(), GameError> {
+        let pops = if self.last_player()? == player { 1 } else { 2 };
+        if self.current_depth() < pops {
+            return Err(GameError::NothingToTakeBack);
+        }
+        for _ in 0..pops {
+            self.pop()?;
+        }
         Ok(())
     }
+    // the side isn't stored anywhere, so play the history again from scratch to see which way each piece went.
+    // when a piece fills the last cell of a row, both sides are equally right, Left is reported
+    pub fn history(&self) -> Vec<HistoryTurn> {
+        let mut replay = State::new(self.size_x(), self.size_y()).with_win_len(self.win_len());
+        let mut res = Vec::with_capacity(self.coords_history.len());
+        for coords in self.coords_history.iter() {
+            let player = replay.next_player().unwrap(); // the history was valid when it was built
+            let side = if replay.next_cell_towards(Side::Left, coords.1) == Ok(Some(*coords)) { Side::Left } else { Side::Right };
+            let turn = (player, coords.1, side);
+            replay.push(turn).unwrap();
+            res.push((turn, *coords));
+        }
+        res
+    }
+    // the game as it was after up_to_turn turns
+    pub fn rewind(&self, up_to_turn: u8) -> Result<State, GameError> {
+        if up_to_turn > self.current_depth() {
+            return Err(GameError::Invalid(format!("Only {} turns made", self.current_depth())));
+        }
+        let mut res = self.clone();
+        while res.current_depth() > up_to_turn {
+            res.pop()?;
+        }
+        Ok(res)
+    }
     pub fn new(size_x: u8, size_y: u8) -> State {
         let size_xy = size_x as usize * size_y as usize;
-        State { size_x, size_y, coords_history: Vec::with_capacity(size_xy), field: vec![None; size_xy], winner_cache: None }
+        State { coords_history: Vec::with_capacity(size_xy), board: Bitboard::new(size_x, size_y, DEFAULT_WIN_LEN) }
+    }
+    // the serialized state knows nothing about the winning length, so it comes separately
+    pub fn with_win_len(mut self, win_len: u8) -> State {
+        self.board = self.board.with_win_len(win_len);
+        self
+    }
+    // for the engine, which doesn't care about the history
+    pub fn board(&self) -> &Bitboard {
+        &self.board
     }
 }
 
+pub fn validate_dimensions(width: u8, height: u8, win_len: u8) -> Result<(), GameError> {
+    if width < MIN_DIM || height < MIN_DIM || width > MAX_DIM || height > MAX_DIM {
+        return Err(GameError::Invalid(format!("field dimensions must be between {} and {}, got {}x{}", MIN_DIM, MAX_DIM, width, height)));
+    }
+    if win_len < MIN_WIN_LEN || win_len > max(width, height) {
+        return Err(GameError::Invalid(format!("win length must be between {} and {}, got {}", MIN_WIN_LEN, max(width, height), win_len)));
+    }
+    Ok(())
+}
+
 
 #[cfg(test)]
 mod tests {
     use crate::db::GameStateSerialized;
-    use crate::game::{calc_field_index, GameOperations, Move};
+    use crate::error::GameError;
+    use crate::game::{calc_field_index, GameOperations, Move, validate_dimensions};
     use crate::game::GameSerializations;
     use crate::game::Player::*;
     use crate::game::Side::{Left, Right};
@@ -583,6 +501,13 @@ mod tests {
 0  0  0  0 0  0  0
 0  0  0  0 0  0  0
 0  0  0  0 0  0  0
+    "#;
+    const GAME_WIN_LEN_3: &str = r#"
+1 3 5 0 0
+0 0 0 0 2
+0 0 0 0 4
+0 0 0 0 0
+0 0 0 0 0
     "#;
     #[test]
     fn calc_field_index_rect_test() {
@@ -684,14 +609,76 @@ mod tests {
         assert_eq!(vec![(3, Left), (3, Right), (4, Left), (4, Right), (5, Left), (5, Right), (0, Left), (0, Right), (6, Left), (6, Right)], state.possible_moves());
     }
     #[test]
-    fn hashing() {
-        let state = super::State::deserialize(&GameStateSerialized(r#"
-0 0 0 0 0
-0 0 0 0 4
-0 0 7 5 3
-0 0 0 2 1
-0 0 0 0 6
-    "#.to_string())).unwrap();
-        assert_eq!(state.hash_non_historical(), "_ _ _ _ _\n_ _ _ _ B\n_ _ R R R\n_ _ _ B R\n_ _ _ _ B");
+    fn winner_longer_win_len() {
+        let mut state = super::State::deserialize(&GameStateSerialized(GAME_NAIVE_VERTICAL_WON.to_string())).unwrap().with_win_len(5);
+        assert_eq!(None, state.try_winner());
+        state.push((Blue, 3, Right)).unwrap();
+        state.push((Red, 4, Left)).unwrap();
+        assert_eq!(Some(Red), state.try_winner())
+    }
+    #[test]
+    fn winner_shorter_win_len() {
+        let state = super::State::deserialize(&GameStateSerialized(GAME_WIN_LEN_3.to_string())).unwrap();
+        assert_eq!(None, state.try_winner());
+        let state = state.with_win_len(3);
+        assert_eq!(Some(Red), state.try_winner());
+        assert_eq!(Vec::<Move>::new(), state.possible_moves());
+    }
+    #[test]
+    fn history_sides() {
+        let state = super::State::deserialize(&GameStateSerialized(GAME_NAIVE_HORIZONTAL_WON.to_string())).unwrap();
+        let history = state.history();
+        assert_eq!(7, history.len());
+        assert_eq!(((Red, 3, Left), (0, 3)), history[0]);
+        assert_eq!(((Blue, 3, Right), (6, 3)), history[1]);
+        assert_eq!(((Red, 3, Left), (3, 3)), history[6]);
+    }
+    #[test]
+    fn rewind() {
+        let state = super::State::deserialize(&GameStateSerialized(GAME_NAIVE_HORIZONTAL_WON.to_string())).unwrap();
+        let rewound = state.rewind(6).unwrap();
+        assert!(!rewound.is_finished());
+        assert_eq!(Ok(Red), rewound.next_player());
+        assert_eq!(0, state.rewind(0).unwrap().current_depth());
+        assert!(state.rewind(8).is_err());
+    }
+    #[test]
+    fn take_back() {
+        let mut state = super::State::deserialize(&GameStateSerialized(GAME_BLUE_WINNING.to_string())).unwrap();
+        state.take_back(Red).unwrap();
+        assert_eq!(6, state.current_depth());
+        assert_eq!(Ok(Red), state.next_player());
+        // blue took a turn since the last red one, so it goes too
+        state.take_back(Red).unwrap();
+        assert_eq!(4, state.current_depth());
+        assert_eq!(Ok(Red), state.next_player());
+        state.take_back(Blue).unwrap();
+        assert_eq!(3, state.current_depth());
+        assert_eq!(Ok(Blue), state.next_player());
+        let mut state = state.rewind(1).unwrap();
+        assert_eq!(Err(GameError::NothingToTakeBack), state.take_back(Blue));
+        state.take_back(Red).unwrap();
+        assert_eq!(0, state.current_depth());
+    }
+    #[test]
+    fn turn_errors() {
+        let mut state = super::State::new(2, 1).with_win_len(2);
+        assert_eq!(Err(GameError::WrongPlayer), state.push((Blue, 0, Left)));
+        assert_eq!(Err(GameError::OutOfBounds { height: 1 }), state.push((Red, 1, Left)));
+        state.push((Red, 0, Left)).unwrap();
+        state.push((Blue, 0, Left)).unwrap();
+        assert_eq!(Err(GameError::GameOver), state.push((Red, 0, Left)));
+        let mut state = super::State::new(2, 2).with_win_len(2);
+        state.push((Red, 0, Left)).unwrap();
+        state.push((Blue, 0, Left)).unwrap();
+        assert_eq!(Err(GameError::RowFull { height: 0, side: Right }), state.push((Red, 0, Right)));
+    }
+    #[test]
+    fn dimensions_validation() {
+        assert!(validate_dimensions(9, 9, 5).is_ok());
+        assert!(validate_dimensions(0, 7, 4).is_err());
+        assert!(validate_dimensions(7, 11, 4).is_err());
+        assert!(validate_dimensions(4, 5, 6).is_err());
+        assert!(validate_dimensions(7, 7, 1).is_err());
     }
 }
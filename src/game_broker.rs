@@ -0,0 +1,196 @@
+// where game updates and chat messages come from. in memory, an update is only seen by the instance that made it;
+// with postgres, triggers on games and game_messages notify every instance listening, including the one that made it,
+// and the instances tell each other who is watching the games, see presence.rs. the matchmaking queue stays per instance
+
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+use futures_util::{stream, StreamExt};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_postgres::{AsyncMessage, Client, Notification, NoTls};
+use uuid::Uuid;
+use crate::broker::SimpleBroker;
+use crate::db::{DbConfig, GameToken};
+use crate::presence::{local_games, local_presence, remote_presence_changed, Presence, INSTANCE_ID, PRESENCE_REFRESH};
+use crate::store::Store;
+use crate::db_schema::{DbGame, DbGameMessage};
+
+// keep in sync with the notify_game_changed and notify_game_message triggers
+const GAME_CHANNEL: &str = "game_changed";
+const MESSAGE_CHANNEL: &str = "game_message";
+const PRESENCE_CHANNEL: &str = "presence_changed";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameBroker {
+    Memory,
+    Postgres,
+}
+
+static GAME_BROKER: OnceCell<GameBroker> = OnceCell::new();
+
+// BROKER=postgres to scale out, memory is the default
+pub fn init_game_broker() -> GameBroker {
+    let broker = match env::var("BROKER").as_deref() {
+        Ok("postgres") => GameBroker::Postgres,
+        Ok("memory") | Err(_) => GameBroker::Memory,
+        Ok(other) => panic!("Unknown broker {}", other),
+    };
+    GAME_BROKER.set(broker).expect("game broker is initialized twice");
+    broker
+}
+
+fn game_broker() -> GameBroker {
+    *GAME_BROKER.get().unwrap_or(&GameBroker::Memory)
+}
+
+pub fn publish_game(game: DbGame) {
+    match game_broker() {
+        GameBroker::Memory => SimpleBroker::publish(game),
+        GameBroker::Postgres => (), // the trigger has it covered
+    }
+}
+
+pub fn publish_game_message(message: DbGameMessage) {
+    match game_broker() {
+        GameBroker::Memory => SimpleBroker::publish(message),
+        GameBroker::Postgres => (),
+    }
+}
+
+// the games whose presence has changed here, for the listener to tell the other instances
+struct PresenceChanges {
+    sender: UnboundedSender<GameToken>,
+    receiver: Mutex<Option<UnboundedReceiver<GameToken>>>,
+}
+
+static PRESENCE_CHANGES: Lazy<PresenceChanges> = Lazy::new(|| {
+    let (sender, receiver) = unbounded_channel();
+    PresenceChanges { sender, receiver: Mutex::new(Some(receiver)) }
+});
+
+pub fn publish_presence(game: &GameToken) {
+    match game_broker() {
+        GameBroker::Memory => (), // the local count is all there is
+        GameBroker::Postgres => { let _ = PRESENCE_CHANGES.sender.send(game.clone()); }
+    }
+}
+
+// instance game spectators red blue
+fn presence_payload(game: &GameToken, presence: Presence) -> String {
+    format!("{} {} {} {} {}", *INSTANCE_ID, game.0, presence.spectators, presence.red, presence.blue)
+}
+
+fn parse_presence_payload(payload: &str) -> Option<(Uuid, GameToken, Presence)> {
+    let parts = payload.split(' ').collect::<Vec<_>>();
+    if parts.len() != 5 {
+        return None;
+    }
+    let count = |i: usize| parts[i].parse::<usize>().ok();
+    Some((Uuid::parse_str(parts[0]).ok()?, GameToken(Uuid::parse_str(parts[1]).ok()?),
+          Presence { spectators: count(2)?, red: count(3)?, blue: count(4)? }))
+}
+
+pub async fn run_game_broker(store: Store, config: DbConfig) {
+    if game_broker() != GameBroker::Postgres {
+        return;
+    }
+    if !config.url.starts_with("postgres") {
+        panic!("BROKER=postgres needs a postgres DATABASE_URL");
+    }
+    let mut presence_changes = PRESENCE_CHANGES.receiver.lock().unwrap().take().expect("game broker is run twice");
+    loop {
+        if let Err(e) = listen(&store, &config.url, &mut presence_changes).await {
+            eprintln!("game broker: {}", e);
+        }
+        // whatever happened in between is lost, but the next update carries the whole game anyways
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen(store: &Store, database_url: &str, presence_changes: &mut UnboundedReceiver<GameToken>) -> Result<(), String> {
+    let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await.map_err(|e| e.to_string())?;
+    // the connection is driven on its own, so that the client can notify meanwhile
+    let (sender, mut messages) = unbounded_channel();
+    let driver = tokio::spawn(async move {
+        let mut connection_messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = connection_messages.next().await {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let result = relay(store, &client, &mut messages, presence_changes).await;
+    driver.abort();
+    result
+}
+
+async fn relay(store: &Store,
+               client: &Client,
+               messages: &mut UnboundedReceiver<Result<AsyncMessage, tokio_postgres::Error>>,
+               presence_changes: &mut UnboundedReceiver<GameToken>) -> Result<(), String> {
+    let listen_query = format!("LISTEN {}; LISTEN {}; LISTEN {}", GAME_CHANNEL, MESSAGE_CHANNEL, PRESENCE_CHANNEL);
+    client.batch_execute(&listen_query).await.map_err(|e| e.to_string())?;
+    let mut refresh = tokio::time::interval(PRESENCE_REFRESH);
+    loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Some(message) => if let AsyncMessage::Notification(n) = message.map_err(|e| e.to_string())? {
+                    received(store, &n).await;
+                },
+                None => return Err("connection closed".into()),
+            },
+            Some(game) = presence_changes.recv() => tell_presence(client, &game).await?,
+            _ = refresh.tick() => for game in local_games() {
+                tell_presence(client, &game).await?;
+            },
+        }
+    }
+}
+
+async fn tell_presence(client: &Client, game: &GameToken) -> Result<(), String> {
+    let payload = presence_payload(game, local_presence(game));
+    client.execute("SELECT pg_notify($1, $2)", &[&PRESENCE_CHANNEL, &payload]).await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn received(store: &Store, n: &Notification) {
+    if n.channel() == PRESENCE_CHANNEL {
+        match parse_presence_payload(n.payload()) {
+            // this instance has published it already
+            Some((instance, game, presence)) if instance != *INSTANCE_ID => remote_presence_changed(instance, game, presence),
+            _ => (),
+        }
+        return;
+    }
+    let id = match Uuid::parse_str(n.payload()) {
+        Ok(id) => id,
+        Err(_) => return,
+    };
+    let published = match n.channel() {
+        GAME_CHANNEL => store.fetch_game_state(&GameToken(id)).await.map(SimpleBroker::publish),
+        MESSAGE_CHANNEL => store.fetch_game_message(&id).await.map(SimpleBroker::publish),
+        _ => return,
+    };
+    if let Err(e) = published {
+        eprintln!("game broker: {}", e.message());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::db::GameToken;
+    use crate::game_broker::{parse_presence_payload, presence_payload};
+    use crate::presence::{Presence, INSTANCE_ID};
+
+    #[test]
+    fn presence_payloads() {
+        let game = GameToken(Uuid::new_v4());
+        let presence = Presence { spectators: 3, red: 1, blue: 0 };
+        assert_eq!(Some((*INSTANCE_ID, game.clone(), presence)), parse_presence_payload(&presence_payload(&game, presence)));
+        assert_eq!(None, parse_presence_payload("nonsense"));
+        assert_eq!(None, parse_presence_payload(&format!("{} {} 1 2", *INSTANCE_ID, game.0)));
+    }
+}
@@ -1,12 +1,39 @@
-use crate::db::{claim_game_player, fetch_game_state_for_player, GameToken, init_game_state, PlayerToken, update_game_state, fetch_game_state};
-use crate::game::{GameOperations, Player, Side, State};
+use crate::accounts::{self, verify_token};
+use crate::db::{GameToken, PlayerToken, UserId};
+use crate::error::GameError;
+use crate::store::{Store, UserGamesFilter};
+use crate::game::{GameOperations, MatrixOperations, Player, Side, State, validate_dimensions};
 use crate::game::GameSerializations;
-use async_graphql::{FieldResult, Object, SimpleObject, InputObject, Schema, Subscription};
+use async_graphql::{Context, ErrorExtensions, FieldResult, Object, SimpleObject, InputObject, Schema, Subscription};
 use async_graphql::futures_util::Stream;
 use tokio_stream::StreamExt;
 use crate::adversary::BotId;
-use crate::broker::SimpleBroker;
-use crate::db_schema::DbGame;
+use crate::ratings::bot_user_id;
+use crate::broker::{self, BrokerMetrics, SimpleBroker};
+use crate::db_schema::{DbGame, DbGameMessage, DbRatingChange, DbUser, GameDimensions, GameOutcome};
+use crate::chat::send_message;
+use crate::clock;
+use crate::presence::{enter, presence, PresenceChanged};
+use crate::matchmaking::{join_queue, leave_queue, take_found, watch, MatchFound, TicketToken};
+use crate::clock::TimeControl;
+use crate::lobby::{LobbyChange, LobbyList, OpenGames};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use std::cmp::max;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_PAGE_LIMIT: i32 = 50;
+const MAX_PAGE_LIMIT: i32 = 200;
+
+// limit and offset for the store
+fn page(limit: Option<i32>, offset: Option<i32>) -> Result<(i64, i64), GameError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+        return Err(GameError::Invalid(format!("Limit must be between 1 and {}", MAX_PAGE_LIMIT)));
+    }
+    Ok((limit as i64, max(offset.unwrap_or(0), 0) as i64))
+}
 
 #[derive(SimpleObject)]
 pub struct GameStateResult {
@@ -17,6 +44,54 @@ pub struct GameStateResult {
     is_stalemate: bool,
     red_claimed: bool,
     blue_claimed: bool,
+    width: u8,
+    height: u8,
+    win_len: u8,
+    history: Vec<HistoryTurnResult>,
+    undo_requested_by: Option<Player>,
+    draw_offered_by: Option<Player>,
+    outcome: Option<GameOutcome>,
+    outcome_reason: Option<String>,
+    clock: Option<ClockResult>,
+    spectator_count: u32,
+    red_online: bool,
+    blue_online: bool,
+    version: i64, // pass it along with the next turn
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+// time left is as of the moment of the response, the ticking one keeps going down from there
+#[derive(SimpleObject)]
+pub struct ClockResult {
+    red_time_left_ms: i64,
+    blue_time_left_ms: i64,
+    ticking: Option<Player>,
+    increment_ms: i64,
+    per_move: bool,
+}
+
+impl ClockResult {
+    fn from_db_game(db_game: &DbGame) -> Option<ClockResult> {
+        let now = Utc::now();
+        Some(ClockResult {
+            red_time_left_ms: max(clock::time_left(db_game, Player::Red, now)?, 0),
+            blue_time_left_ms: max(clock::time_left(db_game, Player::Blue, now)?, 0),
+            ticking: clock::ticking(db_game),
+            increment_ms: db_game.clock_increment_ms,
+            per_move: db_game.clock_per_move,
+        })
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct HistoryTurnResult {
+    turn: u8, // 1-indexed, same as in the serialized state
+    player: Player,
+    height: u8,
+    side: Side,
+    x: u8,
+    y: u8,
 }
 
 #[derive(SimpleObject)]
@@ -25,9 +100,159 @@ pub struct ClaimPlayerResult {
     player_token: PlayerToken,
 }
 
+#[derive(SimpleObject)]
+pub struct ChatMessageResult {
+    id: Uuid,
+    player: Player,
+    text: String,
+    created_at: DateTime<Utc>,
+}
+
+impl ChatMessageResult {
+    fn from_db_game_message(m: &DbGameMessage) -> ChatMessageResult {
+        ChatMessageResult {
+            id: m.id,
+            player: m.player,
+            text: m.text.clone(),
+            created_at: m.created_at,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct UserResult {
+    id: UserId,
+    username: String,
+    created_at: DateTime<Utc>,
+    rating: i32,
+    rated_games: i32,
+}
+
+impl UserResult {
+    fn from_db_user(u: &DbUser) -> UserResult {
+        UserResult {
+            id: u.id.clone(),
+            username: u.username.clone(),
+            created_at: u.created_at,
+            rating: u.rating.round() as i32,
+            rated_games: u.rated_games,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct RatingChangeResult {
+    game_id: GameToken,
+    opponent: String,
+    rating_before: i32,
+    rating_after: i32,
+    created_at: DateTime<Utc>,
+}
+
+impl RatingChangeResult {
+    fn from_db_rating_change(c: &DbRatingChange, opponent: &DbUser) -> RatingChangeResult {
+        RatingChangeResult {
+            game_id: c.game_id.clone(),
+            opponent: opponent.username.clone(),
+            rating_before: c.rating_before.round() as i32,
+            rating_after: c.rating_after.round() as i32,
+            created_at: c.created_at,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct LobbyChangeResult {
+    change: LobbyChange,
+    game: GameStateResult,
+}
+
+// pass the token along as authToken wherever it's asked for
+#[derive(SimpleObject)]
+pub struct AuthResult {
+    user: UserResult,
+    auth_token: String,
+}
+
+impl AuthResult {
+    fn from_db_user_and_token((user, auth_token): (DbUser, String)) -> AuthResult {
+        AuthResult {
+            user: UserResult::from_db_user(&user),
+            auth_token,
+        }
+    }
+}
+
+// the seat the user has in the game, and the token to keep playing it with from anywhere
+#[derive(SimpleObject)]
+pub struct MyGameResult {
+    game: GameStateResult,
+    player: Player,
+    player_token: Option<PlayerToken>,
+}
+
+// counted per subscriber since the start of this instance
+#[derive(SimpleObject)]
+pub struct BrokerMetricsResult {
+    published: u64,
+    dropped: u64,
+    coalesced: u64,
+    disconnected: u64,
+    max_lag: u64,
+}
+
+impl BrokerMetricsResult {
+    fn from_metrics(m: &BrokerMetrics) -> BrokerMetricsResult {
+        BrokerMetricsResult {
+            published: m.published,
+            dropped: m.dropped,
+            coalesced: m.coalesced,
+            disconnected: m.disconnected,
+            max_lag: m.max_lag as u64,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct MatchFoundResult {
+    ticket: TicketToken,
+    game: GameStateResult,
+    player: Player,
+    player_token: PlayerToken,
+}
+
+impl MatchFoundResult {
+    fn from_match_found(m: &MatchFound) -> MatchFoundResult {
+        MatchFoundResult {
+            ticket: m.ticket.clone(),
+            game: GameStateResult::from_db_game(&m.game),
+            player: m.player,
+            player_token: m.player_token.clone(),
+        }
+    }
+}
+
 impl GameStateResult {
     pub fn from_db_game(db_game: &DbGame) -> GameStateResult {
-        let game = game_from_db_game(db_game).unwrap();
+        let mut res = GameStateResult::from_db_game_and_state(db_game, &game_from_db_game(db_game).unwrap());
+        // a game can also end off the board, i.e. with a resignation
+        if db_game.outcome.is_some() {
+            res.next_player = None;
+            res.winner = db_game.outcome_winner;
+            res.outcome = db_game.outcome;
+            res.outcome_reason = db_game.outcome_reason.clone();
+        }
+        res
+    }
+    // the db game is still the source of the metadata, but the board can be any (i.e. a replayed) one
+    pub fn from_db_game_and_state(db_game: &DbGame, game: &State) -> GameStateResult {
+        let presence = presence(&db_game.id);
+        // a bot never leaves
+        let bot_seat = match (db_game.bot_id, &db_game.player_red, &db_game.player_blue) {
+            (Some(_), None, Some(_)) => Some(Player::Red),
+            (Some(_), Some(_), None) => Some(Player::Blue),
+            _ => None,
+        };
         GameStateResult {
             id: db_game.id.clone(),
             state: game.to_rows(),
@@ -36,28 +261,211 @@ impl GameStateResult {
             is_stalemate: game.is_stalemate(),
             red_claimed: db_game.player_red.is_some(),
             blue_claimed: db_game.player_blue.is_some(),
+            width: game.size_x(),
+            height: game.size_y(),
+            win_len: game.win_len(),
+            history: game.history().into_iter().enumerate().map(|(i, ((player, height, side), (x, y)))| HistoryTurnResult {
+                turn: i as u8 + 1,
+                player,
+                height,
+                side,
+                x,
+                y,
+            }).collect(),
+            undo_requested_by: db_game.undo_requested_by,
+            draw_offered_by: db_game.draw_offered_by,
+            outcome: None,
+            outcome_reason: None,
+            clock: ClockResult::from_db_game(db_game),
+            spectator_count: presence.spectators as u32,
+            red_online: presence.red > 0 || bot_seat == Some(Player::Red),
+            blue_online: presence.blue > 0 || bot_seat == Some(Player::Blue),
+            version: db_game.version,
+            created_at: db_game.created_at,
+            updated_at: db_game.updated_at,
         }
     }
 }
 
 pub(crate) struct QueryRoot;
 
-fn game_from_db_game(db_game: &DbGame) -> Result<State, String> {
-    State::deserialize(&db_game.state)
+fn store<'a>(ctx: &Context<'a>) -> &'a Store {
+    ctx.data_unchecked::<Store>()
+}
+
+// the code is what the clients go by, the message can change any time
+impl From<GameError> for async_graphql::Error {
+    fn from(e: GameError) -> Self {
+        let message = match &e {
+            GameError::Internal(internal) => {
+                eprintln!("internal error: {}", internal);
+                "Something went wrong".to_string()
+            }
+            e => e.message(),
+        };
+        async_graphql::Error::new(message).extend_with(|_, extensions| {
+            extensions.set("code", e.code());
+            if let GameError::Conflict { actual, .. } = e {
+                extensions.set("version", actual);
+            }
+        })
+    }
+}
+
+fn game_from_db_game(db_game: &DbGame) -> Result<State, GameError> {
+    Ok(db_game.game()?)
+}
+
+fn ongoing_game_from_db_game(db_game: &DbGame) -> Result<State, GameError> {
+    let state = game_from_db_game(db_game)?;
+    if db_game.outcome.is_some() || !state.can_continue() {
+        return Err(GameError::GameOver);
+    }
+    Ok(state)
 }
 
 #[Object]
 impl QueryRoot {
-    pub(crate) async fn game(&self, game_token: GameToken) -> FieldResult<GameStateResult> {
-        Ok(GameStateResult::from_db_game(&fetch_game_state(&game_token).await?))
+    pub(crate) async fn game(&self, ctx: &Context<'_>, game_token: GameToken) -> FieldResult<GameStateResult> {
+        Ok(GameStateResult::from_db_game(&store(ctx).fetch_game_or_archived(&game_token).await?))
+    }
+    pub(crate) async fn replay(&self, ctx: &Context<'_>, game_token: GameToken, up_to_turn: u8) -> FieldResult<GameStateResult> {
+        let db_game = store(ctx).fetch_game_or_archived(&game_token).await?;
+        let game = game_from_db_game(&db_game)?.rewind(up_to_turn)?;
+        Ok(GameStateResult::from_db_game_and_state(&db_game, &game))
+    }
+    pub(crate) async fn chat(&self, ctx: &Context<'_>, game_token: GameToken) -> Result<Vec<ChatMessageResult>, GameError> {
+        Ok(store(ctx).fetch_game_messages(&game_token).await?.iter().map(ChatMessageResult::from_db_game_message).collect())
+    }
+    pub(crate) async fn me(&self, ctx: &Context<'_>, player_token: PlayerToken) -> FieldResult<Player> {
+        Ok(store(ctx).fetch_game_state_for_player(&player_token).await?.player)
+    }
+    pub(crate) async fn account(&self, ctx: &Context<'_>, auth_token: String) -> Result<UserResult, GameError> {
+        Ok(UserResult::from_db_user(&store(ctx).fetch_user(&verify_token(&auth_token)?).await?))
+    }
+    // the games where the user has claimed a seat while logged in
+    pub(crate) async fn my_games(&self, ctx: &Context<'_>, auth_token: String, filter: Option<GamesFilterInput>) -> Result<Vec<MyGameResult>, GameError> {
+        let user_id = verify_token(&auth_token)?;
+        let filter = filter.map(|f| f.filter()).unwrap_or_default();
+        Ok(store(ctx).fetch_user_games(&user_id, &filter).await?.iter().filter_map(|db_game| {
+            let player = db_game.user_seat(&user_id)?;
+            Some(MyGameResult {
+                game: GameStateResult::from_db_game(db_game),
+                player,
+                player_token: db_game.player_token(player).cloned(),
+            })
+        }).collect())
+    }
+    // the users with at least one rated game, bots included
+    pub(crate) async fn leaderboard(&self, ctx: &Context<'_>, limit: Option<i32>, offset: Option<i32>) -> Result<Vec<UserResult>, GameError> {
+        let (limit, offset) = page(limit, offset)?;
+        Ok(store(ctx).fetch_leaderboard(limit, offset).await?.iter().map(UserResult::from_db_user).collect())
+    }
+    // the most recently updated first
+    pub(crate) async fn open_games(&self, ctx: &Context<'_>, limit: Option<i32>, offset: Option<i32>) -> Result<Vec<GameStateResult>, GameError> {
+        lobby_games(ctx, LobbyList::Open, limit, offset).await
+    }
+    pub(crate) async fn ongoing_games(&self, ctx: &Context<'_>, limit: Option<i32>, offset: Option<i32>) -> Result<Vec<GameStateResult>, GameError> {
+        lobby_games(ctx, LobbyList::Ongoing, limit, offset).await
+    }
+    pub(crate) async fn finished_games(&self, ctx: &Context<'_>, limit: Option<i32>, offset: Option<i32>) -> Result<Vec<GameStateResult>, GameError> {
+        lobby_games(ctx, LobbyList::Finished, limit, offset).await
+    }
+    // oldest first, optionally only the games against one opponent. a bot goes by its username or its botId
+    pub(crate) async fn rating_history(&self, ctx: &Context<'_>, username: String, opponent: Option<String>) -> Result<Vec<RatingChangeResult>, GameError> {
+        let store = store(ctx);
+        let user = fetch_user_or_bot(store, &username).await?;
+        let opponent = match opponent {
+            Some(opponent) => Some(fetch_user_or_bot(store, &opponent).await?),
+            None => None,
+        };
+        let changes = store.fetch_rating_history(&user.id, opponent.as_ref().map(|o| o.id.clone())).await?;
+        let mut opponents: HashMap<UserId, DbUser> = opponent.into_iter().map(|o| (o.id.clone(), o)).collect();
+        let mut result = vec![];
+        for change in changes.iter() {
+            if !opponents.contains_key(&change.opponent_id) {
+                opponents.insert(change.opponent_id.clone(), store.fetch_user(&change.opponent_id).await?);
+            }
+            result.push(RatingChangeResult::from_db_rating_change(change, &opponents[&change.opponent_id]));
+        }
+        Ok(result)
     }
-    pub(crate) async fn me(&self, player_token: PlayerToken) -> FieldResult<Player> {
-        Ok(fetch_game_state_for_player(&player_token).await?.player)
+    pub(crate) async fn broker_metrics(&self) -> BrokerMetricsResult {
+        BrokerMetricsResult::from_metrics(&broker::metrics())
     }
 }
 
+// "EASY" is "EASY bot", unless a player has taken the name
+async fn fetch_user_or_bot(store: &Store, name: &str) -> Result<DbUser, GameError> {
+    match (store.fetch_user_by_name(name).await, name.parse::<BotId>()) {
+        (Err(GameError::NotFound), Ok(bot)) => store.fetch_user(&bot_user_id(bot)).await,
+        (result, _) => result,
+    }
+}
+
+async fn lobby_games(ctx: &Context<'_>, list: LobbyList, limit: Option<i32>, offset: Option<i32>) -> Result<Vec<GameStateResult>, GameError> {
+    let (limit, offset) = page(limit, offset)?;
+    Ok(store(ctx).fetch_lobby_games(list, limit, offset).await?.iter().map(GameStateResult::from_db_game).collect())
+}
+
 pub(crate) type GraphQlSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
 
+// anything omitted falls back to the defaults
+#[derive(InputObject)]
+struct GameConfigInput {
+    width: Option<u8>,
+    height: Option<u8>,
+    win_len: Option<u8>,
+}
+
+impl GameConfigInput {
+    fn dimensions(&self) -> GameDimensions {
+        let default = GameDimensions::default();
+        GameDimensions {
+            width: self.width.unwrap_or(default.width),
+            height: self.height.unwrap_or(default.height),
+            win_len: self.win_len.unwrap_or(default.win_len),
+        }
+    }
+}
+
+// either a budget for the whole game with an optional increment, or a budget for each turn
+#[derive(InputObject)]
+struct TimeControlInput {
+    initial_seconds: Option<i32>,
+    increment_seconds: Option<i32>,
+    per_move_seconds: Option<i32>,
+}
+
+impl TimeControlInput {
+    fn time_control(&self) -> Result<TimeControl, GameError> {
+        let increment_ms = self.increment_seconds.unwrap_or(0) as i64 * 1000;
+        match (self.initial_seconds, self.per_move_seconds) {
+            (Some(initial), None) => TimeControl::new(initial as i64 * 1000, increment_ms, false),
+            (None, Some(per_move)) => TimeControl::new(per_move as i64 * 1000, increment_ms, true),
+            _ => Err(GameError::Invalid("exactly one of initialSeconds and perMoveSeconds is expected".into())),
+        }
+    }
+}
+
+// anything omitted matches any game
+#[derive(InputObject)]
+pub(crate) struct GamesFilterInput {
+    finished: Option<bool>,
+    outcome: Option<GameOutcome>,
+    vs_bot: Option<bool>,
+}
+
+impl GamesFilterInput {
+    fn filter(&self) -> UserGamesFilter {
+        UserGamesFilter {
+            finished: self.finished,
+            outcome: self.outcome,
+            vs_bot: self.vs_bot,
+        }
+    }
+}
+
 #[derive(InputObject)]
 struct TurnInput {
     side: Side,
@@ -68,38 +476,204 @@ pub(crate) struct MutationRoot;
 
 #[Object]
 impl MutationRoot {
-    async fn init_game(&self, bot_id: Option<BotId>) -> FieldResult<GameStateResult> {
-        Ok(GameStateResult::from_db_game(&init_game_state(bot_id).await?))
+    async fn init_game(&self, ctx: &Context<'_>, bot_id: Option<BotId>, config: Option<GameConfigInput>, time_control: Option<TimeControlInput>) -> FieldResult<GameStateResult> {
+        let dimensions = config.map(|c| c.dimensions()).unwrap_or_default();
+        let time_control = time_control.map(|t| t.time_control()).transpose()?;
+        Ok(GameStateResult::from_db_game(&store(ctx).init_game_state(bot_id, dimensions, time_control).await?))
     }
-    async fn claim_player(&self, game_token: GameToken, player: Player) -> Result<ClaimPlayerResult, String> {
-        let (id, db_game) = claim_game_player(&game_token, player).await?;
+    // the ticket is to subscribe to matchFound with
+    async fn join_queue(&self, ctx: &Context<'_>, config: Option<GameConfigInput>) -> Result<TicketToken, GameError> {
+        let dimensions = config.map(|c| c.dimensions()).unwrap_or_default();
+        validate_dimensions(dimensions.width, dimensions.height, dimensions.win_len)?;
+        join_queue(store(ctx), dimensions).await
+    }
+    async fn leave_queue(&self, ticket: TicketToken) -> bool {
+        leave_queue(&ticket)
+    }
+    async fn register(&self, ctx: &Context<'_>, username: String, password: String) -> Result<AuthResult, GameError> {
+        Ok(AuthResult::from_db_user_and_token(accounts::register(store(ctx), &username, &password).await?))
+    }
+    async fn login(&self, ctx: &Context<'_>, username: String, password: String) -> Result<AuthResult, GameError> {
+        Ok(AuthResult::from_db_user_and_token(accounts::login(store(ctx), &username, &password).await?))
+    }
+    // logged in, the seat shows up in myGames
+    async fn claim_player(&self, ctx: &Context<'_>, game_token: GameToken, player: Player, auth_token: Option<String>) -> Result<ClaimPlayerResult, GameError> {
+        let user = auth_token.map(|t| verify_token(&t)).transpose()?;
+        let (id, db_game) = store(ctx).claim_game_player(&game_token, player, user).await?;
         let game = GameStateResult::from_db_game(&db_game);
         Ok(ClaimPlayerResult {
             player_token: PlayerToken(id),
             game,
         })
     }
-    async fn turn(&self, player_token: PlayerToken, turn: TurnInput) -> Result<GameStateResult, String> {
-        let db_game_and_player = fetch_game_state_for_player(&player_token).await?;
-        let db_game = db_game_and_player.game;
+    // with the expected version, a turn made on a board that has changed since is refused
+    async fn turn(&self, ctx: &Context<'_>, player_token: PlayerToken, turn: TurnInput, expected_version: Option<i64>) -> FieldResult<GameStateResult> {
+        let db_game_and_player = store(ctx).fetch_game_state_for_player(&player_token).await?;
+        let mut db_game = db_game_and_player.game;
         let player = db_game_and_player.player;
-        let mut state = game_from_db_game(&db_game)?;
+        if let Some(expected) = expected_version.filter(|v| *v != db_game.version) {
+            return Err(GameError::Conflict { expected, actual: db_game.version }.into());
+        }
+        let mut state = ongoing_game_from_db_game(&db_game)?;
+        // the clock watcher might have not got to it yet
+        if let Some(flagged) = clock::flagged(&db_game, Utc::now()) {
+            clock::flag(&mut db_game, flagged);
+            store(ctx).save_game(&db_game).await?;
+            return Err(GameError::TimeIsUp.into());
+        }
         state.push((player, turn.height, turn.side))?;
-        let new_db_game = update_game_state(&db_game.id, state.serialize()).await?;
+        let new_db_game = store(ctx).update_game_state(&db_game, state.serialize()).await?;
+        Ok(GameStateResult::from_db_game(&new_db_game))
+    }
+    // a bot doesn't mind, its turn is taken back right away along with the player's one
+    async fn request_undo(&self, ctx: &Context<'_>, player_token: PlayerToken) -> Result<GameStateResult, GameError> {
+        let db_game_and_player = store(ctx).fetch_game_state_for_player(&player_token).await?;
+        let mut db_game = db_game_and_player.game;
+        let player = db_game_and_player.player;
+        let mut state = ongoing_game_from_db_game(&db_game)?;
+        if db_game.undo_requested_by.is_some() {
+            return Err(GameError::UndoAlreadyRequested);
+        }
+        state.take_back(player)?;
+        let new_db_game = if db_game.bot_id.is_some() {
+            store(ctx).update_game_state(&db_game, state.serialize()).await?
+        } else {
+            db_game.undo_requested_by = Some(player);
+            store(ctx).save_game(&db_game).await?
+        };
         Ok(GameStateResult::from_db_game(&new_db_game))
     }
+    async fn answer_undo(&self, ctx: &Context<'_>, player_token: PlayerToken, accept: bool) -> Result<GameStateResult, GameError> {
+        let db_game_and_player = store(ctx).fetch_game_state_for_player(&player_token).await?;
+        let mut db_game = db_game_and_player.game;
+        let player = db_game_and_player.player;
+        let requested_by = match db_game.undo_requested_by {
+            Some(p) if p != player => p,
+            _ => return Err(GameError::NoUndoRequested),
+        };
+        let new_db_game = if accept {
+            let mut state = ongoing_game_from_db_game(&db_game)?;
+            state.take_back(requested_by)?;
+            store(ctx).update_game_state(&db_game, state.serialize()).await?
+        } else {
+            db_game.undo_requested_by = None;
+            store(ctx).save_game(&db_game).await?
+        };
+        Ok(GameStateResult::from_db_game(&new_db_game))
+    }
+    async fn send_chat(&self, ctx: &Context<'_>, player_token: PlayerToken, text: String) -> Result<ChatMessageResult, GameError> {
+        Ok(ChatMessageResult::from_db_game_message(&send_message(store(ctx), &player_token, &text).await?))
+    }
+    async fn resign(&self, ctx: &Context<'_>, player_token: PlayerToken) -> Result<GameStateResult, GameError> {
+        let db_game_and_player = store(ctx).fetch_game_state_for_player(&player_token).await?;
+        let mut db_game = db_game_and_player.game;
+        let player = db_game_and_player.player;
+        ongoing_game_from_db_game(&db_game)?;
+        db_game.set_outcome(GameOutcome::RESIGN, Some(player.other()), format!("{} resigned", player));
+        Ok(GameStateResult::from_db_game(&store(ctx).save_game(&db_game).await?))
+    }
+    async fn offer_draw(&self, ctx: &Context<'_>, player_token: PlayerToken) -> Result<GameStateResult, GameError> {
+        let db_game_and_player = store(ctx).fetch_game_state_for_player(&player_token).await?;
+        let mut db_game = db_game_and_player.game;
+        let player = db_game_and_player.player;
+        ongoing_game_from_db_game(&db_game)?;
+        if db_game.bot_id.is_some() {
+            return Err(GameError::BotsPlayToTheEnd);
+        }
+        if db_game.draw_offered_by.is_some() {
+            return Err(GameError::DrawAlreadyOffered);
+        }
+        db_game.draw_offered_by = Some(player);
+        Ok(GameStateResult::from_db_game(&store(ctx).save_game(&db_game).await?))
+    }
+    async fn accept_draw(&self, ctx: &Context<'_>, player_token: PlayerToken) -> Result<GameStateResult, GameError> {
+        let db_game_and_player = store(ctx).fetch_game_state_for_player(&player_token).await?;
+        let mut db_game = db_game_and_player.game;
+        let player = db_game_and_player.player;
+        ongoing_game_from_db_game(&db_game)?;
+        match db_game.draw_offered_by {
+            Some(p) if p != player => (),
+            _ => return Err(GameError::NoDrawOffered),
+        };
+        db_game.set_outcome(GameOutcome::DRAW, None, "draw agreed".into());
+        Ok(GameStateResult::from_db_game(&store(ctx).save_game(&db_game).await?))
+    }
 }
 
 pub(crate) struct SubscriptionRoot;
 
 #[Subscription]
 impl SubscriptionRoot {
-    // a "readonly" game for anyone to subscribe to. I push the whole game state, because I'm lazy and also it isn't big size anyways
-    async fn game(&self, game_token: GameToken) -> impl Stream<Item = GameStateResult> {
-        SimpleBroker::<DbGame>::subscribe().filter(move |db_game: &DbGame| {
-            db_game.id == game_token
-        }).map(|db_game: DbGame| {
+    // a "readonly" game for anyone to subscribe to. I push the whole game state, because I'm lazy and also it isn't big size anyways.
+    // players pass their token to be seen online, anyone else is a spectator
+    async fn game(&self, ctx: &Context<'_>, game_token: GameToken, player_token: Option<PlayerToken>) -> Result<impl Stream<Item = GameStateResult>, GameError> {
+        let seat = match player_token {
+            Some(token) => {
+                let db_game_and_player = store(ctx).fetch_game_state_for_player(&token).await?;
+                if db_game_and_player.game.id != game_token {
+                    return Err(GameError::NotInThisGame);
+                }
+                Some(db_game_and_player.player)
+            }
+            None => None,
+        };
+        let game_token_ = game_token.clone();
+        let updates = SimpleBroker::<DbGame>::subscribe_coalesced_where(move |db_game: &DbGame| db_game.id == game_token_);
+        let game_token_ = game_token.clone();
+        // the game itself didn't change, but its presence did
+        let presence_changes = SimpleBroker::<PresenceChanged>::subscribe_coalesced_where(move |p: &PresenceChanged| p.0 == game_token_)
+            .map(|_| None);
+        let store = store(ctx).clone();
+        let game_token_ = game_token.clone();
+        // presence is filled in from the latest game the stream has seen, it's only fetched if there is none yet
+        let last: Arc<Mutex<Option<DbGame>>> = Default::default();
+        let changes = futures_util::stream::select(updates.map(Some), presence_changes);
+        let changes = futures_util::StreamExt::filter_map(changes, move |update: Option<DbGame>| {
+            let (store, game_token, last) = (store.clone(), game_token_.clone(), last.clone());
+            async move {
+                let known = update.or_else(|| last.lock().unwrap().clone());
+                let db_game = match known {
+                    Some(db_game) => db_game,
+                    None => store.fetch_game_state(&game_token).await.ok()?,
+                };
+                *last.lock().unwrap() = Some(db_game.clone());
+                Some(db_game)
+            }
+        });
+        // subscribed already, so the subscriber gets its own arrival as the initial state
+        let guard = enter(game_token, seat);
+        Ok(changes.map(move |db_game: DbGame| {
+            let _ = &guard; // leaves when the stream is dropped
             GameStateResult::from_db_game(&db_game)
+        }))
+    }
+    async fn chat(&self, game_token: GameToken) -> impl Stream<Item = ChatMessageResult> {
+        SimpleBroker::<DbGameMessage>::subscribe_where(move |m: &DbGameMessage| m.game_id == game_token).map(|m: DbGameMessage| {
+            ChatMessageResult::from_db_game_message(&m)
         })
     }
-}
\ No newline at end of file
+    // the open games as of subscribing are what the changes are relative to, i.e. fetch openGames right after
+    async fn lobby(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = LobbyChangeResult>, GameError> {
+        let updates = SimpleBroker::<DbGame>::subscribe_coalesced();
+        let mut open = OpenGames::new(store(ctx).fetch_open_game_ids().await?);
+        Ok(updates.filter_map(move |db_game: DbGame| {
+            let change = open.update(&db_game)?;
+            Some(LobbyChangeResult { change, game: GameStateResult::from_db_game(&db_game) })
+        }))
+    }
+    // emits once, when the ticket holder has got an opponent
+    async fn match_found(&self, ticket: TicketToken) -> impl Stream<Item = MatchFoundResult> {
+        let for_ticket = ticket.clone();
+        let published = SimpleBroker::<MatchFound>::subscribe_where(move |m: &MatchFound| m.ticket == for_ticket);
+        let already_found = take_found(&ticket);
+        // a client gone without leaveQueue isn't waiting anymore
+        let guard = watch(&ticket);
+        tokio_stream::iter(already_found).chain(published).take(1).map(move |m: MatchFound| {
+            let _ = &guard;
+            take_found(&m.ticket);
+            MatchFoundResult::from_match_found(&m)
+        })
+    }
+}
+#[cfg(test)]
+mod tests;
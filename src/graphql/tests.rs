@@ -0,0 +1,377 @@
+// the whole api against the stores that need nothing running: memory and sqlite.
+// TEST_DATABASE_URL=postgres://... adds postgres to the list
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use async_graphql::{Request, Schema, Value as GqlValue};
+use futures_util::{Stream, StreamExt};
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::adversary::try_bot;
+use crate::db::{DbConfig, GameToken};
+use crate::graphql::{GraphQlSchema, MutationRoot, QueryRoot, SubscriptionRoot};
+use crate::memory_store::MemoryStore;
+use crate::store::{open_store, Store};
+
+struct Api {
+    name: String,
+    store: Store,
+    schema: GraphQlSchema,
+}
+
+fn config(url: &str) -> DbConfig {
+    DbConfig {
+        url: url.into(),
+        pool_size: 2,
+        connection_timeout: Duration::from_secs(5),
+        statement_timeout: None,
+    }
+}
+
+fn apis() -> Vec<Api> {
+    let mut stores: Vec<(String, Store)> = vec![
+        ("memory".into(), Arc::new(MemoryStore::default())),
+        ("sqlite".into(), open_store(&config("sqlite::memory:")).unwrap()),
+    ];
+    if let Ok(url) = env::var("TEST_DATABASE_URL") {
+        stores.push(("postgres".into(), open_store(&config(&url)).unwrap()));
+    }
+    stores.into_iter().map(|(name, store)| Api {
+        name,
+        schema: Schema::build(QueryRoot, MutationRoot, SubscriptionRoot).data(store.clone()).finish(),
+        store,
+    }).collect()
+}
+
+impl Api {
+    // the data, or the code of the first error; the messages are for humans
+    async fn run(&self, query: &str) -> Result<Value, String> {
+        let response = self.schema.execute(query).await;
+        match response.errors.first() {
+            Some(e) => Err(match e.extensions.as_ref().and_then(|x| x.get("code")) {
+                Some(GqlValue::String(code)) => code.clone(),
+                _ => panic!("{}: no code in {:?}", self.name, e),
+            }),
+            None => Ok(response.data.into_json().unwrap()),
+        }
+    }
+
+    async fn ok(&self, query: &str) -> Value {
+        self.run(query).await.unwrap_or_else(|e| panic!("{}: {} failed with {}", self.name, query, e))
+    }
+
+    async fn err(&self, query: &str) -> String {
+        match self.run(query).await {
+            Ok(data) => panic!("{}: {} should have failed, got {}", self.name, query, data),
+            Err(e) => e,
+        }
+    }
+
+    async fn init_game(&self, args: &str) -> String {
+        let data = self.ok(&format!("mutation {{ initGame{} {{ id }} }}", args)).await;
+        data["initGame"]["id"].as_str().unwrap().to_string()
+    }
+
+    async fn claim(&self, game: &str, player: &str) -> Result<String, String> {
+        let data = self.run(&format!("mutation {{ claimPlayer(gameToken: \"{}\", player: {}) {{ playerToken }} }}", game, player)).await?;
+        Ok(data["claimPlayer"]["playerToken"].as_str().unwrap().to_string())
+    }
+
+    async fn turn(&self, player_token: &str, side: &str, height: u8) -> Result<Value, String> {
+        let data = self.run(&format!(
+            "mutation {{ turn(playerToken: \"{}\", turn: {{side: {}, height: {}}}) {{ nextPlayer winner isStalemate outcome history {{ player }} }} }}",
+            player_token, side, height,
+        )).await?;
+        Ok(data["turn"].clone())
+    }
+
+    async fn game(&self, game: &str) -> Value {
+        self.ok(&format!("{{ game(gameToken: \"{}\") {{ nextPlayer winner outcome history {{ player }} }} }}", game)).await["game"].clone()
+    }
+
+    fn subscribe(&self, query: &str) -> impl Stream<Item = Value> + Unpin {
+        self.schema.execute_stream(Request::new(query)).map(|r| {
+            assert!(r.errors.is_empty(), "{:?}", r.errors);
+            r.data.into_json().unwrap()
+        })
+    }
+}
+
+async fn next<S: Stream<Item = Value> + Unpin>(stream: &mut S) -> Value {
+    tokio::time::timeout(Duration::from_secs(5), stream.next()).await.expect("no update in time").expect("stream ended")
+}
+
+fn game_token(game: &str) -> GameToken {
+    GameToken(Uuid::parse_str(game).unwrap())
+}
+
+// the next lobby change of the game, the other tests' games are in there too
+async fn next_lobby_change<S: Stream<Item = Value> + Unpin>(stream: &mut S, game: &str) -> Value {
+    loop {
+        let change = next(stream).await;
+        if change["lobby"]["game"]["id"] == json!(game) {
+            return change["lobby"]["change"].clone();
+        }
+    }
+}
+
+const SMALL: &str = "(config: {width: 3, height: 3, winLen: 2})";
+
+#[tokio::test]
+async fn win() {
+    for api in apis() {
+        let game = api.init_game(SMALL).await;
+        let red = api.claim(&game, "RED").await.unwrap();
+        let blue = api.claim(&game, "BLUE").await.unwrap();
+        let turn = api.turn(&red, "LEFT", 0).await.unwrap();
+        assert_eq!(json!("BLUE"), turn["nextPlayer"], "{}", api.name);
+        api.turn(&blue, "LEFT", 1).await.unwrap();
+        let turn = api.turn(&red, "LEFT", 0).await.unwrap();
+        assert_eq!(json!("RED"), turn["winner"], "{}", api.name);
+        assert_eq!(json!("WIN"), turn["outcome"], "{}", api.name);
+        assert_eq!(json!(null), turn["nextPlayer"], "{}", api.name);
+        assert_eq!("GAME_OVER", api.turn(&blue, "LEFT", 2).await.unwrap_err(), "{}", api.name);
+        assert_eq!(3, api.game(&game).await["history"].as_array().unwrap().len(), "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn stalemate() {
+    for api in apis() {
+        let game = api.init_game("(config: {width: 2, height: 1, winLen: 2})").await;
+        let red = api.claim(&game, "RED").await.unwrap();
+        let blue = api.claim(&game, "BLUE").await.unwrap();
+        api.turn(&red, "LEFT", 0).await.unwrap();
+        let turn = api.turn(&blue, "RIGHT", 0).await.unwrap();
+        assert_eq!(json!(true), turn["isStalemate"], "{}", api.name);
+        assert_eq!(json!("DRAW"), turn["outcome"], "{}", api.name);
+        assert_eq!(json!(null), turn["winner"], "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn claim_and_turn_errors() {
+    for api in apis() {
+        let game = api.init_game(SMALL).await;
+        let red = api.claim(&game, "RED").await.unwrap();
+        // can_player_join is checked first
+        assert_eq!("GAME_FULL", api.claim(&game, "RED").await.unwrap_err(), "{}", api.name);
+        assert_eq!("NOT_FOUND", api.claim(&Uuid::new_v4().to_string(), "RED").await.unwrap_err(), "{}", api.name);
+        let blue = api.claim(&game, "BLUE").await.unwrap();
+        assert_eq!("WRONG_PLAYER", api.turn(&blue, "LEFT", 0).await.unwrap_err(), "{}", api.name);
+        assert_eq!("NOT_FOUND", api.turn(&Uuid::new_v4().to_string(), "LEFT", 0).await.unwrap_err(), "{}", api.name);
+        api.turn(&red, "LEFT", 0).await.unwrap();
+        assert_eq!("OUT_OF_BOUNDS", api.turn(&blue, "LEFT", 3).await.unwrap_err(), "{}", api.name);
+        // red, blue, red is not a win
+        api.turn(&blue, "LEFT", 0).await.unwrap();
+        api.turn(&red, "LEFT", 0).await.unwrap();
+        assert_eq!("ROW_FULL", api.turn(&blue, "RIGHT", 0).await.unwrap_err(), "{}", api.name);
+        let bot_game = api.init_game("(botId: RANDY)").await;
+        api.claim(&bot_game, "BLUE").await.unwrap();
+        assert_eq!("GAME_FULL", api.claim(&bot_game, "RED").await.unwrap_err(), "{}", api.name);
+        assert_eq!("INVALID_INPUT", api.err("mutation { initGame(config: {width: 11}) { id } }").await, "{}", api.name);
+        let stale = api.err(&format!("mutation {{ turn(playerToken: \"{}\", turn: {{side: LEFT, height: 1}}, expectedVersion: 0) {{ id }} }}", blue)).await;
+        assert_eq!("VERSION_CONFLICT", stale, "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn bot_replies() {
+    for api in apis() {
+        let game = api.init_game("(botId: RANDY)").await;
+        let red = api.claim(&game, "RED").await.unwrap();
+        api.turn(&red, "LEFT", 0).await.unwrap();
+        // the bots listen to the broker in the app, here it's called right away
+        try_bot(&api.store, &api.store.fetch_game_state(&game_token(&game)).await.unwrap()).await;
+        let after = api.game(&game).await;
+        assert_eq!(json!([{"player": "RED"}, {"player": "BLUE"}]), after["history"], "{}", api.name);
+        assert_eq!(json!("RED"), after["nextPlayer"], "{}", api.name);
+        // nothing to answer now
+        try_bot(&api.store, &api.store.fetch_game_state(&game_token(&game)).await.unwrap()).await;
+        assert_eq!(2, api.game(&game).await["history"].as_array().unwrap().len(), "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn game_locks() {
+    for api in apis() {
+        let mut games = vec![];
+        for _ in 0..3 {
+            games.push(game_token(&api.init_game(SMALL).await));
+        }
+        // more than the pool has connections, the bots thinking mustn't starve everyone else
+        let mut locks = vec![];
+        for game in games.iter() {
+            locks.push(api.store.try_lock_game(game).await.unwrap().unwrap());
+        }
+        assert!(api.store.try_lock_game(&games[0]).await.unwrap().is_none(), "{}", api.name);
+        api.game(&api.init_game(SMALL).await).await;
+        drop(locks);
+        // released in the background with postgres
+        let mut relocked = None;
+        for _ in 0..50 {
+            relocked = api.store.try_lock_game(&games[0]).await.unwrap();
+            if relocked.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(relocked.is_some(), "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn game_subscription() {
+    for api in apis() {
+        let game = api.init_game(SMALL).await;
+        let mut updates = api.subscribe(&format!("subscription {{ game(gameToken: \"{}\") {{ redClaimed spectatorCount history {{ player }} }} }}", game));
+        // arriving is an update on its own
+        let first = next(&mut updates).await;
+        assert_eq!(json!(1), first["game"]["spectatorCount"], "{}", api.name);
+        assert_eq!(json!(false), first["game"]["redClaimed"], "{}", api.name);
+        let red = api.claim(&game, "RED").await.unwrap();
+        assert_eq!(json!(true), next(&mut updates).await["game"]["redClaimed"], "{}", api.name);
+        api.claim(&game, "BLUE").await.unwrap();
+        next(&mut updates).await;
+        api.turn(&red, "LEFT", 0).await.unwrap();
+        assert_eq!(json!([{"player": "RED"}]), next(&mut updates).await["game"]["history"], "{}", api.name);
+        // somebody else arriving is shown on the game as it was last seen
+        let mut other = api.subscribe(&format!("subscription {{ game(gameToken: \"{}\") {{ spectatorCount }} }}", game));
+        assert_eq!(json!(2), next(&mut other).await["game"]["spectatorCount"], "{}", api.name);
+        let arrived = next(&mut updates).await;
+        assert_eq!(json!(2), arrived["game"]["spectatorCount"], "{}", api.name);
+        assert_eq!(json!([{"player": "RED"}]), arrived["game"]["history"], "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn chat() {
+    for api in apis() {
+        let game = api.init_game(SMALL).await;
+        let red = api.claim(&game, "RED").await.unwrap();
+        let mut messages = api.subscribe(&format!("subscription {{ chat(gameToken: \"{}\") {{ player text }} }}", game));
+        // the subscription is set up on the first poll
+        assert!(tokio::time::timeout(Duration::from_millis(50), messages.next()).await.is_err());
+        api.ok(&format!("mutation {{ sendChat(playerToken: \"{}\", text: \" gl hf \") {{ text }} }}", red)).await;
+        assert_eq!(json!({"chat": {"player": "RED", "text": "gl hf"}}), next(&mut messages).await, "{}", api.name);
+        let history = api.ok(&format!("{{ chat(gameToken: \"{}\") {{ text }} }}", game)).await;
+        assert_eq!(json!({"chat": [{"text": "gl hf"}]}), history, "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn accounts() {
+    for api in apis() {
+        // every store is fresh, but postgres keeps the users from the last run
+        let name = format!("u{}", &Uuid::new_v4().to_simple().to_string()[..12]);
+        let registered = api.ok(&format!("mutation {{ register(username: \"{}\", password: \"hunter2hunter2\") {{ authToken user {{ username }} }} }}", name)).await;
+        assert_eq!(json!(name), registered["register"]["user"]["username"], "{}", api.name);
+        assert_eq!("USERNAME_TAKEN", api.err(&format!("mutation {{ register(username: \"{}\", password: \"hunter2hunter2\") {{ authToken }} }}", name.to_uppercase())).await, "{}", api.name);
+        assert_eq!("WRONG_CREDENTIALS", api.err(&format!("mutation {{ login(username: \"{}\", password: \"hunter3hunter3\") {{ authToken }} }}", name)).await, "{}", api.name);
+        assert_eq!("WRONG_CREDENTIALS", api.err("mutation { login(username: \"nobody_at_all\", password: \"hunter2hunter2\") { authToken } }").await, "{}", api.name);
+        let login = api.ok(&format!("mutation {{ login(username: \"{}\", password: \"hunter2hunter2\") {{ authToken }} }}", name.to_uppercase())).await;
+        let auth = login["login"]["authToken"].as_str().unwrap().to_string();
+
+        let game = api.init_game(SMALL).await;
+        let data = api.ok(&format!("mutation {{ claimPlayer(gameToken: \"{}\", player: BLUE, authToken: \"{}\") {{ playerToken }} }}", game, auth)).await;
+        let blue = data["claimPlayer"]["playerToken"].clone();
+        api.claim(&game, "RED").await.unwrap();
+        let bot_game = api.init_game("(botId: RANDY)").await;
+        api.ok(&format!("mutation {{ claimPlayer(gameToken: \"{}\", player: RED, authToken: \"{}\") {{ playerToken }} }}", bot_game, auth)).await;
+
+        let my_games = |filter: &str| format!("{{ myGames(authToken: \"{}\"{}) {{ player playerToken game {{ id }} }} }}", auth, filter);
+        let all = api.ok(&my_games("")).await;
+        assert_eq!(2, all["myGames"].as_array().unwrap().len(), "{}", api.name);
+        let humans = api.ok(&my_games(", filter: {vsBot: false, finished: false}")).await;
+        assert_eq!(json!([{"player": "BLUE", "playerToken": blue, "game": {"id": game}}]), humans["myGames"], "{}", api.name);
+        assert_eq!(json!([]), api.ok(&my_games(", filter: {finished: true}")).await["myGames"], "{}", api.name);
+        assert_eq!("UNAUTHENTICATED", api.err("{ myGames(authToken: \"nope\") { player } }").await, "{}", api.name);
+        assert_eq!(json!(name), api.ok(&format!("{{ account(authToken: \"{}\") {{ username }} }}", auth)).await["account"]["username"], "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn ratings() {
+    for api in apis() {
+        let name = format!("u{}", &Uuid::new_v4().to_simple().to_string()[..12]);
+        let registered = api.ok(&format!("mutation {{ register(username: \"{}\", password: \"hunter2hunter2\") {{ authToken user {{ rating ratedGames }} }} }}", name)).await;
+        assert_eq!(json!({"rating": 1200, "ratedGames": 0}), registered["register"]["user"], "{}", api.name);
+        let auth = registered["register"]["authToken"].as_str().unwrap().to_string();
+
+        let bot_game = api.init_game("(botId: RANDY)").await;
+        let data = api.ok(&format!("mutation {{ claimPlayer(gameToken: \"{}\", player: RED, authToken: \"{}\") {{ playerToken }} }}", bot_game, auth)).await;
+        let red = data["claimPlayer"]["playerToken"].as_str().unwrap().to_string();
+        api.ok(&format!("mutation {{ resign(playerToken: \"{}\") {{ winner }} }}", red)).await;
+        // anonymous games don't count
+        let game = api.init_game(SMALL).await;
+        let anonymous = api.claim(&game, "RED").await.unwrap();
+        api.claim(&game, "BLUE").await.unwrap();
+        api.ok(&format!("mutation {{ resign(playerToken: \"{}\") {{ winner }} }}", anonymous)).await;
+
+        let account = api.ok(&format!("{{ account(authToken: \"{}\") {{ rating ratedGames }} }}", auth)).await;
+        assert_eq!(json!(1), account["account"]["ratedGames"], "{}", api.name);
+        assert!(account["account"]["rating"].as_i64().unwrap() < 1200, "{}", api.name);
+        let history = api.ok(&format!("{{ ratingHistory(username: \"{}\", opponent: \"RANDY\") {{ gameId opponent ratingBefore ratingAfter }} }}", name)).await;
+        let history = history["ratingHistory"].as_array().unwrap();
+        assert_eq!(1, history.len(), "{}", api.name);
+        assert_eq!(json!(bot_game), history[0]["gameId"], "{}", api.name);
+        assert_eq!(json!("RANDY"), history[0]["opponent"], "{}", api.name);
+        assert_eq!(json!(1200), history[0]["ratingBefore"], "{}", api.name);
+        assert_eq!(account["account"]["rating"], history[0]["ratingAfter"], "{}", api.name);
+        assert_eq!(json!([]), api.ok(&format!("{{ ratingHistory(username: \"{}\", opponent: \"SMART\") {{ gameId }} }}", name)).await["ratingHistory"], "{}", api.name);
+        // by the botId too, the username is "EASY bot"
+        assert_eq!(json!([]), api.ok(&format!("{{ ratingHistory(username: \"{}\", opponent: \"EASY\") {{ gameId }} }}", name)).await["ratingHistory"], "{}", api.name);
+        assert_eq!(json!([]), api.ok(&format!("{{ ratingHistory(username: \"{}\", opponent: \"EASY bot\") {{ gameId }} }}", name)).await["ratingHistory"], "{}", api.name);
+        assert_eq!("NOT_FOUND", api.err("{ ratingHistory(username: \"NOBODY\") { gameId } }").await, "{}", api.name);
+
+        let leaderboard = api.ok("{ leaderboard(limit: 200) { username ratedGames } }").await;
+        let leaderboard = leaderboard["leaderboard"].as_array().unwrap();
+        assert!(leaderboard.iter().any(|u| u["username"] == json!("RANDY")), "{}", api.name);
+        assert!(leaderboard.iter().all(|u| u["ratedGames"].as_i64().unwrap() > 0), "{}", api.name);
+        assert_eq!("INVALID_INPUT", api.err("{ leaderboard(limit: 0) { username } }").await, "{}", api.name);
+    }
+}
+
+#[tokio::test]
+async fn lobby() {
+    for api in apis() {
+        let ids = |data: &Value, list: &str| data[list].as_array().unwrap().iter().map(|g| g["id"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+        let lists = || api.ok("{ openGames(limit: 200) { id } ongoingGames(limit: 200) { id } finishedGames(limit: 200) { id } }");
+        let mut lobby = api.subscribe("subscription { lobby { change game { id } } }");
+        let game = api.init_game(SMALL).await;
+        assert!(!ids(&lists().await, "openGames").contains(&game), "{}", api.name);
+        // the subscription only gets going once it's polled
+        let (opened, red) = tokio::join!(next_lobby_change(&mut lobby, &game), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            api.claim(&game, "RED").await.unwrap()
+        });
+        assert_eq!(json!("OPENED"), opened, "{}", api.name);
+        let data = lists().await;
+        assert!(ids(&data, "openGames").contains(&game), "{}", api.name);
+        assert!(!ids(&data, "ongoingGames").contains(&game), "{}", api.name);
+        // beyond the first page too, for the next lobby subscriber
+        assert!(api.store.fetch_open_game_ids().await.unwrap().contains(&game_token(&game)), "{}", api.name);
+
+        api.claim(&game, "BLUE").await.unwrap();
+        assert_eq!(json!("FILLED"), next_lobby_change(&mut lobby, &game).await, "{}", api.name);
+        let data = lists().await;
+        assert!(!ids(&data, "openGames").contains(&game), "{}", api.name);
+        assert!(ids(&data, "ongoingGames").contains(&game), "{}", api.name);
+        assert!(!api.store.fetch_open_game_ids().await.unwrap().contains(&game_token(&game)), "{}", api.name);
+        // a bot is as good as a second player
+        let bot_game = api.init_game("(botId: RANDY)").await;
+        api.claim(&bot_game, "RED").await.unwrap();
+        let data = lists().await;
+        assert!(!ids(&data, "openGames").contains(&bot_game), "{}", api.name);
+        assert!(ids(&data, "ongoingGames").contains(&bot_game), "{}", api.name);
+
+        api.ok(&format!("mutation {{ resign(playerToken: \"{}\") {{ winner }} }}", red)).await;
+        let data = lists().await;
+        assert!(!ids(&data, "ongoingGames").contains(&game), "{}", api.name);
+        assert!(ids(&data, "finishedGames").contains(&game), "{}", api.name);
+        let finished = api.ok(&format!("{{ game(gameToken: \"{}\") {{ createdAt updatedAt }} }}", game)).await;
+        assert!(finished["game"]["createdAt"].as_str() < finished["game"]["updatedAt"].as_str(), "{}", api.name);
+        assert_eq!(1, api.ok("{ finishedGames(limit: 1) { id } }").await["finishedGames"].as_array().unwrap().len(), "{}", api.name);
+    }
+}
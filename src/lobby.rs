@@ -0,0 +1,113 @@
+// what the lobby page shows: the open games to join, the ongoing ones to watch and the recently finished ones.
+// a game nobody has claimed a seat in yet isn't anywhere, somebody has just created it and is about to
+
+use std::collections::HashSet;
+use crate::db::GameToken;
+use crate::db_schema::DbGame;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, async_graphql::Enum)]
+pub enum LobbyList {
+    Open, // one human waits for another
+    Ongoing,
+    Finished,
+}
+
+impl LobbyList {
+    // keep in sync with the queries in the stores
+    pub fn of(game: &DbGame) -> Option<LobbyList> {
+        let players = game.player_red.is_some() as u8 + game.player_blue.is_some() as u8;
+        if players == 0 {
+            return None;
+        }
+        if game.outcome.is_some() {
+            return Some(LobbyList::Finished);
+        }
+        if players == 1 && game.bot_id.is_none() {
+            return Some(LobbyList::Open);
+        }
+        Some(LobbyList::Ongoing)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, async_graphql::Enum)]
+pub enum LobbyChange {
+    Opened,
+    Filled,
+    Closed, // finished before anybody joined
+}
+
+// the open games as one lobby subscriber knows them, to tell a game that has just filled from a move in a game
+// that was full already
+#[derive(Default)]
+pub struct OpenGames(HashSet<GameToken>);
+
+impl OpenGames {
+    pub fn new(open: impl IntoIterator<Item = GameToken>) -> OpenGames {
+        OpenGames(open.into_iter().collect())
+    }
+
+    pub fn update(&mut self, game: &DbGame) -> Option<LobbyChange> {
+        let list = LobbyList::of(game);
+        let was_open = self.0.contains(&game.id);
+        match (was_open, list) {
+            (false, Some(LobbyList::Open)) => {
+                self.0.insert(game.id.clone());
+                Some(LobbyChange::Opened)
+            }
+            (true, Some(LobbyList::Open)) => None,
+            (true, list) => {
+                self.0.remove(&game.id);
+                Some(if list == Some(LobbyList::Ongoing) { LobbyChange::Filled } else { LobbyChange::Closed })
+            }
+            (false, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::adversary::BotId;
+    use crate::db::PlayerToken;
+    use crate::db_schema::{DbGame, GameDimensions, GameOutcome};
+    use crate::game::Player;
+    use crate::lobby::{LobbyChange, LobbyList, OpenGames};
+
+    #[test]
+    fn lists() {
+        let mut game = DbGame::new(GameDimensions::default());
+        assert_eq!(None, LobbyList::of(&game));
+        game.player_blue = Some(PlayerToken(Uuid::new_v4()));
+        assert_eq!(Some(LobbyList::Open), LobbyList::of(&game));
+        game.player_red = Some(PlayerToken(Uuid::new_v4()));
+        assert_eq!(Some(LobbyList::Ongoing), LobbyList::of(&game));
+        game.set_outcome(GameOutcome::RESIGN, Some(Player::Red), "".into());
+        assert_eq!(Some(LobbyList::Finished), LobbyList::of(&game));
+
+        let mut bot_game = DbGame::new(GameDimensions::default());
+        bot_game.bot_id = Some(BotId::RANDY);
+        assert_eq!(None, LobbyList::of(&bot_game));
+        bot_game.player_red = Some(PlayerToken(Uuid::new_v4()));
+        assert_eq!(Some(LobbyList::Ongoing), LobbyList::of(&bot_game));
+    }
+
+    #[test]
+    fn changes() {
+        let mut game = DbGame::new(GameDimensions::default());
+        let mut open = OpenGames::default();
+        assert_eq!(None, open.update(&game));
+        game.player_red = Some(PlayerToken(Uuid::new_v4()));
+        assert_eq!(Some(LobbyChange::Opened), open.update(&game));
+        assert_eq!(None, open.update(&game));
+        game.player_blue = Some(PlayerToken(Uuid::new_v4()));
+        assert_eq!(Some(LobbyChange::Filled), open.update(&game));
+        // just a move
+        assert_eq!(None, open.update(&game));
+
+        let mut lonely = DbGame::new(GameDimensions::default());
+        lonely.player_blue = Some(PlayerToken(Uuid::new_v4()));
+        let mut open = OpenGames::new([lonely.id.clone()]);
+        lonely.set_outcome(GameOutcome::RESIGN, Some(Player::Red), "".into());
+        assert_eq!(Some(LobbyChange::Closed), open.update(&lonely));
+    }
+}
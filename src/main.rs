@@ -12,19 +12,37 @@ extern crate diesel_derive_enum;
 extern crate dotenv;
 use dotenv::dotenv;
 mod game;
+mod bitboard;
 mod graphql;
 mod db;
 mod db_schema;
 mod broker;
 mod adversary;
 mod adversary_minimax;
+mod transposition;
 mod db_schema_macro;
+mod clock;
+mod matchmaking;
+mod presence;
+mod chat;
+mod game_broker;
+mod store;
+mod memory_store;
+mod sqlite_store;
+mod error;
+mod accounts;
+mod ratings;
+mod lobby;
+mod cleanup;
 
 
 
 use std::env;
 
 use crate::adversary::run_subscribe_bots;
+use crate::clock::run_clock_watcher;
+use crate::cleanup::run_cleanup;
+use crate::game_broker::{init_game_broker, run_game_broker};
 
 use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
@@ -38,7 +56,8 @@ use axum::{
     Router,
 };
 use tower_http::cors::{Any, CorsLayer};
-use crate::db::run_embed_migrations;
+use crate::db::DbConfig;
+use crate::store::open_store;
 use crate::graphql::{GraphQlSchema, MutationRoot, QueryRoot, SubscriptionRoot};
 
 //async fn graphql_handler(schema: Extension<OrderBookSchema>, req: GraphQLRequest) -> GraphQLResponse {
@@ -53,10 +72,13 @@ async fn graphql_playground() -> impl IntoResponse {
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    run_embed_migrations();
+    let db_config = DbConfig::from_env();
+    let store = open_store(&db_config).expect("failed to open the store");
+    init_game_broker();
     let port = env::var("PORT").unwrap_or("3000".to_string());
 
     let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(store.clone())
         .finish();
 
     let app = Router::new()
@@ -69,7 +91,7 @@ async fn main() {
                    .allow_headers(Any),
         );
 
-    tokio::join!(run_subscribe_bots(), axum::Server::bind(&format!("0.0.0.0:{}", &port).parse().unwrap())
+    tokio::join!(run_subscribe_bots(store.clone()), run_clock_watcher(store.clone()), run_cleanup(store.clone()), run_game_broker(store, db_config), axum::Server::bind(&format!("0.0.0.0:{}", &port).parse().unwrap())
         .serve(app.into_make_service()));
 
 }
\ No newline at end of file
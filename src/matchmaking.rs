@@ -0,0 +1,195 @@
+// "play now": players wait in a queue until someone with the same game settings shows up.
+// the queue lives in memory, so it's per instance
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use async_graphql::NewType;
+use once_cell::sync::Lazy;
+use rand::random;
+use uuid::Uuid;
+use crate::broker::SimpleBroker;
+use crate::db::PlayerToken;
+use crate::error::GameError;
+use crate::store::Store;
+use crate::db_schema::{DbGame, GameDimensions};
+use crate::game::Player;
+
+// a match nobody came for is forgotten after a while
+const FOUND_TTL: Duration = Duration::from_secs(10 * 60);
+// and so is a ticket nobody has subscribed to matchFound with; a subscribed one leaves with the subscription
+const WAITING_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, NewType, PartialEq, Eq, Hash)]
+pub struct TicketToken(pub Uuid);
+
+#[derive(Clone)]
+pub struct MatchFound {
+    pub ticket: TicketToken,
+    pub game: DbGame,
+    pub player: Player,
+    pub player_token: PlayerToken,
+}
+
+struct Waiting {
+    ticket: TicketToken,
+    dimensions: GameDimensions,
+    since: Instant,
+    watchers: usize, // matchFound subscriptions
+    // a game is being made for it; it stays in the queue meanwhile, so that the watchers are still counted
+    matching: bool,
+}
+
+impl Waiting {
+    fn new(ticket: TicketToken, dimensions: GameDimensions) -> Waiting {
+        Waiting { ticket, dimensions, since: Instant::now(), watchers: 0, matching: false }
+    }
+
+    fn is_gone(&self) -> bool {
+        self.watchers == 0 && self.since.elapsed() >= WAITING_TTL
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    waiting: Vec<Waiting>,
+    found: HashMap<TicketToken, (Instant, MatchFound)>,
+}
+
+static QUEUE: Lazy<Mutex<Queue>> = Lazy::new(Default::default);
+
+pub async fn join_queue(store: &Store, dimensions: GameDimensions) -> Result<TicketToken, GameError> {
+    let ticket = TicketToken(Uuid::new_v4());
+    let opponent = {
+        let mut queue = QUEUE.lock().unwrap();
+        queue.found.retain(|_, (at, _)| at.elapsed() < FOUND_TTL);
+        queue.waiting.retain(|w| !w.is_gone());
+        match queue.waiting.iter_mut().find(|w| !w.matching && w.dimensions == dimensions) {
+            Some(w) => {
+                w.matching = true;
+                w.ticket.clone()
+            }
+            None => {
+                queue.waiting.push(Waiting::new(ticket.clone(), dimensions));
+                return Ok(ticket);
+            }
+        }
+    };
+    let result = start_game(store, &opponent, &ticket, dimensions).await;
+    let mut queue = QUEUE.lock().unwrap();
+    // unless it's gone meanwhile
+    let waiting = queue.waiting.iter().position(|w| w.ticket == opponent);
+    match result {
+        Ok(matches) => {
+            if let Some(i) = waiting {
+                queue.waiting.remove(i);
+            }
+            for m in matches.iter() {
+                queue.found.insert(m.ticket.clone(), (Instant::now(), m.clone()));
+            }
+            drop(queue);
+            for m in matches {
+                SimpleBroker::publish(m);
+            }
+            Ok(ticket)
+        }
+        Err(e) => {
+            // not the opponent's fault, they keep their place
+            if let Some(i) = waiting {
+                queue.waiting[i].matching = false;
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn start_game(store: &Store, a: &TicketToken, b: &TicketToken, dimensions: GameDimensions) -> Result<Vec<MatchFound>, GameError> {
+    let game = store.init_game_state(None, dimensions, None).await?;
+    let (red, blue) = if random() { (a, b) } else { (b, a) };
+    let (red_token, _) = store.claim_game_player(&game.id, Player::Red, None).await?;
+    let (blue_token, game) = store.claim_game_player(&game.id, Player::Blue, None).await?;
+    Ok(vec![
+        MatchFound { ticket: red.clone(), game: game.clone(), player: Player::Red, player_token: PlayerToken(red_token) },
+        MatchFound { ticket: blue.clone(), game, player: Player::Blue, player_token: PlayerToken(blue_token) },
+    ])
+}
+
+pub fn leave_queue(ticket: &TicketToken) -> bool {
+    let mut queue = QUEUE.lock().unwrap();
+    let len = queue.waiting.len();
+    queue.waiting.retain(|w| w.ticket != *ticket);
+    len != queue.waiting.len()
+}
+
+// a matchFound subscription holds the ticket's place in the queue; the last one to go takes it out of the queue
+pub struct WaitingGuard(TicketToken);
+
+pub fn watch(ticket: &TicketToken) -> WaitingGuard {
+    if let Some(w) = QUEUE.lock().unwrap().waiting.iter_mut().find(|w| w.ticket == *ticket) {
+        w.watchers += 1;
+    }
+    WaitingGuard(ticket.clone())
+}
+
+impl Drop for WaitingGuard {
+    fn drop(&mut self) {
+        let mut queue = QUEUE.lock().unwrap();
+        if let Some(i) = queue.waiting.iter().position(|w| w.ticket == self.0) {
+            let waiting = &mut queue.waiting[i];
+            waiting.watchers = waiting.watchers.saturating_sub(1);
+            if waiting.watchers == 0 {
+                queue.waiting.remove(i);
+            }
+        }
+    }
+}
+
+// the match could be made before the ticket holder subscribed
+pub fn take_found(ticket: &TicketToken) -> Option<MatchFound> {
+    QUEUE.lock().unwrap().found.remove(ticket).map(|(_, m)| m)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use crate::db_schema::GameDimensions;
+    use crate::matchmaking::{join_queue, leave_queue, take_found, watch, QUEUE, WAITING_TTL};
+    use crate::memory_store::MemoryStore;
+    use crate::store::Store;
+
+    #[tokio::test]
+    async fn ghosts() {
+        let store: Store = Arc::new(MemoryStore::default());
+        // nobody else plays on this one
+        let dimensions = GameDimensions { width: 5, height: 7, win_len: 3 };
+
+        // left with the subscription
+        let gone = join_queue(&store, dimensions).await.unwrap();
+        drop(watch(&gone));
+        assert!(!leave_queue(&gone));
+
+        // never subscribed
+        let idle = join_queue(&store, dimensions).await.unwrap();
+        QUEUE.lock().unwrap().waiting.iter_mut().find(|w| w.ticket == idle).unwrap().since = Instant::now() - WAITING_TTL - Duration::from_secs(1);
+        let waiting = join_queue(&store, dimensions).await.unwrap();
+        assert!(take_found(&idle).is_none());
+        assert!(take_found(&waiting).is_none());
+
+        // still there while subscribed, however long it takes
+        let _guard = watch(&waiting);
+        QUEUE.lock().unwrap().waiting.iter_mut().find(|w| w.ticket == waiting).unwrap().since = Instant::now() - WAITING_TTL - Duration::from_secs(1);
+        let other = join_queue(&store, dimensions).await.unwrap();
+        assert_eq!(take_found(&waiting).unwrap().game.id, take_found(&other).unwrap().game.id);
+
+        // a game is being made for it: not matched again, and the subscription going meanwhile still counts
+        let busy = join_queue(&store, dimensions).await.unwrap();
+        let guard = watch(&busy);
+        QUEUE.lock().unwrap().waiting.iter_mut().find(|w| w.ticket == busy).unwrap().matching = true;
+        let next = join_queue(&store, dimensions).await.unwrap();
+        assert!(take_found(&next).is_none());
+        drop(guard);
+        assert!(!leave_queue(&busy));
+        assert!(leave_queue(&next));
+    }
+}
@@ -0,0 +1,268 @@
+// games in a hashmap, behaves like the postgres store as far as the api can tell
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use crate::db::{GameToken, PlayerToken, UserId};
+use crate::db_schema::{DbArchivedGame, DbGame, DbGameMessage, DbRatingChange, DbUser};
+use crate::error::GameError;
+use crate::lobby::LobbyList;
+use crate::ratings::{bot_users, needs_rating, rate, rated_players};
+use crate::store::{GameLock, GameStore, UserGamesFilter};
+
+// when several are locked, it's in the order of the fields
+pub struct MemoryStore {
+    games: Mutex<HashMap<GameToken, DbGame>>,
+    archived_games: Mutex<HashMap<GameToken, DbArchivedGame>>,
+    messages: Mutex<Vec<DbGameMessage>>,
+    users: Mutex<HashMap<UserId, DbUser>>,
+    rating_history: Mutex<Vec<DbRatingChange>>,
+    locks: LocalLocks,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            games: Default::default(),
+            archived_games: Default::default(),
+            messages: Default::default(),
+            users: Mutex::new(bot_users().into_iter().map(|u| (u.id.clone(), u)).collect()),
+            rating_history: Default::default(),
+            locks: Default::default(),
+        }
+    }
+}
+
+// game locks for a single instance
+#[derive(Default)]
+pub(crate) struct LocalLocks(Arc<Mutex<HashSet<GameToken>>>);
+
+struct LocalLock {
+    locks: Arc<Mutex<HashSet<GameToken>>>,
+    game: GameToken,
+}
+
+impl Drop for LocalLock {
+    fn drop(&mut self) {
+        self.locks.lock().unwrap().remove(&self.game);
+    }
+}
+
+impl LocalLocks {
+    pub(crate) fn try_lock(&self, game_token: &GameToken) -> Option<GameLock> {
+        if !self.0.lock().unwrap().insert(game_token.clone()) {
+            return None;
+        }
+        Some(GameLock::new(LocalLock { locks: self.0.clone(), game: game_token.clone() }))
+    }
+}
+
+#[async_trait]
+impl GameStore for MemoryStore {
+    async fn insert_game(&self, game: &DbGame) -> Result<DbGame, GameError> {
+        let mut games = self.games.lock().unwrap();
+        if games.contains_key(&game.id) {
+            return Err(GameError::Internal("Game already exists".into()));
+        }
+        games.insert(game.id.clone(), game.clone());
+        Ok(game.clone())
+    }
+
+    async fn fetch_game_state(&self, game_token: &GameToken) -> Result<DbGame, GameError> {
+        self.games.lock().unwrap().get(game_token).cloned().ok_or(GameError::NotFound)
+    }
+
+    async fn fetch_game_by_player(&self, player_token: &PlayerToken) -> Result<DbGame, GameError> {
+        let player_token = Some(player_token.clone());
+        self.games.lock().unwrap().values()
+            .find(|g| g.player_red == player_token || g.player_blue == player_token)
+            .cloned().ok_or(GameError::NotFound)
+    }
+
+    async fn write_game(&self, game: &DbGame) -> Result<DbGame, GameError> {
+        let mut games = self.games.lock().unwrap();
+        let stored = games.get_mut(&game.id).ok_or(GameError::NotFound)?;
+        if stored.version != game.version {
+            return Err(GameError::Conflict { expected: game.version, actual: stored.version });
+        }
+        let mut next = game.clone();
+        next.version += 1;
+        next.updated_at = Utc::now();
+        if needs_rating(&next) {
+            next.rated = true;
+            let mut users = self.users.lock().unwrap();
+            let players = rated_players(&next).and_then(|(red, blue)| Some((users.get(&red)?, users.get(&blue)?)));
+            if let Some((red, blue)) = players {
+                let changes = rate(&next, red, blue, Utc::now());
+                for change in changes.iter() {
+                    let user = users.get_mut(&change.user_id).unwrap();
+                    user.rating = change.rating_after;
+                    user.rated_games += 1;
+                }
+                self.rating_history.lock().unwrap().extend(changes);
+            }
+        }
+        *stored = next;
+        Ok(stored.clone())
+    }
+
+    async fn fetch_running_timed_games(&self) -> Result<Vec<DbGame>, GameError> {
+        Ok(self.games.lock().unwrap().values()
+            .filter(|g| g.outcome.is_none() && g.clock_base_ms.is_some() && g.clock_started_at.is_some())
+            .cloned().collect())
+    }
+
+    async fn try_lock_game(&self, game_token: &GameToken) -> Result<Option<GameLock>, GameError> {
+        Ok(self.locks.try_lock(game_token))
+    }
+
+    async fn insert_message(&self, message: &DbGameMessage) -> Result<DbGameMessage, GameError> {
+        self.messages.lock().unwrap().push(message.clone());
+        Ok(message.clone())
+    }
+
+    async fn fetch_game_messages(&self, game_token: &GameToken) -> Result<Vec<DbGameMessage>, GameError> {
+        let mut messages = self.messages.lock().unwrap().iter()
+            .filter(|m| m.game_id == *game_token)
+            .cloned().collect::<Vec<_>>();
+        messages.sort_by_key(|m| m.created_at);
+        Ok(messages)
+    }
+
+    async fn fetch_game_message(&self, message_id: &Uuid) -> Result<DbGameMessage, GameError> {
+        self.messages.lock().unwrap().iter().find(|m| m.id == *message_id).cloned().ok_or(GameError::NotFound)
+    }
+
+    async fn insert_user(&self, user: &DbUser) -> Result<DbUser, GameError> {
+        let mut users = self.users.lock().unwrap();
+        if users.values().any(|u| u.username.to_lowercase() == user.username.to_lowercase()) {
+            return Err(GameError::UsernameTaken);
+        }
+        users.insert(user.id.clone(), user.clone());
+        Ok(user.clone())
+    }
+
+    async fn fetch_user(&self, user_id: &UserId) -> Result<DbUser, GameError> {
+        self.users.lock().unwrap().get(user_id).cloned().ok_or(GameError::NotFound)
+    }
+
+    async fn fetch_user_by_name(&self, username: &str) -> Result<DbUser, GameError> {
+        self.users.lock().unwrap().values()
+            .find(|u| u.username.to_lowercase() == username.to_lowercase())
+            .cloned().ok_or(GameError::NotFound)
+    }
+
+    async fn fetch_leaderboard(&self, limit: i64, offset: i64) -> Result<Vec<DbUser>, GameError> {
+        let mut rated = self.users.lock().unwrap().values().filter(|u| u.rated_games > 0).cloned().collect::<Vec<_>>();
+        rated.sort_by(|a, b| b.rating.total_cmp(&a.rating).then_with(|| a.username.cmp(&b.username)));
+        Ok(rated.into_iter().skip(offset as usize).take(limit as usize).collect())
+    }
+
+    async fn fetch_rating_history(&self, user_id: &UserId, opponent_id: Option<UserId>) -> Result<Vec<DbRatingChange>, GameError> {
+        let mut history = self.rating_history.lock().unwrap().iter()
+            .filter(|c| c.user_id == *user_id && opponent_id.as_ref().is_none_or(|o| c.opponent_id == *o))
+            .cloned().collect::<Vec<_>>();
+        history.sort_by_key(|c| c.created_at);
+        Ok(history)
+    }
+
+    async fn fetch_user_games(&self, user_id: &UserId, filter: &UserGamesFilter) -> Result<Vec<DbGame>, GameError> {
+        let mut games = self.games.lock().unwrap().values()
+            .filter(|g| g.user_seat(user_id).is_some() && filter.matches(g))
+            .cloned().collect::<Vec<_>>();
+        games.sort_by_key(|g| Reverse(g.updated_at));
+        Ok(games)
+    }
+
+    async fn fetch_lobby_games(&self, list: LobbyList, limit: i64, offset: i64) -> Result<Vec<DbGame>, GameError> {
+        let mut games = self.games.lock().unwrap().values()
+            .filter(|g| LobbyList::of(g) == Some(list))
+            .cloned().collect::<Vec<_>>();
+        games.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| a.id.0.cmp(&b.id.0)));
+        Ok(games.into_iter().skip(offset as usize).take(limit as usize).collect())
+    }
+
+    async fn fetch_open_game_ids(&self) -> Result<Vec<GameToken>, GameError> {
+        Ok(self.games.lock().unwrap().values().filter(|g| LobbyList::of(g) == Some(LobbyList::Open)).map(|g| g.id.clone()).collect())
+    }
+
+    async fn fetch_idle_games(&self, updated_before: DateTime<Utc>, limit: i64) -> Result<Vec<DbGame>, GameError> {
+        let mut games = self.games.lock().unwrap().values()
+            .filter(|g| g.outcome.is_none() && g.updated_at < updated_before)
+            .cloned().collect::<Vec<_>>();
+        games.sort_by_key(|g| g.updated_at);
+        games.truncate(limit as usize);
+        Ok(games)
+    }
+
+    async fn delete_game(&self, game: &DbGame) -> Result<(), GameError> {
+        let mut games = self.games.lock().unwrap();
+        let stored = games.get(&game.id).ok_or(GameError::NotFound)?;
+        if stored.version != game.version {
+            return Err(GameError::Conflict { expected: game.version, actual: stored.version });
+        }
+        games.remove(&game.id);
+        self.messages.lock().unwrap().retain(|m| m.game_id != game.id);
+        Ok(())
+    }
+
+    async fn archive_finished_games(&self, finished_before: DateTime<Utc>, limit: i64) -> Result<usize, GameError> {
+        let mut games = self.games.lock().unwrap();
+        let mut archived_games = self.archived_games.lock().unwrap();
+        let mut finished = games.values()
+            .filter(|g| g.updated_at < finished_before)
+            .filter_map(DbArchivedGame::from_db_game)
+            .collect::<Vec<_>>();
+        finished.sort_by_key(|g| g.finished_at);
+        finished.truncate(limit as usize);
+        let ids = finished.iter().map(|g| g.id.clone()).collect::<HashSet<_>>();
+        games.retain(|id, _| !ids.contains(id));
+        self.messages.lock().unwrap().retain(|m| !ids.contains(&m.game_id));
+        archived_games.extend(finished.into_iter().map(|g| (g.id.clone(), g)));
+        Ok(ids.len())
+    }
+
+    async fn fetch_archived_game(&self, game_token: &GameToken) -> Result<DbArchivedGame, GameError> {
+        self.archived_games.lock().unwrap().get(game_token).cloned().ok_or(GameError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::PlayerToken;
+    use crate::db_schema::{DbGame, GameDimensions};
+    use crate::game::Player;
+    use crate::memory_store::MemoryStore;
+    use crate::error::GameError;
+    use crate::store::GameStore;
+
+    #[tokio::test]
+    async fn claim_and_save() {
+        let store = MemoryStore::default();
+        let game = store.init_game_state(None, GameDimensions::default(), None).await.unwrap();
+        let (red, game) = store.claim_game_player(&game.id, Player::Red, None).await.unwrap();
+        assert!(store.claim_game_player(&game.id, Player::Red, None).await.is_err());
+        let for_player = store.fetch_game_state_for_player(&PlayerToken(red)).await.unwrap();
+        assert_eq!(Player::Red, for_player.player);
+        assert_eq!(1, for_player.game.version);
+        let saved = store.save_game(&game).await.unwrap();
+        assert_eq!(2, saved.version);
+        // the same game once again, as if it was double-clicked
+        assert_eq!(Err(GameError::Conflict { expected: 1, actual: 2 }), store.save_game(&game).await.map(|g| g.version));
+        assert!(store.fetch_game_state(&DbGame::new(GameDimensions::default()).id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lock() {
+        let store = MemoryStore::default();
+        let game = store.init_game_state(None, GameDimensions::default(), None).await.unwrap();
+        let lock = store.try_lock_game(&game.id).await.unwrap();
+        assert!(lock.is_some());
+        assert!(store.try_lock_game(&game.id).await.unwrap().is_none());
+        drop(lock);
+        assert!(store.try_lock_game(&game.id).await.unwrap().is_some());
+    }
+}
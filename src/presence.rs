@@ -0,0 +1,160 @@
+// who is watching a game right now. every game subscription holds a guard for as long as it lives.
+// with the postgres broker, the other instances tell their own counts for a game whenever they change, and every
+// PRESENCE_REFRESH anyways; the counts of an instance that hasn't told them for a while are forgotten
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+use crate::broker::{Coalesce, SimpleBroker};
+use crate::db::GameToken;
+use crate::game::Player;
+use crate::game_broker::publish_presence;
+
+pub const PRESENCE_REFRESH: Duration = Duration::from_secs(30);
+const REMOTE_PRESENCE_TTL: Duration = Duration::from_secs(3 * PRESENCE_REFRESH.as_secs());
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Presence {
+    pub spectators: usize,
+    pub red: usize, // one player can have several tabs open
+    pub blue: usize,
+}
+
+impl Presence {
+    fn seat_mut(&mut self, seat: Option<Player>) -> &mut usize {
+        match seat {
+            None => &mut self.spectators,
+            Some(Player::Red) => &mut self.red,
+            Some(Player::Blue) => &mut self.blue,
+        }
+    }
+
+    fn add(&mut self, other: &Presence) {
+        self.spectators += other.spectators;
+        self.red += other.red;
+        self.blue += other.blue;
+    }
+}
+
+// published whenever someone comes or goes
+#[derive(Clone, Debug)]
+pub struct PresenceChanged(pub GameToken);
+
+impl Coalesce for PresenceChanged {
+    type Key = GameToken;
+    fn coalesce_key(&self) -> GameToken {
+        self.0.clone()
+    }
+}
+
+static PRESENCE: Lazy<Mutex<HashMap<GameToken, Presence>>> = Lazy::new(Default::default);
+// per instance, as of when it was told
+type Instances = HashMap<Uuid, (Presence, Instant)>;
+static REMOTE_PRESENCE: Lazy<Mutex<HashMap<GameToken, Instances>>> = Lazy::new(Default::default);
+// to tell this instance's own notifications from the others'
+pub static INSTANCE_ID: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
+
+pub struct PresenceGuard {
+    game: GameToken,
+    seat: Option<Player>,
+}
+
+pub fn enter(game: GameToken, seat: Option<Player>) -> PresenceGuard {
+    *PRESENCE.lock().unwrap().entry(game.clone()).or_default().seat_mut(seat) += 1;
+    SimpleBroker::publish(PresenceChanged(game.clone()));
+    publish_presence(&game);
+    PresenceGuard { game, seat }
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        {
+            let mut all = PRESENCE.lock().unwrap();
+            if let Some(presence) = all.get_mut(&self.game) {
+                *presence.seat_mut(self.seat) -= 1;
+                if *presence == Presence::default() {
+                    all.remove(&self.game);
+                }
+            }
+        }
+        SimpleBroker::publish(PresenceChanged(self.game.clone()));
+        publish_presence(&self.game);
+    }
+}
+
+// on this instance only, that's what the others are told
+pub fn local_presence(game: &GameToken) -> Presence {
+    PRESENCE.lock().unwrap().get(game).copied().unwrap_or_default()
+}
+
+pub fn local_games() -> Vec<GameToken> {
+    PRESENCE.lock().unwrap().keys().cloned().collect()
+}
+
+pub fn remote_presence_changed(instance: Uuid, game: GameToken, presence: Presence) {
+    {
+        let mut all = REMOTE_PRESENCE.lock().unwrap();
+        let instances = all.entry(game.clone()).or_default();
+        if presence == Presence::default() {
+            instances.remove(&instance);
+        } else {
+            instances.insert(instance, (presence, Instant::now()));
+        }
+        if instances.is_empty() {
+            all.remove(&game);
+        }
+    }
+    SimpleBroker::publish(PresenceChanged(game));
+}
+
+pub fn presence(game: &GameToken) -> Presence {
+    let mut presence = local_presence(game);
+    let mut all = REMOTE_PRESENCE.lock().unwrap();
+    if let Some(instances) = all.get_mut(game) {
+        instances.retain(|_, (_, told_at)| told_at.elapsed() < REMOTE_PRESENCE_TTL);
+        for (remote, _) in instances.values() {
+            presence.add(remote);
+        }
+        if instances.is_empty() {
+            all.remove(game);
+        }
+    }
+    presence
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::db::GameToken;
+    use crate::game::Player::{Blue, Red};
+    use crate::presence::{enter, local_presence, presence, remote_presence_changed, Presence};
+
+    #[test]
+    fn enter_and_leave() {
+        let game = GameToken(Uuid::new_v4());
+        let spectator = enter(game.clone(), None);
+        let red = enter(game.clone(), Some(Red));
+        let red_again = enter(game.clone(), Some(Red));
+        assert_eq!(Presence { spectators: 1, red: 2, blue: 0 }, presence(&game));
+        drop(red);
+        drop(spectator);
+        assert_eq!(Presence { spectators: 0, red: 1, blue: 0 }, presence(&game));
+        drop(red_again);
+        assert_eq!(Presence::default(), presence(&game));
+    }
+
+    #[test]
+    fn other_instances() {
+        let game = GameToken(Uuid::new_v4());
+        let _blue = enter(game.clone(), Some(Blue));
+        let (one, other) = (Uuid::new_v4(), Uuid::new_v4());
+        remote_presence_changed(one, game.clone(), Presence { spectators: 2, red: 1, blue: 0 });
+        remote_presence_changed(other, game.clone(), Presence { spectators: 1, red: 0, blue: 0 });
+        assert_eq!(Presence { spectators: 3, red: 1, blue: 1 }, presence(&game));
+        assert_eq!(Presence { spectators: 0, red: 0, blue: 1 }, local_presence(&game));
+        remote_presence_changed(one, game.clone(), Presence::default());
+        assert_eq!(Presence { spectators: 1, red: 0, blue: 1 }, presence(&game));
+    }
+}
@@ -0,0 +1,154 @@
+// elo ratings. a game is rated when it's saved with an outcome for the first time, in the same transaction,
+// if both sides are rated: logged in users, or a bot. the bots are users nobody can log in as, so that they are on the
+// leaderboard too and their ratings move, and the players can see how they are doing against them
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use crate::adversary::BotId;
+use crate::db::UserId;
+use crate::db_schema::{DbGame, DbRatingChange, DbUser};
+use crate::game::Player;
+
+pub const DEFAULT_RATING: f64 = 1200.0;
+// the first games move the rating more, to get to where the player is faster
+const PROVISIONAL_GAMES: i32 = 30;
+const PROVISIONAL_K: f64 = 40.0;
+const K: f64 = 20.0;
+
+// the same as in the migrations
+pub fn bot_user_id(bot: BotId) -> UserId {
+    UserId(Uuid::from_u128(match bot {
+        BotId::RANDY => 1,
+        BotId::SMART => 2,
+        BotId::EASY => 3,
+        BotId::MEDIUM => 4,
+        BotId::HARD => 5,
+        BotId::PERFECT => 6,
+    }))
+}
+
+// the graded bots start where they are expected to end up, so that their first games don't throw the ratings around
+fn bot_seed_rating(bot: BotId) -> f64 {
+    match bot {
+        BotId::RANDY | BotId::MEDIUM => DEFAULT_RATING,
+        BotId::EASY => 900.0,
+        BotId::HARD => 1500.0,
+        BotId::SMART => 1650.0,
+        BotId::PERFECT => 1800.0,
+    }
+}
+
+// the later bots have a space in the name, which no player can have, so that nobody has taken it already
+fn bot_username(bot: BotId) -> String {
+    match bot {
+        BotId::RANDY | BotId::SMART => bot.as_str().to_string(),
+        _ => format!("{} bot", bot.as_str()),
+    }
+}
+
+pub fn bot_users() -> Vec<DbUser> {
+    BotId::ALL.into_iter().map(|bot| DbUser {
+        id: bot_user_id(bot),
+        username: bot_username(bot),
+        password_hash: "".into(),
+        created_at: Utc::now(),
+        rating: bot_seed_rating(bot),
+        rated_games: 0,
+    }).collect()
+}
+
+pub fn needs_rating(game: &DbGame) -> bool {
+    game.outcome.is_some() && !game.rated
+}
+
+// who plays red and blue as far as the ratings go, if both are rated
+pub fn rated_players(game: &DbGame) -> Option<(UserId, UserId)> {
+    let bot = game.bot_id.map(bot_user_id);
+    let red = game.user_red.clone().or_else(|| if game.player_red.is_none() { bot.clone() } else { None })?;
+    let blue = game.user_blue.clone().or_else(|| if game.player_blue.is_none() { bot.clone() } else { None })?;
+    // playing against yourself proves nothing
+    if red == blue {
+        return None;
+    }
+    Some((red, blue))
+}
+
+fn expected_score(rating: f64, opponent_rating: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent_rating - rating) / 400.0))
+}
+
+fn k_factor(user: &DbUser) -> f64 {
+    if user.rated_games < PROVISIONAL_GAMES { PROVISIONAL_K } else { K }
+}
+
+fn change(game: &DbGame, user: &DbUser, opponent: &DbUser, score: f64, now: DateTime<Utc>) -> DbRatingChange {
+    DbRatingChange {
+        id: Uuid::new_v4(),
+        user_id: user.id.clone(),
+        opponent_id: opponent.id.clone(),
+        game_id: game.id.clone(),
+        rating_before: user.rating,
+        rating_after: user.rating + k_factor(user) * (score - expected_score(user.rating, opponent.rating)),
+        created_at: now,
+    }
+}
+
+// the changes for red and blue; the outcome must be there
+pub fn rate(game: &DbGame, red: &DbUser, blue: &DbUser, now: DateTime<Utc>) -> [DbRatingChange; 2] {
+    let red_score = match game.outcome_winner {
+        Some(Player::Red) => 1.0,
+        Some(Player::Blue) => 0.0,
+        None => 0.5,
+    };
+    [change(game, red, blue, red_score, now), change(game, blue, red, 1.0 - red_score, now)]
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+    use crate::adversary::BotId;
+    use crate::db::{PlayerToken, UserId};
+    use crate::db_schema::{DbGame, DbUser, GameDimensions, GameOutcome};
+    use crate::game::Player;
+    use crate::ratings::{bot_user_id, rate, rated_players, DEFAULT_RATING};
+
+    fn user(rating: f64, rated_games: i32) -> DbUser {
+        DbUser { id: UserId(Uuid::new_v4()), username: "u".into(), password_hash: "".into(), created_at: Utc::now(), rating, rated_games }
+    }
+
+    #[test]
+    fn players() {
+        let mut game = DbGame::new(GameDimensions::default());
+        let alice = UserId(Uuid::new_v4());
+        game.player_red = Some(PlayerToken(Uuid::new_v4()));
+        game.user_red = Some(alice.clone());
+        assert_eq!(None, rated_players(&game));
+        // anonymous
+        game.player_blue = Some(PlayerToken(Uuid::new_v4()));
+        assert_eq!(None, rated_players(&game));
+        game.user_blue = Some(alice.clone());
+        assert_eq!(None, rated_players(&game));
+        let mut bot_game = DbGame::new(GameDimensions::default());
+        bot_game.bot_id = Some(BotId::SMART);
+        bot_game.player_blue = Some(PlayerToken(Uuid::new_v4()));
+        bot_game.user_blue = Some(alice.clone());
+        assert_eq!(Some((bot_user_id(BotId::SMART), alice)), rated_players(&bot_game));
+    }
+
+    #[test]
+    fn elo() {
+        let mut game = DbGame::new(GameDimensions::default());
+        game.set_outcome(GameOutcome::WIN, Some(Player::Red), "".into());
+        let [red, blue] = rate(&game, &user(DEFAULT_RATING, 0), &user(DEFAULT_RATING, 0), Utc::now());
+        assert_eq!(1220.0, red.rating_after);
+        assert_eq!(1180.0, blue.rating_after);
+        // the favourite gets little for a win and loses a lot for a draw
+        let [red, blue] = rate(&game, &user(1600.0, 100), &user(1200.0, 100), Utc::now());
+        assert!(red.rating_after - red.rating_before < 2.0);
+        assert_eq!(red.rating_after - red.rating_before, blue.rating_before - blue.rating_after);
+        game.set_outcome(GameOutcome::DRAW, None, "".into());
+        let [red, _] = rate(&game, &user(1600.0, 100), &user(1200.0, 100), Utc::now());
+        assert!(red.rating_before - red.rating_after > 8.0);
+    }
+}
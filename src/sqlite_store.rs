@@ -0,0 +1,826 @@
+// games in sqlite, for a single instance without a postgres around: DATABASE_URL=sqlite://sstackrr.db or sqlite::memory:.
+// sqlite has no uuids, timestamps with time zones or enums, so a row is text where postgres has those
+
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
+use diesel::sqlite::SqliteConnection;
+use uuid::Uuid;
+use crate::adversary::BotId;
+use crate::db::{DbConfig, GameStateSerialized, GameToken, lower, PlayerToken, run_blocking, UserId, username_taken};
+use crate::db_schema::{DbArchivedGame, DbGame, DbGameMessage, DbRatingChange, DbUser, GameOutcome};
+use crate::game::Player;
+use crate::memory_store::LocalLocks;
+use crate::error::GameError;
+use crate::lobby::LobbyList;
+use crate::ratings::{needs_rating, rate, rated_players};
+use crate::store::{GameLock, GameStore, UserGamesFilter};
+
+embed_migrations!("migrations_sqlite");
+
+mod schema {
+    table! {
+        games {
+            id -> Text,
+            state -> Text,
+            player_red -> Nullable<Text>,
+            player_blue -> Nullable<Text>,
+            bot_id -> Nullable<Text>,
+            width -> SmallInt,
+            height -> SmallInt,
+            win_len -> SmallInt,
+            undo_requested_by -> Nullable<Text>,
+            draw_offered_by -> Nullable<Text>,
+            outcome -> Nullable<Text>,
+            outcome_winner -> Nullable<Text>,
+            outcome_reason -> Nullable<Text>,
+            clock_base_ms -> Nullable<BigInt>,
+            clock_increment_ms -> BigInt,
+            clock_per_move -> Bool,
+            red_time_left_ms -> Nullable<BigInt>,
+            blue_time_left_ms -> Nullable<BigInt>,
+            clock_started_at -> Nullable<Text>,
+            version -> BigInt,
+            user_red -> Nullable<Text>,
+            user_blue -> Nullable<Text>,
+            rated -> Bool,
+            created_at -> Text,
+            updated_at -> Text,
+        }
+    }
+    table! {
+        game_messages {
+            id -> Text,
+            game_id -> Text,
+            player -> Text,
+            text -> Text,
+            created_at -> Text,
+        }
+    }
+    table! {
+        users {
+            id -> Text,
+            username -> Text,
+            password_hash -> Text,
+            created_at -> Text,
+            rating -> Double,
+            rated_games -> Integer,
+        }
+    }
+    table! {
+        rating_history {
+            id -> Text,
+            user_id -> Text,
+            opponent_id -> Text,
+            game_id -> Text,
+            rating_before -> Double,
+            rating_after -> Double,
+            created_at -> Text,
+        }
+    }
+    table! {
+        archived_games {
+            id -> Text,
+            state -> Text,
+            bot_id -> Nullable<Text>,
+            width -> SmallInt,
+            height -> SmallInt,
+            win_len -> SmallInt,
+            outcome -> Text,
+            outcome_winner -> Nullable<Text>,
+            outcome_reason -> Nullable<Text>,
+            user_red -> Nullable<Text>,
+            user_blue -> Nullable<Text>,
+            created_at -> Text,
+            finished_at -> Text,
+        }
+    }
+}
+
+use schema::{archived_games, games, game_messages, rating_history, users};
+
+type SqlitePool = Pool<ConnectionManager<SqliteConnection>>;
+
+// enums are stored the same way postgres stores them
+trait TextEnum: Sized {
+    fn to_text(&self) -> &'static str;
+    fn from_text(s: &str) -> Result<Self, String>;
+}
+
+impl TextEnum for Player {
+    fn to_text(&self) -> &'static str {
+        match self {
+            Player::Red => "RED",
+            Player::Blue => "BLUE",
+        }
+    }
+    fn from_text(s: &str) -> Result<Self, String> {
+        match s {
+            "RED" => Ok(Player::Red),
+            "BLUE" => Ok(Player::Blue),
+            _ => Err(format!("Unknown player {}", s)),
+        }
+    }
+}
+
+impl TextEnum for BotId {
+    fn to_text(&self) -> &'static str {
+        self.as_str()
+    }
+    fn from_text(s: &str) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl TextEnum for GameOutcome {
+    fn to_text(&self) -> &'static str {
+        match self {
+            GameOutcome::WIN => "WIN",
+            GameOutcome::DRAW => "DRAW",
+            GameOutcome::RESIGN => "RESIGN",
+            GameOutcome::ABANDON => "ABANDON",
+            GameOutcome::TIMEOUT => "TIMEOUT",
+        }
+    }
+    fn from_text(s: &str) -> Result<Self, String> {
+        match s {
+            "WIN" => Ok(GameOutcome::WIN),
+            "DRAW" => Ok(GameOutcome::DRAW),
+            "RESIGN" => Ok(GameOutcome::RESIGN),
+            "ABANDON" => Ok(GameOutcome::ABANDON),
+            "TIMEOUT" => Ok(GameOutcome::TIMEOUT),
+            _ => Err(format!("Unknown outcome {}", s)),
+        }
+    }
+}
+
+fn enum_to_text<T: TextEnum>(v: &Option<T>) -> Option<String> {
+    v.as_ref().map(|v| v.to_text().to_string())
+}
+
+fn enum_from_text<T: TextEnum>(s: &Option<String>) -> Result<Option<T>, String> {
+    s.as_deref().map(T::from_text).transpose()
+}
+
+fn uuid_from_text(s: &str) -> Result<Uuid, String> {
+    Uuid::parse_str(s).map_err(|e| e.to_string())
+}
+
+// fixed precision, so that the text sorts the same as the time
+fn time_to_text(t: &DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn time_from_text(s: &str) -> Result<DateTime<Utc>, String> {
+    Ok(DateTime::parse_from_rfc3339(s).map_err(|e| e.to_string())?.with_timezone(&Utc))
+}
+
+#[derive(Queryable, Insertable, AsChangeset)]
+#[table_name="games"]
+#[changeset_options(treat_none_as_null="true")]
+struct SqliteGame {
+    id: String,
+    state: String,
+    player_red: Option<String>,
+    player_blue: Option<String>,
+    bot_id: Option<String>,
+    width: i16,
+    height: i16,
+    win_len: i16,
+    undo_requested_by: Option<String>,
+    draw_offered_by: Option<String>,
+    outcome: Option<String>,
+    outcome_winner: Option<String>,
+    outcome_reason: Option<String>,
+    clock_base_ms: Option<i64>,
+    clock_increment_ms: i64,
+    clock_per_move: bool,
+    red_time_left_ms: Option<i64>,
+    blue_time_left_ms: Option<i64>,
+    clock_started_at: Option<String>,
+    version: i64,
+    user_red: Option<String>,
+    user_blue: Option<String>,
+    rated: bool,
+    created_at: String,
+    updated_at: String,
+}
+
+impl SqliteGame {
+    fn from_db_game(g: &DbGame) -> SqliteGame {
+        SqliteGame {
+            id: g.id.0.to_string(),
+            state: g.state.0.clone(),
+            player_red: g.player_red.as_ref().map(|p| p.0.to_string()),
+            player_blue: g.player_blue.as_ref().map(|p| p.0.to_string()),
+            bot_id: enum_to_text(&g.bot_id),
+            width: g.width,
+            height: g.height,
+            win_len: g.win_len,
+            undo_requested_by: enum_to_text(&g.undo_requested_by),
+            draw_offered_by: enum_to_text(&g.draw_offered_by),
+            outcome: enum_to_text(&g.outcome),
+            outcome_winner: enum_to_text(&g.outcome_winner),
+            outcome_reason: g.outcome_reason.clone(),
+            clock_base_ms: g.clock_base_ms,
+            clock_increment_ms: g.clock_increment_ms,
+            clock_per_move: g.clock_per_move,
+            red_time_left_ms: g.red_time_left_ms,
+            blue_time_left_ms: g.blue_time_left_ms,
+            clock_started_at: g.clock_started_at.as_ref().map(time_to_text),
+            version: g.version,
+            user_red: g.user_red.as_ref().map(|u| u.0.to_string()),
+            user_blue: g.user_blue.as_ref().map(|u| u.0.to_string()),
+            rated: g.rated,
+            created_at: time_to_text(&g.created_at),
+            updated_at: time_to_text(&g.updated_at),
+        }
+    }
+
+    fn db_game(self) -> Result<DbGame, GameError> {
+        Ok(DbGame {
+            id: GameToken(uuid_from_text(&self.id)?),
+            state: GameStateSerialized(self.state),
+            player_red: self.player_red.as_deref().map(uuid_from_text).transpose()?.map(PlayerToken),
+            player_blue: self.player_blue.as_deref().map(uuid_from_text).transpose()?.map(PlayerToken),
+            bot_id: enum_from_text(&self.bot_id)?,
+            width: self.width,
+            height: self.height,
+            win_len: self.win_len,
+            undo_requested_by: enum_from_text(&self.undo_requested_by)?,
+            draw_offered_by: enum_from_text(&self.draw_offered_by)?,
+            outcome: enum_from_text(&self.outcome)?,
+            outcome_winner: enum_from_text(&self.outcome_winner)?,
+            outcome_reason: self.outcome_reason,
+            clock_base_ms: self.clock_base_ms,
+            clock_increment_ms: self.clock_increment_ms,
+            clock_per_move: self.clock_per_move,
+            red_time_left_ms: self.red_time_left_ms,
+            blue_time_left_ms: self.blue_time_left_ms,
+            clock_started_at: self.clock_started_at.as_deref().map(time_from_text).transpose()?,
+            version: self.version,
+            user_red: self.user_red.as_deref().map(uuid_from_text).transpose()?.map(UserId),
+            user_blue: self.user_blue.as_deref().map(uuid_from_text).transpose()?.map(UserId),
+            rated: self.rated,
+            created_at: time_from_text(&self.created_at)?,
+            updated_at: time_from_text(&self.updated_at)?,
+        })
+    }
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name="archived_games"]
+struct SqliteArchivedGame {
+    id: String,
+    state: String,
+    bot_id: Option<String>,
+    width: i16,
+    height: i16,
+    win_len: i16,
+    outcome: String,
+    outcome_winner: Option<String>,
+    outcome_reason: Option<String>,
+    user_red: Option<String>,
+    user_blue: Option<String>,
+    created_at: String,
+    finished_at: String,
+}
+
+impl SqliteArchivedGame {
+    fn from_db_archived_game(g: &DbArchivedGame) -> SqliteArchivedGame {
+        SqliteArchivedGame {
+            id: g.id.0.to_string(),
+            state: g.state.0.clone(),
+            bot_id: enum_to_text(&g.bot_id),
+            width: g.width,
+            height: g.height,
+            win_len: g.win_len,
+            outcome: g.outcome.to_text().to_string(),
+            outcome_winner: enum_to_text(&g.outcome_winner),
+            outcome_reason: g.outcome_reason.clone(),
+            user_red: g.user_red.as_ref().map(|u| u.0.to_string()),
+            user_blue: g.user_blue.as_ref().map(|u| u.0.to_string()),
+            created_at: time_to_text(&g.created_at),
+            finished_at: time_to_text(&g.finished_at),
+        }
+    }
+
+    fn db_archived_game(self) -> Result<DbArchivedGame, GameError> {
+        Ok(DbArchivedGame {
+            id: GameToken(uuid_from_text(&self.id)?),
+            state: GameStateSerialized(self.state),
+            bot_id: enum_from_text(&self.bot_id)?,
+            width: self.width,
+            height: self.height,
+            win_len: self.win_len,
+            outcome: GameOutcome::from_text(&self.outcome)?,
+            outcome_winner: enum_from_text(&self.outcome_winner)?,
+            outcome_reason: self.outcome_reason,
+            user_red: self.user_red.as_deref().map(uuid_from_text).transpose()?.map(UserId),
+            user_blue: self.user_blue.as_deref().map(uuid_from_text).transpose()?.map(UserId),
+            created_at: time_from_text(&self.created_at)?,
+            finished_at: time_from_text(&self.finished_at)?,
+        })
+    }
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name="game_messages"]
+struct SqliteGameMessage {
+    id: String,
+    game_id: String,
+    player: String,
+    text: String,
+    created_at: String,
+}
+
+impl SqliteGameMessage {
+    fn from_db_game_message(m: &DbGameMessage) -> SqliteGameMessage {
+        SqliteGameMessage {
+            id: m.id.to_string(),
+            game_id: m.game_id.0.to_string(),
+            player: m.player.to_text().to_string(),
+            text: m.text.clone(),
+            created_at: time_to_text(&m.created_at),
+        }
+    }
+
+    fn db_game_message(self) -> Result<DbGameMessage, GameError> {
+        Ok(DbGameMessage {
+            id: uuid_from_text(&self.id)?,
+            game_id: GameToken(uuid_from_text(&self.game_id)?),
+            player: Player::from_text(&self.player)?,
+            text: self.text,
+            created_at: time_from_text(&self.created_at)?,
+        })
+    }
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name="users"]
+struct SqliteUser {
+    id: String,
+    username: String,
+    password_hash: String,
+    created_at: String,
+    rating: f64,
+    rated_games: i32,
+}
+
+impl SqliteUser {
+    fn from_db_user(u: &DbUser) -> SqliteUser {
+        SqliteUser {
+            id: u.id.0.to_string(),
+            username: u.username.clone(),
+            password_hash: u.password_hash.clone(),
+            created_at: time_to_text(&u.created_at),
+            rating: u.rating,
+            rated_games: u.rated_games,
+        }
+    }
+
+    fn db_user(self) -> Result<DbUser, GameError> {
+        Ok(DbUser {
+            id: UserId(uuid_from_text(&self.id)?),
+            username: self.username,
+            password_hash: self.password_hash,
+            created_at: time_from_text(&self.created_at)?,
+            rating: self.rating,
+            rated_games: self.rated_games,
+        })
+    }
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name="rating_history"]
+struct SqliteRatingChange {
+    id: String,
+    user_id: String,
+    opponent_id: String,
+    game_id: String,
+    rating_before: f64,
+    rating_after: f64,
+    created_at: String,
+}
+
+impl SqliteRatingChange {
+    fn from_db_rating_change(c: &DbRatingChange) -> SqliteRatingChange {
+        SqliteRatingChange {
+            id: c.id.to_string(),
+            user_id: c.user_id.0.to_string(),
+            opponent_id: c.opponent_id.0.to_string(),
+            game_id: c.game_id.0.to_string(),
+            rating_before: c.rating_before,
+            rating_after: c.rating_after,
+            created_at: time_to_text(&c.created_at),
+        }
+    }
+
+    fn db_rating_change(self) -> Result<DbRatingChange, GameError> {
+        Ok(DbRatingChange {
+            id: uuid_from_text(&self.id)?,
+            user_id: UserId(uuid_from_text(&self.user_id)?),
+            opponent_id: UserId(uuid_from_text(&self.opponent_id)?),
+            game_id: GameToken(uuid_from_text(&self.game_id)?),
+            rating_before: self.rating_before,
+            rating_after: self.rating_after,
+            created_at: time_from_text(&self.created_at)?,
+        })
+    }
+}
+
+// the whole database is locked by the write already
+fn rate_game(conn: &SqliteConnection, game: &DbGame) -> Result<(), GameError> {
+    let (red, blue) = match rated_players(game) {
+        Some(players) => players,
+        None => return Ok(()),
+    };
+    let find = |user_id: &UserId| users::table.filter(users::id.eq(user_id.0.to_string())).first::<SqliteUser>(conn).map_err(GameError::from)?.db_user();
+    let changes = rate(game, &find(&red)?, &find(&blue)?, Utc::now());
+    for change in changes.iter() {
+        diesel::update(users::table.filter(users::id.eq(change.user_id.0.to_string())))
+            .set((users::rating.eq(change.rating_after), users::rated_games.eq(users::rated_games + 1)))
+            .execute(conn)?;
+    }
+    let rows = changes.iter().map(SqliteRatingChange::from_db_rating_change).collect::<Vec<_>>();
+    diesel::insert_into(rating_history::table).values(&rows).execute(conn)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct SqliteSettings {
+    busy_timeout: Duration,
+}
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for SqliteSettings {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        // writers wait for each other instead of failing right away
+        conn.batch_execute(&format!("PRAGMA foreign_keys = ON; PRAGMA busy_timeout = {};", self.busy_timeout.as_millis()))
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+pub struct SqliteStore {
+    pool: SqlitePool,
+    locks: LocalLocks, // one instance per database file is assumed
+}
+
+impl SqliteStore {
+    pub fn connect(config: &DbConfig) -> Result<SqliteStore, String> {
+        let path = config.url.strip_prefix("sqlite://").or_else(|| config.url.strip_prefix("sqlite:")).unwrap_or(&config.url);
+        let in_memory = path == ":memory:";
+        let builder = Pool::builder()
+            .connection_timeout(config.connection_timeout)
+            .connection_customizer(Box::new(SqliteSettings { busy_timeout: config.connection_timeout }));
+        // every connection to :memory: is a database of its own, so there must be just one that lives forever
+        let builder = if in_memory {
+            builder.max_size(1).idle_timeout(None).max_lifetime(None)
+        } else {
+            builder.max_size(config.pool_size)
+        };
+        let pool = builder.build(ConnectionManager::new(path)).map_err(|e| e.to_string())?;
+        Ok(SqliteStore { pool, locks: LocalLocks::default() })
+    }
+
+    pub fn run_embed_migrations(&self) {
+        embedded_migrations::run(&self.pool.get().unwrap()).expect("failed to run migrations");
+    }
+
+    async fn run<R, E, F>(&self, f: F) -> Result<R, E>
+        where
+            R: Send + 'static,
+            E: From<String> + Send + 'static,
+            F: FnOnce(&SqliteConnection) -> Result<R, E> + Send + 'static,
+    {
+        run_blocking(&self.pool, f).await
+    }
+}
+
+#[async_trait]
+impl GameStore for SqliteStore {
+    async fn insert_game(&self, game: &DbGame) -> Result<DbGame, GameError> {
+        use schema::games::dsl::*;
+        let row = SqliteGame::from_db_game(game);
+        self.run(move |conn| {
+            diesel::insert_into(games).values(&row).execute(conn).map_err(GameError::from)?;
+            games.filter(id.eq(&row.id)).first::<SqliteGame>(conn).map_err(GameError::from)
+        }).await?.db_game()
+    }
+
+    async fn fetch_game_state(&self, game_token: &GameToken) -> Result<DbGame, GameError> {
+        use schema::games::dsl::*;
+        let token = game_token.0.to_string();
+        self.run(move |conn| {
+            games.filter(id.eq(token)).first::<SqliteGame>(conn).map_err(GameError::from)
+        }).await?.db_game()
+    }
+
+    async fn fetch_game_by_player(&self, player_token: &PlayerToken) -> Result<DbGame, GameError> {
+        use schema::games::dsl::*;
+        let token = player_token.0.to_string();
+        self.run(move |conn| {
+            games.filter(player_red.eq(&token).or(player_blue.eq(&token))).first::<SqliteGame>(conn).map_err(GameError::from)
+        }).await?.db_game()
+    }
+
+    async fn write_game(&self, game: &DbGame) -> Result<DbGame, GameError> {
+        use schema::games::dsl::*;
+        let expected = game.version;
+        let needs_rating = needs_rating(game);
+        let mut next = SqliteGame::from_db_game(game);
+        next.version += 1;
+        next.updated_at = time_to_text(&Utc::now());
+        next.rated |= needs_rating;
+        // no RETURNING in diesel's sqlite, so the write and the read are done together
+        self.run(move |conn| {
+            conn.immediate_transaction::<_, GameError, _>(|| {
+                let updated = diesel::update(games.filter(id.eq(&next.id)).filter(version.eq(expected)))
+                    .set(&next)
+                    .execute(conn)?;
+                if updated == 0 {
+                    let actual = games.filter(id.eq(&next.id)).select(version).first::<i64>(conn)?;
+                    return Err(GameError::Conflict { expected, actual });
+                }
+                let r = games.filter(id.eq(&next.id)).first::<SqliteGame>(conn)?.db_game()?;
+                if needs_rating {
+                    rate_game(conn, &r)?;
+                }
+                Ok(r)
+            })
+        }).await
+    }
+
+    async fn fetch_running_timed_games(&self) -> Result<Vec<DbGame>, GameError> {
+        use schema::games::dsl::*;
+        self.run(|conn| {
+            games.filter(outcome.is_null().and(clock_base_ms.is_not_null()).and(clock_started_at.is_not_null()))
+                .load::<SqliteGame>(conn).map_err(GameError::from)
+        }).await?.into_iter().map(SqliteGame::db_game).collect()
+    }
+
+    async fn try_lock_game(&self, game_token: &GameToken) -> Result<Option<GameLock>, GameError> {
+        Ok(self.locks.try_lock(game_token))
+    }
+
+    async fn insert_message(&self, message: &DbGameMessage) -> Result<DbGameMessage, GameError> {
+        use schema::game_messages::dsl::*;
+        let row = SqliteGameMessage::from_db_game_message(message);
+        self.run(move |conn| {
+            diesel::insert_into(game_messages).values(&row).execute(conn).map_err(GameError::from)?;
+            game_messages.filter(id.eq(&row.id)).first::<SqliteGameMessage>(conn).map_err(GameError::from)
+        }).await?.db_game_message()
+    }
+
+    async fn fetch_game_messages(&self, game_token: &GameToken) -> Result<Vec<DbGameMessage>, GameError> {
+        use schema::game_messages::dsl::*;
+        let token = game_token.0.to_string();
+        self.run(move |conn| {
+            game_messages.filter(game_id.eq(token)).order(created_at.asc())
+                .load::<SqliteGameMessage>(conn).map_err(GameError::from)
+        }).await?.into_iter().map(SqliteGameMessage::db_game_message).collect()
+    }
+
+    async fn fetch_game_message(&self, message_id: &Uuid) -> Result<DbGameMessage, GameError> {
+        use schema::game_messages::dsl::*;
+        let message_id = message_id.to_string();
+        self.run(move |conn| {
+            game_messages.filter(id.eq(message_id)).first::<SqliteGameMessage>(conn).map_err(GameError::from)
+        }).await?.db_game_message()
+    }
+
+    async fn insert_user(&self, user: &DbUser) -> Result<DbUser, GameError> {
+        use schema::users::dsl::*;
+        let row = SqliteUser::from_db_user(user);
+        self.run(move |conn| {
+            diesel::insert_into(users).values(&row).execute(conn).map_err(username_taken)?;
+            users.filter(id.eq(&row.id)).first::<SqliteUser>(conn).map_err(GameError::from)
+        }).await?.db_user()
+    }
+
+    async fn fetch_user(&self, user_id: &UserId) -> Result<DbUser, GameError> {
+        use schema::users::dsl::*;
+        let user_id = user_id.0.to_string();
+        self.run(move |conn| {
+            users.filter(id.eq(user_id)).first::<SqliteUser>(conn).map_err(GameError::from)
+        }).await?.db_user()
+    }
+
+    async fn fetch_user_by_name(&self, name: &str) -> Result<DbUser, GameError> {
+        use schema::users::dsl::*;
+        let name = name.to_lowercase();
+        self.run(move |conn| {
+            users.filter(lower(username).eq(name)).first::<SqliteUser>(conn).map_err(GameError::from)
+        }).await?.db_user()
+    }
+
+    async fn fetch_leaderboard(&self, limit: i64, offset: i64) -> Result<Vec<DbUser>, GameError> {
+        use schema::users::dsl::*;
+        self.run(move |conn| {
+            users.filter(rated_games.gt(0)).order((rating.desc(), username.asc())).limit(limit).offset(offset)
+                .load::<SqliteUser>(conn).map_err(GameError::from)
+        }).await?.into_iter().map(SqliteUser::db_user).collect()
+    }
+
+    async fn fetch_rating_history(&self, user: &UserId, opponent: Option<UserId>) -> Result<Vec<DbRatingChange>, GameError> {
+        use schema::rating_history::dsl::*;
+        let user = user.0.to_string();
+        self.run(move |conn| {
+            let mut query = rating_history.filter(user_id.eq(user)).into_boxed();
+            if let Some(opponent) = opponent {
+                query = query.filter(opponent_id.eq(opponent.0.to_string()));
+            }
+            query.order(created_at.asc()).load::<SqliteRatingChange>(conn).map_err(GameError::from)
+        }).await?.into_iter().map(SqliteRatingChange::db_rating_change).collect()
+    }
+
+    async fn fetch_user_games(&self, user_id: &UserId, filter: &UserGamesFilter) -> Result<Vec<DbGame>, GameError> {
+        use schema::games::dsl::*;
+        let user_id = user_id.0.to_string();
+        let filter = filter.clone();
+        self.run(move |conn| {
+            let mut query = games.filter(user_red.eq(&user_id).or(user_blue.eq(&user_id))).into_boxed();
+            if let Some(finished) = filter.finished {
+                query = if finished { query.filter(outcome.is_not_null()) } else { query.filter(outcome.is_null()) };
+            }
+            if let Some(o) = filter.outcome {
+                query = query.filter(outcome.eq(o.to_text()));
+            }
+            if let Some(vs_bot) = filter.vs_bot {
+                query = if vs_bot { query.filter(bot_id.is_not_null()) } else { query.filter(bot_id.is_null()) };
+            }
+            query.order(updated_at.desc()).load::<SqliteGame>(conn).map_err(GameError::from)
+        }).await?.into_iter().map(SqliteGame::db_game).collect()
+    }
+
+    async fn fetch_lobby_games(&self, list: LobbyList, limit: i64, offset: i64) -> Result<Vec<DbGame>, GameError> {
+        use schema::games::dsl::*;
+        self.run(move |conn| {
+            let mut query = games.filter(player_red.is_not_null().or(player_blue.is_not_null())).into_boxed();
+            query = match list {
+                LobbyList::Open => query.filter(outcome.is_null().and(bot_id.is_null()).and(player_red.is_null().or(player_blue.is_null()))),
+                LobbyList::Ongoing => query.filter(outcome.is_null().and(bot_id.is_not_null().or(player_red.is_not_null().and(player_blue.is_not_null())))),
+                LobbyList::Finished => query.filter(outcome.is_not_null()),
+            };
+            query.order((updated_at.desc(), id)).limit(limit).offset(offset).load::<SqliteGame>(conn).map_err(GameError::from)
+        }).await?.into_iter().map(SqliteGame::db_game).collect()
+    }
+
+    async fn fetch_open_game_ids(&self) -> Result<Vec<GameToken>, GameError> {
+        use schema::games::dsl::*;
+        self.run(move |conn| {
+            games.filter(outcome.is_null().and(bot_id.is_null()))
+                .filter(player_red.is_not_null().or(player_blue.is_not_null()))
+                .filter(player_red.is_null().or(player_blue.is_null()))
+                .select(id).load::<String>(conn).map_err(GameError::from)
+        }).await?.iter().map(|token| Ok(GameToken(uuid_from_text(token)?))).collect()
+    }
+
+    async fn fetch_idle_games(&self, updated_before: DateTime<Utc>, limit: i64) -> Result<Vec<DbGame>, GameError> {
+        use schema::games::dsl::*;
+        let updated_before = time_to_text(&updated_before);
+        self.run(move |conn| {
+            games.filter(outcome.is_null().and(updated_at.lt(updated_before)))
+                .order(updated_at).limit(limit)
+                .load::<SqliteGame>(conn).map_err(GameError::from)
+        }).await?.into_iter().map(SqliteGame::db_game).collect()
+    }
+
+    async fn delete_game(&self, game: &DbGame) -> Result<(), GameError> {
+        use schema::games::dsl::*;
+        let game_id = game.id.0.to_string();
+        let expected = game.version;
+        self.run(move |conn| {
+            conn.immediate_transaction::<_, GameError, _>(|| {
+                let deleted = diesel::delete(games.filter(id.eq(&game_id)).filter(version.eq(expected))).execute(conn)?;
+                if deleted == 0 {
+                    let actual = games.filter(id.eq(&game_id)).select(version).first::<i64>(conn)?;
+                    return Err(GameError::Conflict { expected, actual });
+                }
+                Ok(())
+            })
+        }).await
+    }
+
+    async fn archive_finished_games(&self, finished_before: DateTime<Utc>, limit: i64) -> Result<usize, GameError> {
+        use schema::games::dsl::*;
+        let finished_before = time_to_text(&finished_before);
+        self.run(move |conn| {
+            conn.immediate_transaction::<_, GameError, _>(|| {
+                let finished = games.filter(outcome.is_not_null().and(updated_at.lt(finished_before)))
+                    .order(updated_at).limit(limit)
+                    .load::<SqliteGame>(conn)?;
+                let archived = finished.into_iter()
+                    .map(|g| g.db_game())
+                    .collect::<Result<Vec<_>, _>>()?
+                    .iter().filter_map(DbArchivedGame::from_db_game)
+                    .map(|g| SqliteArchivedGame::from_db_archived_game(&g))
+                    .collect::<Vec<_>>();
+                diesel::insert_into(archived_games::table).values(&archived).execute(conn)?;
+                let ids = archived.iter().map(|g| g.id.clone()).collect::<Vec<_>>();
+                diesel::delete(games.filter(id.eq_any(ids))).execute(conn)?;
+                Ok(archived.len())
+            })
+        }).await
+    }
+
+    async fn fetch_archived_game(&self, game_token: &GameToken) -> Result<DbArchivedGame, GameError> {
+        use schema::archived_games::dsl::*;
+        let game_id = game_token.0.to_string();
+        self.run(move |conn| {
+            archived_games.filter(id.eq(game_id)).first::<SqliteArchivedGame>(conn).map_err(GameError::from)
+        }).await?.db_archived_game()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+    use crate::adversary::BotId;
+    use crate::clock::TimeControl;
+    use crate::db::{DbConfig, PlayerToken};
+    use crate::db_schema::{DbGameMessage, GameDimensions, GameOutcome};
+    use crate::game::Player;
+    use crate::sqlite_store::SqliteStore;
+    use crate::error::GameError;
+    use crate::store::GameStore;
+
+    fn store() -> SqliteStore {
+        let store = SqliteStore::connect(&DbConfig {
+            url: "sqlite::memory:".into(),
+            pool_size: 1,
+            connection_timeout: std::time::Duration::from_secs(1),
+            statement_timeout: None,
+        }).unwrap();
+        store.run_embed_migrations();
+        store
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let store = store();
+        let time_control = TimeControl::new(60_000, 1000, false).unwrap();
+        let game = store.init_game_state(Some(BotId::SMART), GameDimensions::default(), Some(time_control)).await.unwrap();
+        let (red, mut game) = store.claim_game_player(&game.id, Player::Red, None).await.unwrap();
+        game.clock_started_at = Some(Utc::now());
+        game.set_outcome(GameOutcome::RESIGN, Some(Player::Blue), "RED resigned".into());
+        let saved = store.save_game(&game).await.unwrap();
+        let fetched = store.fetch_game_state_for_player(&PlayerToken(red)).await.unwrap();
+        assert_eq!(Player::Red, fetched.player);
+        assert_eq!(2, fetched.game.version);
+        assert_eq!(saved.state, fetched.game.state);
+        assert_eq!(Some(BotId::SMART), fetched.game.bot_id);
+        assert_eq!(Some(GameOutcome::RESIGN), fetched.game.outcome);
+        assert_eq!(Some(Player::Blue), fetched.game.outcome_winner);
+        // microseconds, like in postgres
+        assert_eq!(game.clock_started_at.map(|t| t.timestamp_micros()), fetched.game.clock_started_at.map(|t| t.timestamp_micros()));
+        assert_eq!(Some(60_000), fetched.game.clock_base_ms);
+        assert_eq!(Err(GameError::Conflict { expected: 1, actual: 2 }), store.save_game(&game).await.map(|g| g.version));
+    }
+
+    #[tokio::test]
+    async fn messages() {
+        let store = store();
+        let game = store.init_game_state(None, GameDimensions::default(), None).await.unwrap();
+        for text in ["gl", "hf"] {
+            store.insert_game_message(&DbGameMessage {
+                id: Uuid::new_v4(),
+                game_id: game.id.clone(),
+                player: Player::Blue,
+                text: text.into(),
+                created_at: Utc::now(),
+            }).await.unwrap();
+        }
+        let messages = store.fetch_game_messages(&game.id).await.unwrap();
+        assert_eq!(vec!["gl", "hf"], messages.iter().map(|m| m.text.as_str()).collect::<Vec<_>>());
+        assert_eq!(Player::Blue, messages[0].player);
+    }
+
+    #[tokio::test]
+    async fn archive() {
+        let store = store();
+        let game = store.init_game_state(Some(BotId::RANDY), GameDimensions::default(), None).await.unwrap();
+        let (_, mut game) = store.claim_game_player(&game.id, Player::Blue, None).await.unwrap();
+        let unfinished = store.init_game_state(None, GameDimensions::default(), None).await.unwrap();
+        assert_eq!(2, store.fetch_idle_games(Utc::now(), 10).await.unwrap().len());
+        game.set_outcome(GameOutcome::RESIGN, Some(Player::Red), "BLUE resigned".into());
+        let game = store.save_game(&game).await.unwrap();
+        assert_eq!(0, store.archive_finished_games(game.updated_at, 10).await.unwrap());
+        assert_eq!(1, store.archive_finished_games(Utc::now(), 10).await.unwrap());
+        assert_eq!(Err(GameError::NotFound), store.fetch_game_state(&game.id).await.map(|_| ()));
+        let archived = store.fetch_archived_game(&game.id).await.unwrap();
+        assert_eq!(Some(BotId::RANDY), archived.bot_id);
+        assert_eq!(GameOutcome::RESIGN, archived.outcome);
+        assert_eq!(Some(Player::Red), archived.outcome_winner);
+        assert_eq!(game.updated_at.timestamp_micros(), archived.finished_at.timestamp_micros());
+
+        let mut moved = unfinished.clone();
+        moved.version += 1;
+        assert_eq!(Err(GameError::Conflict { expected: 1, actual: 0 }), store.delete_game(&moved).await);
+        store.delete_game(&unfinished).await.unwrap();
+        assert_eq!(Err(GameError::NotFound), store.fetch_game_state(&unfinished.id).await.map(|_| ()));
+    }
+}
@@ -0,0 +1,191 @@
+// where games are kept. the backend is picked by the DATABASE_URL scheme: postgres://, sqlite:// or memory:
+// (nothing survives a restart, for tests and offline demos)
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use crate::adversary::BotId;
+use crate::clock::{punch, take_back, ticking, TimeControl};
+use crate::db::{DbConfig, GameStateSerialized, GameToken, PgStore, PlayerToken, UserId};
+use crate::db_schema::{DbArchivedGame, DbGame, DbGameMessage, DbRatingChange, DbUser, GameDimensions, GameOutcome};
+use crate::game::{GameOperations, Player, validate_dimensions};
+use crate::game_broker::{publish_game, publish_game_message};
+use crate::error::GameError;
+use crate::lobby::LobbyList;
+use crate::memory_store::MemoryStore;
+use crate::sqlite_store::SqliteStore;
+
+pub type Store = Arc<dyn GameStore>;
+
+pub fn open_store(config: &DbConfig) -> Result<Store, GameError> {
+    if config.url.starts_with("memory:") {
+        return Ok(Arc::new(MemoryStore::default()));
+    }
+    if config.url.starts_with("sqlite:") {
+        let store = SqliteStore::connect(config)?;
+        store.run_embed_migrations();
+        return Ok(Arc::new(store));
+    }
+    let store = PgStore::connect(config)?;
+    store.run_embed_migrations();
+    Ok(Arc::new(store))
+}
+
+pub struct DbGameAndPlayer {
+    pub game: DbGame,
+    pub player: Player
+}
+
+// any of the user's games; a None matches anything
+#[derive(Clone, Debug, Default)]
+pub struct UserGamesFilter {
+    pub finished: Option<bool>,
+    pub outcome: Option<GameOutcome>,
+    pub vs_bot: Option<bool>,
+}
+
+impl UserGamesFilter {
+    pub fn matches(&self, game: &DbGame) -> bool {
+        self.finished.is_none_or(|f| f == game.outcome.is_some())
+            && self.outcome.is_none_or(|o| Some(o) == game.outcome)
+            && self.vs_bot.is_none_or(|b| b == game.bot_id.is_some())
+    }
+}
+
+// held while the lock is; for instance, to not let two instances make a bot turn for the same game
+pub struct GameLock {
+    _guard: Box<dyn Send>, // releases the lock on drop
+}
+
+impl GameLock {
+    pub fn new(guard: impl Send + 'static) -> GameLock {
+        GameLock { _guard: Box::new(guard) }
+    }
+}
+
+// the backends only store and load; the game rules and publishing updates are the same for all of them
+#[async_trait]
+pub trait GameStore: Send + Sync {
+    async fn insert_game(&self, game: &DbGame) -> Result<DbGame, GameError>;
+    async fn fetch_game_state(&self, game_token: &GameToken) -> Result<DbGame, GameError>;
+    async fn fetch_game_by_player(&self, player_token: &PlayerToken) -> Result<DbGame, GameError>;
+    // write everything but the id as is, bump the version and set updated_at, if the version is still the same as the game's.
+    // a game that has just got its outcome is rated in the same transaction, see ratings.rs
+    async fn write_game(&self, game: &DbGame) -> Result<DbGame, GameError>;
+    async fn fetch_running_timed_games(&self) -> Result<Vec<DbGame>, GameError>;
+    // None if somebody else holds it
+    async fn try_lock_game(&self, game_token: &GameToken) -> Result<Option<GameLock>, GameError>;
+    async fn insert_message(&self, message: &DbGameMessage) -> Result<DbGameMessage, GameError>;
+    async fn fetch_game_messages(&self, game_token: &GameToken) -> Result<Vec<DbGameMessage>, GameError>;
+    async fn fetch_game_message(&self, message_id: &Uuid) -> Result<DbGameMessage, GameError>;
+    // UsernameTaken if there is one with the same name, in any case
+    async fn insert_user(&self, user: &DbUser) -> Result<DbUser, GameError>;
+    async fn fetch_user(&self, user_id: &UserId) -> Result<DbUser, GameError>;
+    async fn fetch_user_by_name(&self, username: &str) -> Result<DbUser, GameError>;
+    // the most recently updated first
+    async fn fetch_user_games(&self, user_id: &UserId, filter: &UserGamesFilter) -> Result<Vec<DbGame>, GameError>;
+    // the most recently updated first, see LobbyList::of
+    async fn fetch_lobby_games(&self, list: LobbyList, limit: i64, offset: i64) -> Result<Vec<DbGame>, GameError>;
+    // all of them, not just a page, for the lobby subscription to start from
+    async fn fetch_open_game_ids(&self) -> Result<Vec<GameToken>, GameError>;
+    // unfinished games nobody has saved since then, the longest idle first
+    async fn fetch_idle_games(&self, updated_before: DateTime<Utc>, limit: i64) -> Result<Vec<DbGame>, GameError>;
+    // only if nobody has saved the game since, same as write_game
+    async fn delete_game(&self, game: &DbGame) -> Result<(), GameError>;
+    // moves the games finished before then to archived_games, how many were moved
+    async fn archive_finished_games(&self, finished_before: DateTime<Utc>, limit: i64) -> Result<usize, GameError>;
+    async fn fetch_archived_game(&self, game_token: &GameToken) -> Result<DbArchivedGame, GameError>;
+    // the best rated first, of those who have played a rated game
+    async fn fetch_leaderboard(&self, limit: i64, offset: i64) -> Result<Vec<DbUser>, GameError>;
+    // the oldest first; only the games against the opponent, if there is one
+    async fn fetch_rating_history(&self, user_id: &UserId, opponent_id: Option<UserId>) -> Result<Vec<DbRatingChange>, GameError>;
+
+    async fn init_game_state(&self, bot: Option<BotId>, dimensions: GameDimensions, time_control: Option<TimeControl>) -> Result<DbGame, GameError> {
+        validate_dimensions(dimensions.width, dimensions.height, dimensions.win_len)?;
+        let mut new_game = DbGame::new(dimensions);
+        new_game.bot_id = bot;
+        if let Some(time_control) = time_control {
+            time_control.apply(&mut new_game);
+        }
+        self.insert_game(&new_game).await
+    }
+
+    // an archived game is only good to look at
+    async fn fetch_game_or_archived(&self, game_token: &GameToken) -> Result<DbGame, GameError> {
+        match self.fetch_game_state(game_token).await {
+            Err(GameError::NotFound) => Ok(self.fetch_archived_game(game_token).await?.db_game()),
+            r => r,
+        }
+    }
+
+    async fn fetch_game_state_for_player(&self, player_token: &PlayerToken) -> Result<DbGameAndPlayer, GameError> {
+        let game = self.fetch_game_by_player(player_token).await?;
+        // warn: non exhaustive
+        let player = if game.player_red == Some(player_token.clone()) {
+            Player::Red
+        } else {
+            Player::Blue
+        };
+        Ok(DbGameAndPlayer { game, player })
+    }
+
+    // the new state is based on the game as the caller has read it, so it's only saved if nobody has saved the game since
+    async fn update_game_state(&self, game: &DbGame, s: GameStateSerialized) -> Result<DbGame, GameError> {
+        let mut game = game.clone();
+        let depth_before = game.game()?.current_depth();
+        let ticked = ticking(&game);
+        game.state = s;
+        let after = game.game()?;
+        if after.current_depth() > depth_before {
+            punch(&mut game, after.last_player().ok(), Utc::now());
+        } else {
+            take_back(&mut game, ticked, Utc::now());
+        }
+        // whatever was asked to be taken back is not the last turn anymore, and a turn declines a draw
+        game.undo_requested_by = None;
+        game.draw_offered_by = None;
+        game.settle_outcome()?;
+        self.save_game(&game).await
+    }
+
+    async fn save_game(&self, game: &DbGame) -> Result<DbGame, GameError> {
+        let r = self.write_game(game).await?;
+        publish_game(r.clone());
+        Ok(r)
+    }
+
+    // the user, if any, is who the seat is linked to; the token is still what the turns are made with
+    async fn claim_game_player(&self, game_token: &GameToken, player: Player, user: Option<UserId>) -> Result<(Uuid, DbGame), GameError> {
+        let mut game = self.fetch_game_state(game_token).await?;
+        if !game.can_player_join(&player) {
+            return Err(GameError::GameFull);
+        }
+        // TODO PlayerToken::new
+        let new_id = Uuid::new_v4();
+        match player {
+            Player::Red => {
+                if game.player_red.is_some() {
+                    return Err(GameError::SlotTaken(player));
+                }
+                game.player_red = Some(PlayerToken(new_id));
+                game.user_red = user;
+            },
+            Player::Blue => {
+                if game.player_blue.is_some() {
+                    return Err(GameError::SlotTaken(player));
+                }
+                game.player_blue = Some(PlayerToken(new_id));
+                game.user_blue = user;
+            },
+        };
+        let r = self.save_game(&game).await?;
+        Ok((new_id, r))
+    }
+
+    async fn insert_game_message(&self, message: &DbGameMessage) -> Result<DbGameMessage, GameError> {
+        let r = self.insert_message(message).await?;
+        publish_game_message(r.clone());
+        Ok(r)
+    }
+}
@@ -0,0 +1,123 @@
+// what minimax already knows about a position, shared by all the search threads without locking.
+// a slot is two words, the key xor-ed with the data and the data itself; a slot torn by two threads writing at once
+// doesn't match its key anymore and reads as empty
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::game::{Move, Side};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Bound {
+    Exact,
+    Lower, // the score is at least that, the search was cut off
+    Upper, // at most that, nothing was better than alpha
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Entry {
+    pub score: i32,
+    pub depth: u8, // how many turns deep it was searched
+    pub bound: Bound,
+    pub best: Option<Move>,
+}
+
+const NO_MOVE: u64 = 0xff;
+
+impl Entry {
+    // score | depth | bound | move height | move side. a zero bound is an empty slot
+    fn pack(&self) -> u64 {
+        let bound = match self.bound {
+            Bound::Exact => 1,
+            Bound::Lower => 2,
+            Bound::Upper => 3,
+        };
+        let (height, side) = match self.best {
+            Some((y, side)) => (y as u64, (side == Side::Right) as u64),
+            None => (NO_MOVE, 0),
+        };
+        (self.score as i16 as u16 as u64) | (self.depth as u64) << 16 | bound << 24 | height << 32 | side << 40
+    }
+
+    fn unpack(data: u64) -> Option<Entry> {
+        let bound = match (data >> 24) & 0xff {
+            1 => Bound::Exact,
+            2 => Bound::Lower,
+            3 => Bound::Upper,
+            _ => return None,
+        };
+        let height = (data >> 32) & 0xff;
+        let side = if (data >> 40) & 1 == 1 { Side::Right } else { Side::Left };
+        Some(Entry {
+            score: data as u16 as i16 as i32,
+            depth: (data >> 16) as u8,
+            bound,
+            best: if height == NO_MOVE { None } else { Some((height as u8, side)) },
+        })
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    check: AtomicU64, // key ^ data
+    data: AtomicU64,
+}
+
+pub struct TranspositionTable {
+    slots: Vec<Slot>,
+}
+
+impl TranspositionTable {
+    // rounded up to a power of two
+    pub fn new(size: usize) -> TranspositionTable {
+        TranspositionTable { slots: (0..size.next_power_of_two()).map(|_| Slot::default()).collect() }
+    }
+
+    fn slot(&self, key: u64) -> &Slot {
+        &self.slots[key as usize & (self.slots.len() - 1)]
+    }
+
+    pub fn probe(&self, key: u64) -> Option<Entry> {
+        let slot = self.slot(key);
+        let data = slot.data.load(Ordering::Relaxed);
+        if slot.check.load(Ordering::Relaxed) ^ data != key {
+            return None;
+        }
+        Entry::unpack(data)
+    }
+
+    // a shallower search of the same position doesn't replace a deeper one, any other position does
+    pub fn store(&self, key: u64, entry: Entry) {
+        if let Some(old) = self.probe(key) {
+            if old.depth > entry.depth {
+                return;
+            }
+        }
+        let slot = self.slot(key);
+        let data = entry.pack();
+        slot.check.store(key ^ data, Ordering::Relaxed);
+        slot.data.store(data, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game::Side;
+    use crate::transposition::{Bound, Entry, TranspositionTable};
+
+    #[test]
+    fn store_and_probe() {
+        let table = TranspositionTable::new(1000);
+        assert_eq!(None, table.probe(0));
+        let entry = Entry { score: -7, depth: 12, bound: Bound::Lower, best: Some((3, Side::Right)) };
+        table.store(42, entry);
+        assert_eq!(Some(entry), table.probe(42));
+        // the same slot, another key
+        assert_eq!(None, table.probe(42 + 1024));
+
+        table.store(42, Entry { depth: 3, ..entry });
+        assert_eq!(Some(entry), table.probe(42));
+        let other = Entry { score: 0, depth: 1, bound: Bound::Exact, best: None };
+        table.store(42 + 1024, other);
+        assert_eq!(Some(other), table.probe(42 + 1024));
+        assert_eq!(None, table.probe(42));
+    }
+}